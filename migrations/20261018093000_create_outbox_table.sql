@@ -0,0 +1,10 @@
+-- Notifications waiting to be sent. Rows are deleted once they're delivered (or we give up)
+CREATE TABLE IF NOT EXISTS Outbox (
+    ID INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    channel varchar(35) NOT NULL,
+    target varchar(255) NOT NULL,
+    payload TEXT NOT NULL,
+    attempts INTEGER NOT NULL DEFAULT 0,
+    next_attempt INTEGER NOT NULL,
+    created INTEGER NOT NULL
+);
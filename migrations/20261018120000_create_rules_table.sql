@@ -0,0 +1,9 @@
+-- Rules that decide what happens with events, see src/rules.rs
+-- conditions and actions are json lists
+CREATE TABLE IF NOT EXISTS Rules (
+    ID INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    name varchar(255) NOT NULL,
+    enabled BOOLEAN NOT NULL DEFAULT 1,
+    conditions TEXT NOT NULL,
+    actions TEXT NOT NULL
+);
@@ -0,0 +1,7 @@
+-- Whether the mailbox is armed. There's only ever one row
+CREATE TABLE IF NOT EXISTS ArmState (
+    ID INTEGER NOT NULL PRIMARY KEY CHECK (ID = 1),
+    armed BOOLEAN NOT NULL,
+    changed_by varchar(255),
+    changed_at INTEGER
+);
@@ -0,0 +1,11 @@
+-- Pictures and videos that were captured, see Store::add_media
+CREATE TABLE IF NOT EXISTS Media (
+    ID INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    -- In MODKIT_IMG_DIR
+    file_name varchar(255) NOT NULL,
+    -- still or video
+    kind varchar(32) NOT NULL,
+    -- What it was captured for, ie. ScheduledSnapshot
+    source varchar(255) NOT NULL,
+    captured_at INTEGER NOT NULL
+);
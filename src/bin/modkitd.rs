@@ -0,0 +1,470 @@
+//! The modkit daemon, and a few commands for poking at the box by hand.
+//!
+//! `modkitd run` is what the systemd unit starts. Everything else does one thing and exits,
+//! ie. `modkitd light on` or `modkitd events export --format csv`.
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::Arc;
+
+use chrono::{Local, TimeZone};
+use clap::{Parser, Subcommand, ValueEnum};
+use log::*;
+use modkit::model::Event;
+use modkit::prelude::*;
+use tokio::sync::Mutex;
+
+#[derive(Parser, Debug)]
+#[command(
+    name = "modkitd",
+    version,
+    about = "Runs the mailbox, and pokes at it by hand"
+)]
+struct Cli {
+    #[command(subcommand)]
+    command: Cmd,
+}
+
+#[derive(Subcommand, Debug, PartialEq)]
+enum Cmd {
+    /// Runs the server, the watchdog, the notifiers and MQTT until SIGTERM or SIGINT
+    Run,
+    /// Brings the database up to date
+    Migrate,
+    /// Checks the database, image directory, door sensor, light, camera and ffmpeg
+    SelfTest,
+    /// Takes a picture or records a video into MODKIT_IMG_DIR
+    Capture {
+        #[arg(value_enum)]
+        kind: CaptureKind,
+    },
+    /// Turns the light on or off
+    Light {
+        #[arg(value_enum)]
+        state: LightState,
+    },
+    /// Reads the door sensor
+    Door {
+        #[command(subcommand)]
+        command: DoorCmd,
+    },
+    /// Reads the event history
+    Events {
+        #[command(subcommand)]
+        command: EventsCmd,
+    },
+    /// Checks the MODKIT_* environment variables
+    Config {
+        #[command(subcommand)]
+        command: ConfigCmd,
+    },
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+enum CaptureKind {
+    Still,
+    Video,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+enum LightState {
+    On,
+    Off,
+}
+
+#[derive(Subcommand, Debug, PartialEq)]
+enum DoorCmd {
+    /// Prints whether the door is open or closed
+    Status,
+}
+
+#[derive(Subcommand, Debug, PartialEq)]
+enum EventsCmd {
+    /// Prints the most recent events, oldest first
+    List {
+        /// How many to print
+        #[arg(short = 'n', long, default_value_t = 20)]
+        limit: usize,
+    },
+    /// Writes every event out, to stdout unless there's an --output
+    Export {
+        #[arg(short, long, value_enum, default_value_t = ExportFormat::Json)]
+        format: ExportFormat,
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+enum ExportFormat {
+    Json,
+    Csv,
+}
+
+#[derive(Subcommand, Debug, PartialEq)]
+enum ConfigCmd {
+    /// Lists settings that can't be used or aren't settings at all. Exits with 1 if there are any
+    Check,
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    let cli = Cli::parse();
+    // The daemon logs what it's doing, the rest only print what they're asked for
+    let default_filter = match cli.command {
+        Cmd::Run => "modkit=info,modkitd=info",
+        _ => "warn",
+    };
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(default_filter))
+        .init();
+
+    match cli.command {
+        Cmd::Run => run().await?,
+        Cmd::Migrate => {
+            Store::connect().await?.migrate().await?;
+            println!("The database is up to date");
+        }
+        Cmd::SelfTest => {
+            if !self_test().await {
+                std::process::exit(1);
+            }
+        }
+        Cmd::Capture {
+            kind: CaptureKind::Still,
+        } => {
+            let path = camera::capture_still(None)?;
+            println!("{}", path.display());
+        }
+        Cmd::Capture {
+            kind: CaptureKind::Video,
+        } => {
+            let video = camera::capture_video(None)?;
+            println!("{} ({}s)", video.path.display(), video.duration);
+        }
+        Cmd::Light { state } => {
+            light::set(state == LightState::On)?;
+            println!("The light is {}", on_off(light::is_on()?));
+        }
+        Cmd::Door {
+            command: DoorCmd::Status,
+        } => {
+            let open = ContactSensor::new().poll()?;
+            println!("{}", if open { "open" } else { "closed" });
+        }
+        Cmd::Events {
+            command: EventsCmd::List { limit },
+        } => {
+            let events = Store::connect().await?.get_all_events().await?;
+            if events.is_empty() {
+                println!("No events yet");
+            }
+            for event in &events[events.len().saturating_sub(limit)..] {
+                println!("{}", event_line(event));
+            }
+        }
+        Cmd::Events {
+            command: EventsCmd::Export { format, output },
+        } => {
+            let events = Store::connect().await?.get_all_events().await?;
+            let exported = match format {
+                ExportFormat::Json => serde_json::to_string_pretty(&events)? + "\n",
+                ExportFormat::Csv => to_csv(&events),
+            };
+            match output {
+                Some(path) => {
+                    fs::write(&path, exported)?;
+                    println!("Wrote {} events to {}", events.len(), path.display());
+                }
+                None => print!("{exported}"),
+            }
+        }
+        Cmd::Config {
+            command: ConfigCmd::Check,
+        } => {
+            let problems = defaults::check();
+            if problems.is_empty() {
+                println!("The config looks good");
+            } else {
+                for problem in &problems {
+                    println!("{problem}");
+                }
+                std::process::exit(1);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// The daemon
+async fn run() -> Result<(), Box<dyn Error>> {
+    if hardware_enabled() {
+        info!("Hardware enabled");
+    } else {
+        warn!("Hardware disabled. This means that either (a) you're not running on the raspberry pi or (b) the GPIO is unavailable");
+    }
+    for problem in defaults::check() {
+        warn!("{problem}");
+    }
+
+    // Fail at boot with a nice message if the database can't be reached
+    let store = Store::connect()
+        .await
+        .inspect_err(|_| error!("Database couldn't be reached"))?;
+    store.migrate().await?;
+    info!("DB connected successfully");
+
+    let ws_clients: server::Clients = Arc::new(Mutex::new(HashMap::new()));
+
+    // Everything below returns once this gets SIGTERM or SIGINT
+    tokio::spawn(shutdown::on_signal(ws_clients.clone()));
+
+    // Anything that fails is started again, see `/health` for how they're doing
+    let clients = &ws_clients;
+    tokio::join!(
+        supervisor::supervise("server", clients, || async move {
+            server::run(clients).await;
+            Ok(())
+        }),
+        supervisor::supervise("watchdog", clients, || watchdog::watch(clients)),
+        supervisor::supervise("webhooks", clients, notify::webhook::run),
+        supervisor::supervise("emails", clients, notify::email::run),
+        supervisor::supervise("mqtt", clients, || mqtt::run(clients))
+    );
+    shutdown::finish().await;
+
+    Ok(())
+}
+
+/// Tries everything the daemon needs, printing how each went. Returns false if anything failed
+async fn self_test() -> bool {
+    println!(
+        "Hardware is {}",
+        if hardware_enabled() {
+            "enabled"
+        } else {
+            "disabled, the devices are simulated"
+        }
+    );
+
+    let mut passed = true;
+    let mut report = |name: &str, result: Result<String, String>| {
+        match &result {
+            Ok(detail) => println!("ok    {name}: {detail}"),
+            Err(e) => println!("FAIL  {name}: {e}"),
+        }
+        passed &= result.is_ok();
+    };
+
+    let problems = defaults::check();
+    report(
+        "config",
+        if problems.is_empty() {
+            Ok("looks good".to_string())
+        } else {
+            Err(problems.join("; "))
+        },
+    );
+
+    let database = match Store::connect().await {
+        Ok(store) => store
+            .get_rules()
+            .await
+            .map(|rules| format!("connected, {} rules", rules.len()))
+            .map_err(|e| format!("{e} (try `modkitd migrate`)")),
+        Err(e) => Err(e.to_string()),
+    };
+    report("database", database);
+
+    report(
+        "image directory",
+        check_img_dir(Path::new(&defaults::img_dir())),
+    );
+
+    report(
+        "door sensor",
+        ContactSensor::new()
+            .poll()
+            .map(|open| if open { "open" } else { "closed" }.to_string())
+            .map_err(|e| e.to_string()),
+    );
+
+    // The light doesn't do anything without the GPIO
+    if hardware_enabled() {
+        let flashed = light::set(true)
+            .and_then(|_| light::is_on())
+            .and_then(|on| light::set(false).map(|_| on));
+        report(
+            "light",
+            match flashed {
+                Ok(true) => Ok("turned on and off".to_string()),
+                Ok(false) => Err("turned it on, but it still reads as off".to_string()),
+                Err(e) => Err(e.to_string()),
+            },
+        );
+    }
+
+    report(
+        "camera",
+        camera::capture_still(None)
+            .map(|path| {
+                // It's only a test, don't keep it
+                let _ = fs::remove_file(&path);
+                "took a still".to_string()
+            })
+            .map_err(|e| e.to_string()),
+    );
+
+    report(
+        "ffmpeg",
+        match Command::new("ffmpeg").arg("-version").output() {
+            Ok(output) if output.status.success() => Ok(String::from_utf8_lossy(&output.stdout)
+                .lines()
+                .next()
+                .unwrap_or("installed")
+                .to_string()),
+            Ok(output) => Err(format!("exited with {}", output.status)),
+            Err(e) => Err(format!("{e}, videos can't be converted to mp4")),
+        },
+    );
+
+    passed
+}
+
+/// Makes sure pictures can be saved
+fn check_img_dir(dir: &Path) -> Result<String, String> {
+    let test_file = dir.join(".modkitd-self-test");
+    fs::create_dir_all(dir)
+        .and_then(|_| fs::write(&test_file, b"test"))
+        .and_then(|_| fs::remove_file(&test_file))
+        .map(|_| format!("{} is writable", dir.display()))
+        .map_err(|e| format!("{}: {e}", dir.display()))
+}
+
+fn on_off(on: bool) -> &'static str {
+    if on {
+        "on"
+    } else {
+        "off"
+    }
+}
+
+fn local_time(timestamp: u32) -> String {
+    Local
+        .timestamp_opt(timestamp as i64, 0)
+        .single()
+        .map(|time| time.format("%Y-%m-%d %H:%M:%S").to_string())
+        .unwrap_or_else(|| timestamp.to_string())
+}
+
+/// One event for `events list`, ie. `2026-10-19 08:12:03  DoorOpened  ContactSensor`
+fn event_line(event: &Event) -> String {
+    let mut line = format!("{}  {}", local_time(event.timestamp()), event.kind());
+    if let Some(device) = event.device_type() {
+        line.push_str(&format!("  {device}"));
+    }
+    if let Some(data) = event.data() {
+        line.push_str(&format!(
+            "  {}",
+            serde_json::to_string(data).unwrap_or_default()
+        ));
+    }
+    line
+}
+
+/// Events as csv, with the bundle as json in the last column
+fn to_csv(events: &[Event]) -> String {
+    let mut csv = "timestamp,time,kind,device,data\n".to_string();
+    for event in events {
+        let data = event
+            .data()
+            .map(|data| serde_json::to_string(data).unwrap_or_default())
+            .unwrap_or_default();
+        csv.push_str(&format!(
+            "{},{},{},{},\"{}\"\n",
+            event.timestamp(),
+            local_time(event.timestamp()),
+            event.kind(),
+            event
+                .device_type()
+                .map(|device| device.to_string())
+                .unwrap_or_default(),
+            data.replace('"', "\"\"")
+        ));
+    }
+    csv
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::CommandFactory;
+    use modkit::model::{Bundle, EventKind};
+
+    use super::*;
+
+    fn parse(args: &[&str]) -> Cmd {
+        Cli::try_parse_from([&["modkitd"], args].concat())
+            .unwrap()
+            .command
+    }
+
+    #[test]
+    fn test_cli() {
+        Cli::command().debug_assert();
+
+        assert_eq!(parse(&["run"]), Cmd::Run);
+        assert_eq!(parse(&["self-test"]), Cmd::SelfTest);
+        assert_eq!(
+            parse(&["capture", "video"]),
+            Cmd::Capture {
+                kind: CaptureKind::Video
+            }
+        );
+        assert_eq!(
+            parse(&["light", "off"]),
+            Cmd::Light {
+                state: LightState::Off
+            }
+        );
+        assert_eq!(
+            parse(&["events", "list"]),
+            Cmd::Events {
+                command: EventsCmd::List { limit: 20 }
+            }
+        );
+        assert_eq!(
+            parse(&["events", "export", "--format", "csv", "-o", "events.csv"]),
+            Cmd::Events {
+                command: EventsCmd::Export {
+                    format: ExportFormat::Csv,
+                    output: Some(PathBuf::from("events.csv")),
+                }
+            }
+        );
+        assert!(Cli::try_parse_from(["modkitd", "light", "dim"]).is_err());
+    }
+
+    #[test]
+    fn test_to_csv() {
+        let mut event = Event::new(
+            EventKind::PollDeviceResult,
+            Some(DeviceType::Light),
+            Some(Bundle::Light { on: true }),
+        );
+        event.set_timestamp(1_700_000_000);
+
+        let csv = to_csv(&[event]);
+        let mut lines = csv.lines();
+        assert_eq!(lines.next(), Some("timestamp,time,kind,device,data"));
+        let row = lines.next().unwrap();
+        assert!(row.starts_with("1700000000,"), "{:?}", row);
+        assert!(
+            row.ends_with(r#",PollDeviceResult,Light,"{""Light"":{""on"":true}}""#),
+            "{:?}",
+            row
+        );
+        assert_eq!(lines.next(), None);
+    }
+}
@@ -1,5 +1,13 @@
+use std::collections::HashMap;
 use std::env::var;
 
+use chrono::NaiveTime;
+
+use crate::drivers::camera::camera::VideoLength;
+use crate::schedule::{self, CronSchedule, TimeWindow};
+use crate::vision::pipeline::Pipeline;
+use crate::vision::Roi;
+
 pub fn img_dir() -> String {
     // This only runs in one environment, we can safely
     // assume that it won't panic
@@ -36,3 +44,506 @@ pub fn pin() -> u16 {
     // otherwise, this is the default
     6245
 }
+
+/// Whether to use the camera to decide if mail was delivered or picked up.
+/// If this is off we just switch between the two statuses every time the door closes.
+pub fn mail_classifier() -> bool {
+    match var("MODKIT_MAIL_CLASSIFIER") {
+        Ok(s) => s != "0",
+        Err(_) => true,
+    }
+}
+
+/// The region of the still image that shows the mailbox floor, as fractions of the
+/// image size: `x,y,width,height`
+pub fn mail_roi() -> Roi {
+    if let Ok(s) = var("MODKIT_MAIL_ROI") {
+        if let Some(roi) = Roi::parse(&s) {
+            return roi;
+        }
+    }
+
+    // The bottom half of the image, minus a bit on the sides
+    Roi {
+        x: 0.1,
+        y: 0.5,
+        width: 0.8,
+        height: 0.5,
+    }
+}
+
+/// How different (0 - 1) the mailbox floor has to look before we say the mail changed
+pub fn mail_threshold() -> f32 {
+    if let Ok(s) = var("MODKIT_MAIL_THRESHOLD") {
+        if let Ok(parsed) = s.parse() {
+            return parsed;
+        }
+    }
+
+    0.05
+}
+
+/// The steps to run on every still after it's captured, as json. See `vision::pipeline`
+pub fn still_pipeline() -> Pipeline {
+    if let Ok(s) = var("MODKIT_STILL_PIPELINE") {
+        if let Ok(parsed) = Pipeline::parse(&s) {
+            return parsed;
+        }
+    }
+    Pipeline::default()
+}
+
+/// Whether to watch the camera for motion outside the box, ie. packages left next to it
+pub fn motion_detection() -> bool {
+    match var("MODKIT_MOTION") {
+        Ok(s) => s == "1",
+        Err(_) => false,
+    }
+}
+
+/// Seconds between frames checked for motion
+pub fn motion_interval() -> u32 {
+    if let Ok(s) = var("MODKIT_MOTION_INTERVAL") {
+        if let Ok(parsed) = s.parse() {
+            return parsed;
+        }
+    }
+    5
+}
+
+/// How much of the picture (0 - 1) has to change to count as motion. Lower is more sensitive
+pub fn motion_sensitivity() -> f32 {
+    if let Ok(s) = var("MODKIT_MOTION_SENSITIVITY") {
+        if let Ok(parsed) = s.parse() {
+            return parsed;
+        }
+    }
+    0.02
+}
+
+/// Parts of the picture to ignore for motion, as `x,y,width,height` regions separated by `;`
+pub fn motion_masks() -> Vec<Roi> {
+    var("MODKIT_MOTION_MASKS")
+        .unwrap_or_default()
+        .split(';')
+        .filter(|s| !s.trim().is_empty())
+        .filter_map(Roi::parse)
+        .collect()
+}
+
+/// Seconds after a MotionDetected before another one is sent
+pub fn motion_cooldown() -> u32 {
+    if let Ok(s) = var("MODKIT_MOTION_COOLDOWN") {
+        if let Ok(parsed) = s.parse() {
+            return parsed;
+        }
+    }
+    60
+}
+
+/// Urls to POST notifications to, separated by commas
+pub fn webhook_urls() -> Vec<String> {
+    var("MODKIT_WEBHOOK_URLS")
+        .unwrap_or_default()
+        .split(',')
+        .map(|url| url.trim().to_string())
+        .filter(|url| !url.is_empty())
+        .collect()
+}
+
+/// The key used to sign webhook requests. They aren't signed if this isn't set
+pub fn webhook_secret() -> Option<String> {
+    var("MODKIT_WEBHOOK_SECRET").ok().filter(|s| !s.is_empty())
+}
+
+/// Where the files in `img_dir` are served from, ie. `http://192.168.1.20:3000/img`.
+/// Used to put links to pictures/videos in notifications
+pub fn media_url() -> Option<String> {
+    var("MODKIT_MEDIA_URL").ok().filter(|s| !s.is_empty())
+}
+
+/// A name for this box, used for MQTT client ids and Home Assistant entities
+pub fn device_id() -> String {
+    var("MODKIT_DEVICE_ID").unwrap_or("modkit".to_string())
+}
+
+/// The MQTT broker to connect to. MQTT is off if this isn't set
+pub fn mqtt_host() -> Option<String> {
+    var("MODKIT_MQTT_HOST").ok().filter(|s| !s.is_empty())
+}
+
+pub fn mqtt_port() -> u16 {
+    if let Ok(s) = var("MODKIT_MQTT_PORT") {
+        if let Ok(parsed) = s.parse() {
+            return parsed;
+        }
+    }
+
+    1883
+}
+
+/// The MQTT username and password, if the broker needs them
+pub fn mqtt_credentials() -> Option<(String, String)> {
+    match (var("MODKIT_MQTT_USER"), var("MODKIT_MQTT_PASSWORD")) {
+        (Ok(user), Ok(password)) => Some((user, password)),
+        _ => None,
+    }
+}
+
+/// All our MQTT topics start with this
+pub fn mqtt_prefix() -> String {
+    var("MODKIT_MQTT_PREFIX").unwrap_or("modkit".to_string())
+}
+
+/// Where Home Assistant looks for discovery configs
+pub fn mqtt_discovery_prefix() -> String {
+    var("MODKIT_MQTT_DISCOVERY_PREFIX").unwrap_or("homeassistant".to_string())
+}
+
+/// Whether to record a video when the door opens. It's always recorded while the box is armed
+pub fn record_video() -> bool {
+    match var("MODKIT_RECORD_VIDEO") {
+        Ok(s) => s != "0",
+        Err(_) => true,
+    }
+}
+
+/// How long videos are: `fixed` is always `MODKIT_VIDEO_SECONDS` long, `door` records until
+/// the door closes and then `MODKIT_VIDEO_SECONDS` more
+pub fn video_length() -> VideoLength {
+    let seconds = match var("MODKIT_VIDEO_SECONDS").map(|s| s.parse()) {
+        Ok(Ok(parsed)) => parsed,
+        _ => 5,
+    };
+
+    match var("MODKIT_VIDEO_MODE") {
+        Ok(s) if s == "door" => VideoLength::UntilClosed { after: seconds },
+        _ => VideoLength::Fixed { seconds },
+    }
+}
+
+/// The longest a video can be, not counting the pre-roll, whatever the mode
+pub fn video_max() -> u32 {
+    if let Ok(s) = var("MODKIT_VIDEO_MAX") {
+        if let Ok(parsed) = s.parse() {
+            return parsed;
+        }
+    }
+    60
+}
+
+/// Seconds of video to keep from before the door opened. 0 (the default) turns the ring
+/// buffer off, and videos only start once the door is open
+pub fn preroll() -> u32 {
+    if let Ok(s) = var("MODKIT_PREROLL") {
+        if let Ok(parsed) = s.parse() {
+            return parsed;
+        }
+    }
+    0
+}
+
+/// Where the ring buffer keeps its segments. Should be a tmpfs to save the SD card
+pub fn preroll_dir() -> String {
+    var("MODKIT_PREROLL_DIR").unwrap_or("/dev/shm/modkit".to_string())
+}
+
+/// How many seconds the door can be open before we send a DoorLeftOpen. 0 turns it off
+pub fn door_open_alarm() -> u32 {
+    if let Ok(s) = var("MODKIT_DOOR_OPEN_ALARM") {
+        if let Ok(parsed) = s.parse() {
+            return parsed;
+        }
+    }
+
+    120
+}
+
+/// How many seconds between DoorLeftOpen events while the door stays open. 0 only sends one
+pub fn door_open_repeat() -> u32 {
+    if let Ok(s) = var("MODKIT_DOOR_OPEN_REPEAT") {
+        if let Ok(parsed) = s.parse() {
+            return parsed;
+        }
+    }
+
+    300
+}
+
+/// Whether to watch for suspicious door openings, see `security`
+pub fn security_mode() -> bool {
+    match var("MODKIT_SECURITY") {
+        Ok(s) => s == "1",
+        Err(_) => false,
+    }
+}
+
+/// When mail usually comes, ie. `09:00-12:00,14:00-17:00`. Openings outside these are
+/// suspicious. Set it to nothing to allow any time
+pub fn delivery_windows() -> Vec<TimeWindow> {
+    var("MODKIT_DELIVERY_WINDOWS")
+        .unwrap_or("08:00-20:00".to_string())
+        .split(',')
+        .filter_map(TimeWindow::parse)
+        .collect()
+}
+
+/// This many openings within `security_repeat_window` is suspicious
+pub fn security_repeat_count() -> u32 {
+    if let Ok(s) = var("MODKIT_SECURITY_REPEAT_COUNT") {
+        if let Ok(parsed) = s.parse() {
+            return parsed;
+        }
+    }
+
+    3
+}
+
+/// In seconds
+pub fn security_repeat_window() -> u32 {
+    if let Ok(s) = var("MODKIT_SECURITY_REPEAT_WINDOW") {
+        if let Ok(parsed) = s.parse() {
+            return parsed;
+        }
+    }
+
+    10 * 60
+}
+
+/// Openings this many seconds after the mail is picked up are suspicious
+pub fn security_after_pickup() -> u32 {
+    if let Ok(s) = var("MODKIT_SECURITY_AFTER_PICKUP") {
+        if let Ok(parsed) = s.parse() {
+            return parsed;
+        }
+    }
+
+    5 * 60
+}
+
+/// The SMTP server to send emails through. Email is off if this isn't set
+pub fn smtp_host() -> Option<String> {
+    var("MODKIT_SMTP_HOST").ok().filter(|s| !s.is_empty())
+}
+
+/// The SMTP port. If it isn't set we use the usual one for `smtp_tls`
+pub fn smtp_port() -> Option<u16> {
+    var("MODKIT_SMTP_PORT").ok().and_then(|s| s.parse().ok())
+}
+
+/// The SMTP username and password, if the server needs them
+pub fn smtp_credentials() -> Option<(String, String)> {
+    match (var("MODKIT_SMTP_USER"), var("MODKIT_SMTP_PASSWORD")) {
+        (Ok(user), Ok(password)) => Some((user, password)),
+        _ => None,
+    }
+}
+
+/// How to connect to the SMTP server: `starttls`, `tls` or `none`
+pub fn smtp_tls() -> String {
+    var("MODKIT_SMTP_TLS").unwrap_or("starttls".to_string())
+}
+
+/// Who emails come from
+pub fn email_from() -> String {
+    var("MODKIT_EMAIL_FROM").unwrap_or(format!("{}@localhost", device_id()))
+}
+
+/// Addresses to email about deliveries, separated by commas
+pub fn email_to() -> Vec<String> {
+    var("MODKIT_EMAIL_TO")
+        .unwrap_or_default()
+        .split(',')
+        .map(|address| address.trim().to_string())
+        .filter(|address| !address.is_empty())
+        .collect()
+}
+
+/// A time of day to not send emails, ie. `22:00-07:00`. Anything that comes up then
+/// is sent when it's over
+pub fn email_quiet_hours() -> Option<TimeWindow> {
+    var("MODKIT_EMAIL_QUIET_HOURS")
+        .ok()
+        .and_then(|s| TimeWindow::parse(&s))
+}
+
+/// If this is set (ie. `18:00`), emails are saved up and sent as one digest at that
+/// time every day instead of one for each delivery
+pub fn email_digest() -> Option<NaiveTime> {
+    var("MODKIT_EMAIL_DIGEST")
+        .ok()
+        .and_then(|s| schedule::parse_time(&s))
+}
+
+/// When to take a still, even if nobody opened the door, as a cron line
+/// (ie. `0 8-18 * * *` for every hour during the day). Off if it's not set
+pub fn snapshot_schedule() -> Option<CronSchedule> {
+    var("MODKIT_SNAPSHOT_SCHEDULE")
+        .ok()
+        .and_then(|s| CronSchedule::parse(&s))
+}
+
+/// Looks through the environment for settings that can't be used, ie. `MODKIT_PREROLL=lots`,
+/// or that we don't know about, which are usually typos. Bad values are quietly replaced with
+/// the default everywhere else, so this is how to find them
+pub fn check() -> Vec<String> {
+    check_settings(&std::env::vars().collect())
+}
+
+fn check_settings(vars: &HashMap<String, String>) -> Vec<String> {
+    let mut problems: Vec<String> = vars
+        .iter()
+        .filter(|(name, _)| name.starts_with("MODKIT_"))
+        .filter_map(|(name, value)| {
+            check_setting(name, value)
+                .err()
+                .map(|problem| format!("{name}={value:?}: {problem}"))
+        })
+        .collect();
+
+    for (user, password) in [
+        ("MODKIT_MQTT_USER", "MODKIT_MQTT_PASSWORD"),
+        ("MODKIT_SMTP_USER", "MODKIT_SMTP_PASSWORD"),
+    ] {
+        if vars.contains_key(user) != vars.contains_key(password) {
+            problems.push(format!(
+                "{user} and {password} have to be set together, neither is used"
+            ));
+        }
+    }
+
+    problems.sort();
+    problems
+}
+
+/// Whether one setting can be used, and what's wrong with it if not
+fn check_setting(name: &str, value: &str) -> Result<(), String> {
+    fn expect(ok: bool, expected: &str) -> Result<(), String> {
+        if ok {
+            Ok(())
+        } else {
+            Err(format!("should be {expected}, the default is used instead"))
+        }
+    }
+
+    match name.trim_start_matches("MODKIT_") {
+        "FLIP_VERTICAL" | "MAIL_CLASSIFIER" | "MOTION" | "RECORD_VIDEO" | "SECURITY" => {
+            expect(value == "0" || value == "1", "0 or 1")
+        }
+        "PIN" | "MQTT_PORT" | "SMTP_PORT" => {
+            expect(value.parse::<u16>().is_ok(), "a number up to 65535")
+        }
+        "MOTION_INTERVAL"
+        | "MOTION_COOLDOWN"
+        | "VIDEO_SECONDS"
+        | "VIDEO_MAX"
+        | "PREROLL"
+        | "DOOR_OPEN_ALARM"
+        | "DOOR_OPEN_REPEAT"
+        | "SECURITY_REPEAT_COUNT"
+        | "SECURITY_REPEAT_WINDOW"
+        | "SECURITY_AFTER_PICKUP" => expect(value.parse::<u32>().is_ok(), "a whole number"),
+        "MAIL_THRESHOLD" | "MOTION_SENSITIVITY" => expect(
+            matches!(value.parse::<f32>(), Ok(n) if (0.0..=1.0).contains(&n)),
+            "a number from 0 to 1",
+        ),
+        "MAIL_ROI" => expect(Roi::parse(value).is_some(), "x,y,width,height"),
+        "MOTION_MASKS" => expect(
+            value
+                .split(';')
+                .filter(|s| !s.trim().is_empty())
+                .all(|s| Roi::parse(s).is_some()),
+            "x,y,width,height regions separated by ;",
+        ),
+        "STILL_PIPELINE" => Pipeline::parse(value)
+            .map(|_| ())
+            .map_err(|e| format!("{e}, the default is used instead")),
+        "VIDEO_MODE" => expect(value == "fixed" || value == "door", "fixed or door"),
+        "SMTP_TLS" => expect(
+            ["starttls", "tls", "none"].contains(&value),
+            "starttls, tls or none",
+        ),
+        "DELIVERY_WINDOWS" => expect(
+            value.split(',').all(|s| TimeWindow::parse(s).is_some()),
+            "time windows like 09:00-12:00 separated by commas",
+        ),
+        "EMAIL_QUIET_HOURS" => expect(
+            TimeWindow::parse(value).is_some(),
+            "a time window like 22:00-07:00",
+        ),
+        "EMAIL_DIGEST" => expect(schedule::parse_time(value).is_some(), "a time like 18:00"),
+        "SNAPSHOT_SCHEDULE" => expect(
+            CronSchedule::parse(value).is_some(),
+            "a cron line like 0 8-18 * * *",
+        ),
+        "WEBHOOK_URLS" => expect(
+            value
+                .split(',')
+                .map(str::trim)
+                .filter(|url| !url.is_empty())
+                .all(|url| url.starts_with("http://") || url.starts_with("https://")),
+            "http or https urls separated by commas",
+        ),
+        "IMG_DIR"
+        | "PREROLL_DIR"
+        | "WEBHOOK_SECRET"
+        | "MEDIA_URL"
+        | "DEVICE_ID"
+        | "MQTT_HOST"
+        | "MQTT_USER"
+        | "MQTT_PASSWORD"
+        | "MQTT_PREFIX"
+        | "MQTT_DISCOVERY_PREFIX"
+        | "SMTP_HOST"
+        | "SMTP_USER"
+        | "SMTP_PASSWORD"
+        | "EMAIL_FROM"
+        | "EMAIL_TO"
+        | "UPDATE_SCHEMA" => Ok(()),
+        _ => Err("isn't a setting, is it spelled right?".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(vars: &[(&str, &str)]) -> HashMap<String, String> {
+        vars.iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_check_settings() {
+        let good = settings(&[
+            ("MODKIT_PREROLL", "3"),
+            ("MODKIT_VIDEO_MODE", "door"),
+            ("MODKIT_MAIL_THRESHOLD", "0.1"),
+            ("MODKIT_DELIVERY_WINDOWS", "09:00-12:00,14:00-17:00"),
+            ("MODKIT_SNAPSHOT_SCHEDULE", "0 8-18 * * *"),
+            ("MODKIT_MQTT_USER", "box"),
+            ("MODKIT_MQTT_PASSWORD", "hunter2"),
+            ("HOME", "/home/pi"),
+        ]);
+        assert!(check_settings(&good).is_empty());
+
+        let bad = settings(&[
+            ("MODKIT_PREROLL", "lots"),
+            ("MODKIT_VIDEO_MODE", "forever"),
+            ("MODKIT_MAIL_THRESHOLD", "5"),
+            ("MODKIT_SNAPSHOT_SCHEDULE", "every hour"),
+            ("MODKIT_PREROL", "3"),
+            ("MODKIT_SMTP_USER", "box"),
+        ]);
+        let problems = check_settings(&bad);
+        assert_eq!(problems.len(), 6, "{:?}", problems);
+        assert!(problems[0].starts_with("MODKIT_MAIL_THRESHOLD=\"5\""));
+        assert!(problems
+            .iter()
+            .any(|p| p.contains("MODKIT_PREROL=") && p.contains("spelled")));
+        assert!(problems
+            .iter()
+            .any(|p| p.starts_with("MODKIT_SMTP_USER and MODKIT_SMTP_PASSWORD")));
+    }
+}
@@ -1,16 +1,126 @@
 pub mod camera {
-    use std::path::PathBuf;
-    use std::process::Command;
+    use std::path::{Path, PathBuf};
+    use std::process::{Command, Stdio};
     use std::thread::sleep;
-    use std::time::Duration;
+    use std::time::{Duration, Instant, SystemTime};
 
-    use image::{ImageBuffer, RgbImage};
+    use chrono::Local;
+    use image::{GrayImage, ImageBuffer, RgbImage};
     use log::*;
 
+    use super::super::contact_sensor::ContactSensor;
     use super::super::light::light;
+    use super::super::prebuffer::prebuffer;
     use super::super::DeviceError;
     use crate::defaults;
-    use crate::drivers::hardware_enabled;
+    use crate::drivers::{
+        check_output, command_failed, hardware_enabled, interrupt, run, wait_for_exit,
+    };
+    use crate::metrics;
+    use crate::model::Event;
+    use crate::shutdown;
+    use crate::vision::metadata::{self, Metadata};
+
+    /// How long to record a video for, not counting the pre-roll
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub enum VideoLength {
+        /// Always this many seconds
+        Fixed { seconds: u32 },
+        /// Until the door closes, then this many more seconds
+        UntilClosed { after: u32 },
+    }
+
+    /// Decides when a recording should stop
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct Recording {
+        length: VideoLength,
+        /// Seconds to stop at no matter what
+        max: u32,
+        /// Milliseconds into the recording the door was closed at, if it's closed
+        closed_at: Option<u64>,
+    }
+
+    impl Recording {
+        pub fn new(length: VideoLength, max: u32) -> Self {
+            Recording {
+                length,
+                max,
+                closed_at: None,
+            }
+        }
+
+        /// Uses the length from the environment, see `defaults`
+        pub fn from_env() -> Self {
+            Self::new(defaults::video_length(), defaults::video_max())
+        }
+
+        /// Seconds the camera can be told to stop at by itself
+        pub fn limit(&self) -> u32 {
+            match self.length {
+                VideoLength::Fixed { seconds } => seconds.min(self.max),
+                VideoLength::UntilClosed { .. } => self.max,
+            }
+        }
+
+        /// Whether it only ever stops at `limit`, so the camera can stop itself
+        pub fn stops_itself(&self) -> bool {
+            matches!(self.length, VideoLength::Fixed { .. })
+        }
+
+        /// Whether to stop `elapsed` milliseconds into the recording. `door_open` is only
+        /// called when the door matters
+        pub fn should_stop(&mut self, elapsed: u64, door_open: &mut dyn FnMut() -> bool) -> bool {
+            if elapsed >= self.max as u64 * 1000 {
+                return true;
+            }
+
+            match self.length {
+                VideoLength::Fixed { seconds } => elapsed >= seconds as u64 * 1000,
+                VideoLength::UntilClosed { after } => {
+                    if door_open() {
+                        // Opened again, so wait for it to close again
+                        self.closed_at = None;
+                        return false;
+                    }
+                    let closed_at = *self.closed_at.get_or_insert(elapsed);
+                    elapsed >= closed_at + after as u64 * 1000
+                }
+            }
+        }
+
+        /// Blocks until the recording should stop, or `finished` says it already has.
+        /// Shutting down stops it early too, and whatever was recorded is kept.
+        /// Returns how many seconds it went for
+        fn wait(&mut self, finished: &mut dyn FnMut() -> bool) -> u32 {
+            let started = Instant::now();
+            let sensor = ContactSensor::new();
+            let mut door_open = || {
+                sensor
+                    .poll()
+                    .map_err(|e| error!("Couldn't check the door while recording: {e}"))
+                    .unwrap_or(false)
+            };
+
+            loop {
+                let elapsed = started.elapsed().as_millis() as u64;
+                if finished()
+                    || shutdown::is_stopping()
+                    || self.should_stop(elapsed, &mut door_open)
+                {
+                    return ((elapsed + 500) / 1000) as u32;
+                }
+                sleep(Duration::from_millis(100));
+            }
+        }
+    }
+
+    /// A recorded video
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct Video {
+        pub path: PathBuf,
+        /// How long it is, in seconds
+        pub duration: u32,
+    }
 
     enum FileType {
         Image,
@@ -58,7 +168,25 @@ pub mod camera {
         Ok(img_path)
     }
 
-    pub fn capture_still() -> Result<PathBuf, DeviceError> {
+    /// What gets written into a capture. `trigger` is the event it was taken for, if any
+    fn metadata(trigger: Option<&Event>, mut settings: String) -> Metadata {
+        if defaults::flip_vertical() {
+            settings.push_str(", flipped");
+        }
+        Metadata {
+            captured_at: Local::now(),
+            device_id: defaults::device_id(),
+            event_id: trigger.map(|e| format!("{}-{}", e.kind(), e.timestamp())),
+            settings,
+        }
+    }
+
+    /// Takes a still, timed for the metrics
+    pub fn capture_still(trigger: Option<&Event>) -> Result<PathBuf, DeviceError> {
+        metrics::capture("still", || take_still(trigger))
+    }
+
+    fn take_still(trigger: Option<&Event>) -> Result<PathBuf, DeviceError> {
         let hardware = hardware_enabled();
 
         if hardware {
@@ -71,7 +199,20 @@ pub mod camera {
 
         trace!("File path for captured image: {}", img_path.display());
 
-        if hardware {
+        let settings;
+        if hardware && prebuffer::is_running() {
+            // raspivid has the camera, take the still from what it's recording
+            settings = "800x550, 25fps, from the ring buffer".to_string();
+            trace!("Taking picture from the ring buffer");
+            // The light's been on since a moment ago, so this is a bit late to be safe
+            let saved = prebuffer::save_still(&img_path, SystemTime::now());
+
+            // Even if it didn't work, don't leave the light on
+            sleep(Duration::from_millis(50));
+            trace!("Turning light off after image capture");
+            light::set(false)?;
+            saved?;
+        } else if hardware {
             let mut args = vec![
                 "--drc",
                 "high",
@@ -96,19 +237,22 @@ pub mod camera {
             let path_display = format!("{}", img_path.display());
             args.push(&path_display);
 
+            settings = "800x550, ISO 100, brightness 50, DRC high".to_string();
             trace!("Image capture command = `raspistill {:?}`", args);
 
             trace!("Taking picture with raspistill");
-            Command::new("raspistill")
-                .args(&args)
-                .output()
-                .expect("Run raspistill command");
+            let taken = run("raspistill", &args);
 
+            // Even if it didn't work, don't leave the light on
             sleep(Duration::from_millis(50));
             trace!("Turning light off after image capture");
             light::set(false)?;
+
+            taken?;
+            check_output(&img_path, "raspistill")?;
         } else {
             // Generate an image and save it
+            settings = "simulated".to_string();
             let mut img: RgbImage = ImageBuffer::new(50, 50);
             *img.get_pixel_mut(25, 25) = image::Rgb([255, 255, 255]);
 
@@ -116,19 +260,28 @@ pub mod camera {
             img.save(&img_path)?;
         }
 
+        let pipeline = defaults::still_pipeline();
+        if !pipeline.is_empty() {
+            trace!("Post-processing {}", img_path.display());
+            pipeline.apply_file(&img_path)?;
+        }
+
+        // After the pipeline, re-encoding the picture would throw it away
+        if let Err(e) = metadata::embed_jpeg(&img_path, &metadata(trigger, settings)) {
+            error!("Couldn't write metadata to {}: {e}", img_path.display());
+        }
+
         // And return the path
         Ok(img_path)
     }
 
-    pub fn capture_video() -> Result<PathBuf, DeviceError> {
-        let hardware = hardware_enabled();
-
-        if hardware {
-            trace!("Turning light on to capture image");
-            light::set(true)?;
-            sleep(Duration::from_millis(50));
-        }
+    /// Records a video, for as long as `MODKIT_VIDEO_MODE` says. It's an mp4 if the
+    /// conversion worked, or the raw .h264 if it didn't
+    pub fn capture_video(trigger: Option<&Event>) -> Result<Video, DeviceError> {
+        metrics::capture("video", || take_video(trigger))
+    }
 
+    fn take_video(trigger: Option<&Event>) -> Result<Video, DeviceError> {
         let unproc_video_path = get_output_file(FileType::Video)?;
         let mut proc_video_path = unproc_video_path.clone();
         proc_video_path.set_extension("mp4");
@@ -142,55 +295,237 @@ pub mod camera {
             proc_video_path.display()
         );
 
-        if hardware {
-            let mut args = vec!["-w", "800", "-h", "550", "-fps", "25", "--nopreview"];
+        let mut recording = Recording::from_env();
+
+        if !hardware_enabled() {
+            // Take as long as a real one would, then make one that long
+            let duration = recording.wait(&mut || false);
+            trace!("Generating a {duration}s simulated video");
+            simulate_video(&proc_video_path, duration.max(1))?;
+            if let Err(e) = metadata::embed_mp4(
+                &proc_video_path,
+                &metadata(trigger, "simulated".to_string()),
+            ) {
+                error!(
+                    "Couldn't write metadata to {}: {e}",
+                    proc_video_path.display()
+                );
+            }
+            return Ok(Video {
+                path: proc_video_path,
+                duration,
+            });
+        }
 
-            if defaults::flip_vertical() {
-                args.push("-vf")
+        trace!("Turning light on to capture video");
+        light::hold()?;
+        sleep(Duration::from_millis(50));
+
+        let settings;
+        let recorded = if prebuffer::is_running() {
+            // Save what's in the ring buffer, so the video starts before the trigger
+            settings = format!("800x550, 25fps, {}s pre-roll", defaults::preroll());
+            let from = SystemTime::now() - Duration::from_secs(defaults::preroll() as u64);
+            let duration = recording.wait(&mut || false);
+            prebuffer::save_video(&unproc_video_path, from).map(|_| duration + defaults::preroll())
+        } else {
+            settings = "800x550, 25fps".to_string();
+            record(&unproc_video_path, &mut recording)
+        };
+
+        // Even if it didn't work, don't leave the light on
+        sleep(Duration::from_millis(50));
+        trace!("Turning light off after video capture");
+        light::release()?;
+
+        let duration = recorded?;
+        check_output(&unproc_video_path, "raspivid")?;
+        trace!("Captured {duration}s of unprocessed .h264 video");
+
+        let path = remux(&unproc_video_path, &proc_video_path);
+        if path == proc_video_path {
+            if let Err(e) = metadata::embed_mp4(&path, &metadata(trigger, settings)) {
+                error!("Couldn't write metadata to {}: {e}", path.display());
             }
+        }
+        Ok(Video { path, duration })
+    }
 
-            args.push("-o");
-            let path_display = format!("{}", unproc_video_path.display());
-            args.push(&path_display);
+    /// Runs raspivid until the recording should stop. Returns how many seconds it went for
+    fn record(output: &Path, recording: &mut Recording) -> Result<u32, DeviceError> {
+        // It stops itself at the limit. A fixed length video is left to do that, so it
+        // finishes the file and lets go of the camera properly
+        let limit = (recording.limit() * 1000).to_string();
+        let output = format!("{}", output.display());
+        let mut args = vec![
+            "-t",
+            &limit,
+            "-w",
+            "800",
+            "-h",
+            "550",
+            "-fps",
+            "25",
+            "--nopreview",
+        ];
+
+        if defaults::flip_vertical() {
+            args.push("-vf")
+        }
 
-            // Capture the video as h264
-            Command::new("raspivid")
-                .args(&args)
-                .output()
-                .expect("Run raspivid command");
-
-            trace!("Capture unprocessed .h264 video");
-            trace!("Converting with ffmpeg");
-
-            Command::new("ffmpeg")
-                .args([
-                    "-f",
-                    "h264",
-                    "-i",
-                    &format!("{}", unproc_video_path.display()),
-                    "-c:v",
-                    "copy",
-                    &format!("{}", proc_video_path.display()),
-                ])
-                .output()
-                .expect("Convert .h264 to .mp4");
-
-            trace!("Converted");
-
-            match std::fs::remove_file(&unproc_video_path) {
-                Ok(_) => trace!("Removed unprocessed file: {}", unproc_video_path.display()),
-                Err(e) => error!("Couldn't remove unprocessed .h264 file: {e}"),
-            };
+        args.push("-o");
+        args.push(&output);
+
+        // Capture the video as h264
+        let mut child = Command::new("raspivid")
+            .args(&args)
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| DeviceError::IoError(format!("couldn't run `raspivid`: {e}")))?;
+
+        let mut exited = false;
+        let duration = recording.wait(&mut || {
+            exited = matches!(child.try_wait(), Ok(Some(_)));
+            exited
+        });
+        if !exited && recording.stops_itself() && !shutdown::is_stopping() {
+            // It should be just about done
+            exited = wait_for_exit(&mut child, Duration::from_secs(2));
+        }
+        if !exited {
+            trace!("Stopping raspivid after {duration}s");
+            // It's fine if it finished in the meantime
+            interrupt("raspivid", &mut child);
+        }
 
-            sleep(Duration::from_millis(50));
-            trace!("Turning light off after video capture");
-            light::set(false)?;
+        let result = child.wait_with_output()?;
+        // It's only a failure if it stopped on its own
+        if exited && !result.status.success() {
+            return Err(command_failed("raspivid", &result));
+        }
+        Ok(duration)
+    }
+
+    /// Wraps a raw .h264 in an mp4 and removes the .h264. If that doesn't work, the .h264 is
+    /// kept and returned instead, most players can still show it
+    pub(super) fn remux(h264: &Path, mp4: &Path) -> PathBuf {
+        trace!("Converting with ffmpeg");
+        let converted = run(
+            "ffmpeg",
+            &[
+                "-y",
+                "-f",
+                "h264",
+                "-i",
+                &format!("{}", h264.display()),
+                "-c:v",
+                "copy",
+                &format!("{}", mp4.display()),
+            ],
+        )
+        .and_then(|_| check_output(mp4, "ffmpeg"));
+
+        match converted {
+            Ok(()) => {
+                trace!("Converted");
+                match std::fs::remove_file(h264) {
+                    Ok(_) => trace!("Removed unprocessed file: {}", h264.display()),
+                    Err(e) => error!("Couldn't remove unprocessed .h264 file: {e}"),
+                };
+                mp4.to_path_buf()
+            }
+            Err(e) => {
+                error!("Couldn't convert the video to mp4, keeping the .h264: {e}");
+                let _ = std::fs::remove_file(mp4);
+                h264.to_path_buf()
+            }
+        }
+    }
+
+    /// Makes a test pattern video with a running clock, for when there's no camera
+    fn simulate_video(path: &Path, seconds: u32) -> Result<(), DeviceError> {
+        let source = format!("testsrc=size=800x550:rate=25:duration={seconds}");
+        run(
+            "ffmpeg",
+            &[
+                "-y",
+                "-f",
+                "lavfi",
+                "-i",
+                &source,
+                "-pix_fmt",
+                "yuv420p",
+                &format!("{}", path.display()),
+            ],
+        )?;
+        check_output(path, "ffmpeg")
+    }
+
+    /// Grabs a small greyscale frame without the light, ie. to look for motion
+    pub fn capture_frame() -> Result<GrayImage, DeviceError> {
+        if !hardware_enabled() {
+            return Err(DeviceError::NoConnection("camera".to_string()));
+        }
+
+        if prebuffer::is_running() {
+            let path = PathBuf::from(defaults::preroll_dir()).join("frame.jpg");
+            // Without the light any frame will do, so it's the newest one
+            prebuffer::save_still(&path, SystemTime::UNIX_EPOCH)?;
+            return Ok(image::open(&path)?.to_luma8());
         }
 
-        Ok(proc_video_path)
+        let frame = run(
+            "raspistill",
+            &[
+                "-w",
+                "160",
+                "-h",
+                "120",
+                "--timeout",
+                "1",
+                "--nopreview",
+                "-e",
+                "bmp",
+                "-o",
+                "-",
+            ],
+        )?;
+        Ok(image::load_from_memory(&frame)?.to_luma8())
+    }
+
+    /// Grabs a frame from a video and saves it as a .jpg next to it, ie. for previews
+    pub fn thumbnail(video: &Path) -> Result<PathBuf, DeviceError> {
+        let mut thumb_path = video.to_path_buf();
+        let stem = video
+            .file_stem()
+            .map(|s| s.to_string_lossy().to_string())
+            .unwrap_or_default();
+        thumb_path.set_file_name(format!("{stem}_thumb.jpg"));
+
+        trace!("Making a thumbnail of {}", video.display());
+        run(
+            "ffmpeg",
+            &[
+                "-y",
+                "-ss",
+                "1",
+                "-i",
+                &format!("{}", video.display()),
+                "-frames:v",
+                "1",
+                &format!("{}", thumb_path.display()),
+            ],
+        )?;
+        check_output(&thumb_path, "ffmpeg")?;
+        Ok(thumb_path)
     }
 }
 
+/// The tests that capture into `./img` remove it when they're done, so they take turns
+#[cfg(test)]
+pub(crate) static TEST_IMG_DIR: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
 #[cfg(test)]
 mod tests {
     use std::path::PathBuf;
@@ -199,17 +534,110 @@ mod tests {
 
     #[test]
     fn test_capture_and_place_somewhere() {
+        let _img = TEST_IMG_DIR.blocking_lock();
         let dir = PathBuf::from("./img");
         if !dir.exists() {
             std::fs::create_dir(&dir).unwrap();
         }
         assert!(dir.exists());
 
-        let file_path_res = camera::capture_still();
+        let file_path_res = camera::capture_still(None);
         assert!(file_path_res.is_ok());
         let file_path = file_path_res.unwrap();
         assert_eq!(file_path.extension().unwrap(), "jpg");
 
         std::fs::remove_dir_all("./img").unwrap();
     }
+
+    fn door(sequence: &[bool]) -> impl FnMut() -> bool + '_ {
+        let mut polls = sequence.iter();
+        move || *polls.next().expect("polled the door too many times")
+    }
+
+    #[test]
+    fn test_fixed_length() {
+        let mut recording = camera::Recording::new(camera::VideoLength::Fixed { seconds: 5 }, 60);
+        // The door doesn't matter
+        let mut never = door(&[]);
+        assert!(!recording.should_stop(0, &mut never));
+        assert!(!recording.should_stop(4_900, &mut never));
+        assert!(recording.should_stop(5_000, &mut never));
+
+        // The cap wins
+        let mut recording = camera::Recording::new(camera::VideoLength::Fixed { seconds: 90 }, 60);
+        assert!(recording.should_stop(60_000, &mut never));
+
+        // raspivid can stop itself
+        assert!(recording.stops_itself());
+        assert_eq!(recording.limit(), 60);
+        let recording = camera::Recording::new(camera::VideoLength::Fixed { seconds: 5 }, 60);
+        assert_eq!(recording.limit(), 5);
+    }
+
+    #[test]
+    fn test_until_closed() {
+        let mut recording =
+            camera::Recording::new(camera::VideoLength::UntilClosed { after: 3 }, 60);
+        let mut sensor = door(&[true, true, false, false, true, false, false, false]);
+
+        assert!(!recording.should_stop(0, &mut sensor));
+        assert!(!recording.should_stop(10_000, &mut sensor));
+        // Closed at 12s, so it stops at 15s
+        assert!(!recording.should_stop(12_000, &mut sensor));
+        assert!(!recording.should_stop(14_000, &mut sensor));
+        // Opened again, closed again at 16s
+        assert!(!recording.should_stop(15_000, &mut sensor));
+        assert!(!recording.should_stop(16_000, &mut sensor));
+        assert!(!recording.should_stop(18_900, &mut sensor));
+        assert!(recording.should_stop(19_000, &mut sensor));
+
+        // Only we know when the door closed, so it has to be stopped
+        assert!(!recording.stops_itself());
+        assert_eq!(recording.limit(), 60);
+    }
+
+    #[test]
+    fn test_until_closed_cap() {
+        // Left open
+        let mut recording =
+            camera::Recording::new(camera::VideoLength::UntilClosed { after: 3 }, 60);
+        let mut open = || true;
+        assert!(!recording.should_stop(59_000, &mut open));
+        assert!(recording.should_stop(60_000, &mut open));
+    }
+
+    #[test]
+    fn test_remux_fallback() {
+        let dir = std::env::temp_dir().join("modkit-remux-test");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let h264 = dir.join("1.h264");
+        let mp4 = dir.join("1.mp4");
+
+        // Not a video, so ffmpeg can't convert it (if it's even installed)
+        std::fs::write(&h264, b"not really h264").unwrap();
+        assert_eq!(camera::remux(&h264, &mp4), h264);
+        assert!(h264.exists());
+        assert!(!mp4.exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // Needs ffmpeg. Run with `cargo test -- --ignored`
+    #[test]
+    #[ignore]
+    fn test_simulated_video() {
+        let _img = TEST_IMG_DIR.blocking_lock();
+        let dir = PathBuf::from("./img");
+        if !dir.exists() {
+            std::fs::create_dir(&dir).unwrap();
+        }
+
+        let video = camera::capture_video(None).unwrap();
+        assert_eq!(video.path.extension().unwrap(), "mp4");
+        assert!(std::fs::metadata(&video.path).unwrap().len() > 0);
+        assert_eq!(video.duration, 5);
+
+        std::fs::remove_dir_all("./img").unwrap();
+    }
 }
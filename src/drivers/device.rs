@@ -1,12 +1,14 @@
 use std::fmt::Display;
+use std::str::FromStr;
 
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use sqlx::sqlite::SqliteRow;
 use sqlx::Row;
 
 use crate::store::StoreError;
 
-#[derive(Debug, Serialize, Deserialize, PartialEq, Clone, Copy)]
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone, Copy, JsonSchema)]
 pub enum DeviceType {
     Camera,
     Light,
@@ -19,6 +21,23 @@ impl Display for DeviceType {
     }
 }
 
+/// Parses a device type from a url, ie. `/devices/camera`. Case doesn't matter, and
+/// `contact_sensor` works too
+impl FromStr for DeviceType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().replace('_', "").as_str() {
+            "camera" => Ok(DeviceType::Camera),
+            "light" => Ok(DeviceType::Light),
+            "contactsensor" => Ok(DeviceType::ContactSensor),
+            _ => Err(format!(
+                "There's no device `{s}`, try `Camera`, `Light` or `ContactSensor`"
+            )),
+        }
+    }
+}
+
 impl<'r> sqlx::FromRow<'r, SqliteRow> for DeviceType {
     fn from_row(row: &'r SqliteRow) -> std::result::Result<Self, sqlx::Error> {
         let dev_type = match row.try_get("device")? {
@@ -37,3 +56,16 @@ impl<'r> sqlx::FromRow<'r, SqliteRow> for DeviceType {
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_str() {
+        assert_eq!("Camera".parse(), Ok(DeviceType::Camera));
+        assert_eq!("light".parse(), Ok(DeviceType::Light));
+        assert_eq!("ContactSensor".parse(), Ok(DeviceType::ContactSensor));
+        assert_eq!("contact_sensor".parse(), Ok(DeviceType::ContactSensor));
+        assert!("toaster".parse::<DeviceType>().is_err());
+    }
+}
@@ -4,10 +4,43 @@ pub mod light {
     use crate::defaults;
     use rppal::gpio::{Gpio, OutputPin};
     use log::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    /// Set while a video is being recorded, so nothing else turns the light off on it
+    static HELD: AtomicBool = AtomicBool::new(false);
+    /// What the light would be set to, when there's no hardware
+    static SIMULATED: AtomicBool = AtomicBool::new(false);
+
+    /// Held by tests that use the light
+    #[cfg(test)]
+    pub(crate) static TEST_LIGHT: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+    /// Turns the light on and keeps it on until [`release`], ie. for a recording
+    pub fn hold() -> Result<(), DeviceError> {
+        set(true)?;
+        HELD.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Lets go of the light after [`hold`], and turns it off
+    pub fn release() -> Result<(), DeviceError> {
+        HELD.store(false, Ordering::SeqCst);
+        set(false)
+    }
+
+    pub fn is_held() -> bool {
+        HELD.load(Ordering::SeqCst)
+    }
 
     pub fn set(state: bool) -> Result<(), DeviceError> {
+        if !state && is_held() {
+            trace!("Leaving the light on, something's recording");
+            return Ok(());
+        }
+
         if !hardware_enabled() {
             warn!("You tried to use the light when hardware is not enabled");
+            SIMULATED.store(state, Ordering::SeqCst);
             return Ok(());
         }
 
@@ -43,7 +76,7 @@ pub mod light {
     pub fn is_on() -> Result<bool, DeviceError> {
         if !hardware_enabled() {
             warn!("You tried to use the light when hardware is not enabled");
-            return Ok(false);
+            return Ok(SIMULATED.load(Ordering::SeqCst));
         }
         
         let pin_numbers = defaults::light_gpio_pins();
@@ -65,6 +98,7 @@ mod tests {
 
     #[test]
     fn test_on_off() {
+        let _light = light::TEST_LIGHT.blocking_lock();
         if hardware_enabled() {
             assert!(light::set(true).is_ok());
             let mut status = light::is_on().unwrap();
@@ -74,4 +108,18 @@ mod tests {
             assert!(!status);
         }
     }
+
+    #[test]
+    fn test_hold() {
+        let _light = light::TEST_LIGHT.blocking_lock();
+        light::hold().unwrap();
+        assert!(light::is_held());
+        // Turning it off does nothing while it's held
+        light::set(false).unwrap();
+        assert!(light::is_on().unwrap());
+
+        light::release().unwrap();
+        assert!(!light::is_held());
+        assert!(!light::is_on().unwrap());
+    }
 }
@@ -1,5 +1,10 @@
 use std::io;
+use std::path::Path;
+use std::process::{Child, Command, Output};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
 
+use log::*;
 use thiserror::Error;
 use rppal::gpio;
 
@@ -11,6 +16,7 @@ pub mod device;
 pub mod contact_sensor;
 pub mod camera;
 pub mod light;
+pub mod prebuffer;
 
 #[allow(unused)]
 #[derive(Error, Debug, PartialEq)]
@@ -26,14 +32,96 @@ pub enum DeviceError {
     #[error("Image error: {0}")]
     ImageError(String),
     #[error("IO error: {0}")]
-    IoError(String)
+    IoError(String),
+    /// A program like raspistill or ffmpeg ran, but failed
+    #[error("`{command}` failed ({status}): {stderr}")]
+    CommandFailed {
+        command: String,
+        status: String,
+        /// The end of what it printed to stderr
+        stderr: String,
+    },
 }
 
+/// How many lines of stderr to keep in a CommandFailed. ffmpeg prints a lot before the error
+const STDERR_LINES: usize = 5;
+/// How long a program gets to exit after being interrupted, before it's killed
+const INTERRUPT_GRACE: Duration = Duration::from_secs(5);
+
 pub fn hardware_enabled() -> bool {
     // Try to connect to the GPIO, if we can't then there's no hardware
     rppal::gpio::Gpio::new().is_ok()
 }
 
+/// Runs a program and returns what it printed to stdout. It's an error if it can't be
+/// started or doesn't exit successfully
+pub(crate) fn run(program: &str, args: &[&str]) -> Result<Vec<u8>> {
+    let output = Command::new(program)
+        .args(args)
+        .output()
+        .map_err(|e| DeviceError::IoError(format!("couldn't run `{program}`: {e}")))?;
+
+    if !output.status.success() {
+        return Err(command_failed(program, &output));
+    }
+    Ok(output.stdout)
+}
+
+/// A CommandFailed with the end of the program's stderr
+pub(crate) fn command_failed(program: &str, output: &Output) -> DeviceError {
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let lines: Vec<&str> = stderr.lines().filter(|l| !l.trim().is_empty()).collect();
+    DeviceError::CommandFailed {
+        command: program.to_string(),
+        status: output.status.to_string(),
+        stderr: lines[lines.len().saturating_sub(STDERR_LINES)..].join("\n"),
+    }
+}
+
+/// Waits up to `timeout` for a program to exit. Returns whether it did
+pub(crate) fn wait_for_exit(child: &mut Child, timeout: Duration) -> bool {
+    let started = Instant::now();
+    loop {
+        // If we can't tell, don't wait around
+        if !matches!(child.try_wait(), Ok(None)) {
+            return true;
+        }
+        if started.elapsed() >= timeout {
+            return false;
+        }
+        sleep(Duration::from_millis(50));
+    }
+}
+
+/// Stops a program like Ctrl-C would (SIGINT), so it can finish writing its file. Killing
+/// raspivid or raspistill leaves the camera's memory allocated, and the next one fails.
+/// It's only killed if it doesn't stop in a few seconds
+pub(crate) fn interrupt(program: &str, child: &mut Child) {
+    if let Err(e) = run("kill", &["-INT", &child.id().to_string()]) {
+        warn!("Couldn't interrupt `{program}`: {e}");
+    }
+    if !wait_for_exit(child, INTERRUPT_GRACE) {
+        warn!("`{program}` didn't stop when interrupted, killing it");
+        let _ = child.kill();
+    }
+}
+
+/// Makes sure a program actually wrote the file it was meant to. Some exit successfully
+/// without writing anything
+pub(crate) fn check_output(path: &Path, program: &str) -> Result<()> {
+    match std::fs::metadata(path) {
+        Ok(metadata) if metadata.len() > 0 => Ok(()),
+        Ok(_) => Err(DeviceError::IoError(format!(
+            "`{program}` wrote an empty {}",
+            path.display()
+        ))),
+        Err(_) => Err(DeviceError::IoError(format!(
+            "`{program}` didn't write {}",
+            path.display()
+        ))),
+    }
+}
+
 /// gpio::Error doesn't implement PartialEq, so it can't be automatically
 /// converted. I'll open a pull request.
 impl From<gpio::Error> for DeviceError {
@@ -62,3 +150,62 @@ impl Into<Event> for DeviceError {
 
 /// A custom error type using the DeviceError defined above
 pub type Result<T> = std::result::Result<T, DeviceError>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_run() {
+        assert_eq!(run("echo", &["hello"]).unwrap(), b"hello\n");
+
+        let error = run("sh", &["-c", "echo one >&2; echo two >&2; exit 3"]).unwrap_err();
+        assert_eq!(
+            error,
+            DeviceError::CommandFailed {
+                command: "sh".to_string(),
+                status: "exit status: 3".to_string(),
+                stderr: "one\ntwo".to_string(),
+            }
+        );
+
+        // Only the end of a long stderr is kept
+        let error = run("sh", &["-c", "seq 1 20 >&2; exit 1"]).unwrap_err();
+        match error {
+            DeviceError::CommandFailed { stderr, .. } => assert_eq!(stderr, "16\n17\n18\n19\n20"),
+            other => panic!("{:?}", other),
+        }
+
+        assert!(matches!(
+            run("modkit-not-a-real-program", &[]),
+            Err(DeviceError::IoError(_))
+        ));
+    }
+
+    #[test]
+    fn test_interrupt() {
+        use std::os::unix::process::ExitStatusExt;
+
+        let mut child = Command::new("sleep").arg("30").spawn().unwrap();
+        assert!(!wait_for_exit(&mut child, Duration::from_millis(100)));
+
+        let started = Instant::now();
+        interrupt("sleep", &mut child);
+        assert!(started.elapsed() < INTERRUPT_GRACE);
+        assert_eq!(child.wait().unwrap().signal(), Some(2));
+    }
+
+    #[test]
+    fn test_check_output() {
+        let path = std::env::temp_dir().join("modkit-check-output-test");
+        let _ = std::fs::remove_file(&path);
+        assert!(check_output(&path, "raspistill").is_err());
+
+        std::fs::write(&path, b"").unwrap();
+        assert!(check_output(&path, "raspistill").is_err());
+
+        std::fs::write(&path, b"jpeg").unwrap();
+        assert!(check_output(&path, "raspistill").is_ok());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,347 @@
+/// Keeps the camera recording all the time into a ring of short segments (on a tmpfs by
+/// default), so a video can start a few seconds before whatever triggered it.
+///
+/// `raspivid` writes one second segments (`seg0000.h264`, `seg0001.h264`, ...) and wraps around
+/// after enough of them to hold the pre-roll and the longest recording. Every segment starts with a key frame
+/// and the stream headers, so they can be stuck together byte for byte into one valid stream.
+///
+/// While this is running `raspistill` can't get to the camera, so `camera` takes stills and
+/// videos from here instead.
+pub mod prebuffer {
+    use std::fs;
+    use std::io::Write;
+    use std::path::{Path, PathBuf};
+    use std::process::{Child, Command, Stdio};
+    use std::sync::Mutex;
+    use std::thread::sleep;
+    use std::time::{Duration, SystemTime};
+
+    use log::*;
+
+    use super::super::{check_output, interrupt, run, DeviceError};
+    use crate::defaults;
+
+    /// How long each segment is
+    const SEGMENT_MS: u32 = 1000;
+    /// How long the camera takes to adjust to the light coming on
+    const SETTLE_MS: u64 = 200;
+
+    static RECORDER: Mutex<Option<Recorder>> = Mutex::new(None);
+
+    struct Recorder {
+        dir: PathBuf,
+        process: Child,
+    }
+
+    impl Drop for Recorder {
+        fn drop(&mut self) {
+            // Killing it would leave the camera unusable until a reboot
+            interrupt("raspivid", &mut self.process);
+            let _ = self.process.wait();
+        }
+    }
+
+    /// Starts recording into the ring buffer in `MODKIT_PREROLL_DIR`. `longest` is the most
+    /// seconds a video can go on for after it's triggered
+    pub fn start(pre_roll: u32, longest: u32) -> Result<(), DeviceError> {
+        let dir = PathBuf::from(defaults::preroll_dir());
+        fs::create_dir_all(&dir)?;
+        // Anything left from last time would get mixed in
+        for segment in segments(&dir)? {
+            fs::remove_file(segment.0)?;
+        }
+
+        // Enough to cover both, plus the one being written and one to spare
+        let wrap = (pre_roll + longest) * 1000 / SEGMENT_MS + 2;
+        let segment_ms = SEGMENT_MS.to_string();
+        let wrap = wrap.to_string();
+        let output = format!("{}", dir.join("seg%04d.h264").display());
+        let mut args = vec![
+            "-t",
+            "0",
+            "-sg",
+            &segment_ms,
+            "-wr",
+            &wrap,
+            "-ih",
+            "-g",
+            "25",
+            "-w",
+            "800",
+            "-h",
+            "550",
+            "-fps",
+            "25",
+            "--nopreview",
+        ];
+        if defaults::flip_vertical() {
+            args.push("-vf");
+        }
+        args.push("-o");
+        args.push(&output);
+
+        info!("Starting the pre-roll ring buffer in {}", dir.display());
+        trace!("Ring buffer command = `raspivid {:?}`", args);
+        let process = Command::new("raspivid")
+            .args(&args)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()?;
+
+        *RECORDER.lock().expect("ring buffer lock") = Some(Recorder {
+            dir,
+            process,
+        });
+        Ok(())
+    }
+
+    /// Stops recording. The camera can be used normally again after this.
+    /// It can take a few seconds for raspivid to stop, so this blocks
+    pub fn stop() {
+        // Taken out first so nothing waits on the lock while it stops
+        let recorder = RECORDER.lock().expect("ring buffer lock").take();
+        if let Some(recorder) = recorder {
+            drop(recorder);
+            info!("Stopped the pre-roll ring buffer");
+        }
+    }
+
+    pub fn is_running() -> bool {
+        RECORDER.lock().expect("ring buffer lock").is_some()
+    }
+
+    /// Writes everything recorded since `from` to `output` as one h264 stream. Call it once
+    /// the video should end, with `from` set to the pre-roll before it was triggered
+    pub fn save_video(output: &Path, from: SystemTime) -> Result<(), DeviceError> {
+        let dir = dir()?;
+
+        let selected = select_segments(&segments(&dir)?, from);
+        trace!("Saving {} segments to {}", selected.len(), output.display());
+        if selected.is_empty() {
+            return Err(DeviceError::IoError(format!(
+                "the ring buffer in {} is empty",
+                dir.display()
+            )));
+        }
+        concat(&selected, output)
+    }
+
+    /// Saves a frame from after `lit_at` (when the light came on) as a jpg. It's the first frame
+    /// of the newest finished segment that started after that, so this might wait for one
+    pub fn save_still(output: &Path, lit_at: SystemTime) -> Result<(), DeviceError> {
+        let dir = dir()?;
+        // The one being written when the light came on, the next one and then some
+        let give_up = lit_at + Duration::from_millis(3 * SEGMENT_MS as u64 + SETTLE_MS);
+        let segment = loop {
+            if let Some(segment) = lit_segment(&segments(&dir)?, lit_at) {
+                break segment;
+            }
+            if SystemTime::now() > give_up {
+                return Err(DeviceError::IoError(format!(
+                    "nothing in the ring buffer in {} was recorded with the light on",
+                    dir.display()
+                )));
+            }
+            sleep(Duration::from_millis(100));
+        };
+
+        run(
+            "ffmpeg",
+            &[
+                "-y",
+                "-f",
+                "h264",
+                "-i",
+                &format!("{}", segment.display()),
+                "-frames:v",
+                "1",
+                &format!("{}", output.display()),
+            ],
+        )?;
+        check_output(output, "ffmpeg")
+    }
+
+    fn dir() -> Result<PathBuf, DeviceError> {
+        match RECORDER.lock().expect("ring buffer lock").as_ref() {
+            Some(r) => Ok(r.dir.clone()),
+            None => Err(DeviceError::NoConnection(
+                "the ring buffer isn't running".to_string(),
+            )),
+        }
+    }
+
+    /// The segment files in a dir, and when each was last written to
+    fn segments(dir: &Path) -> Result<Vec<(PathBuf, SystemTime)>, DeviceError> {
+        let mut found = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == "h264") {
+                let modified = fs::metadata(&path)?.modified()?;
+                found.push((path, modified));
+            }
+        }
+        Ok(found)
+    }
+
+    /// The segments that have anything in them from `from` on, oldest first.
+    /// A segment's modified time is when it ended
+    fn select_segments(segments: &[(PathBuf, SystemTime)], from: SystemTime) -> Vec<PathBuf> {
+        let mut selected: Vec<&(PathBuf, SystemTime)> = segments
+            .iter()
+            .filter(|(_, modified)| *modified >= from)
+            .collect();
+        selected.sort_by_key(|(path, modified)| (*modified, path.clone()));
+        selected.into_iter().map(|(path, _)| path.clone()).collect()
+    }
+
+    /// The newest finished segment that started once the light had been on for a bit. A segment
+    /// starts when the one before it ends, and the newest one is still being written
+    fn lit_segment(segments: &[(PathBuf, SystemTime)], lit_at: SystemTime) -> Option<PathBuf> {
+        let mut sorted: Vec<&(PathBuf, SystemTime)> = segments.iter().collect();
+        sorted.sort_by_key(|(path, modified)| (*modified, path.clone()));
+        let finished = sorted.len().checked_sub(1)?;
+
+        let settled = lit_at + Duration::from_millis(SETTLE_MS);
+        (0..finished)
+            .rfind(|&i| {
+                let started = match i {
+                    0 => sorted[i].1 - Duration::from_millis(SEGMENT_MS as u64),
+                    _ => sorted[i - 1].1,
+                };
+                started >= settled
+            })
+            .map(|i| sorted[i].0.clone())
+    }
+
+    /// Sticks the segments together into one file
+    fn concat(segments: &[PathBuf], output: &Path) -> Result<(), DeviceError> {
+        let mut out = fs::File::create(output)?;
+        for segment in segments {
+            out.write_all(&fs::read(segment)?)?;
+        }
+        Ok(())
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use std::time::Duration;
+
+        use super::*;
+
+        fn test_dir(name: &str) -> PathBuf {
+            let dir = std::env::temp_dir().join(format!("modkit-prebuffer-{name}"));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            dir
+        }
+
+        // Writes a segment that finished `age` seconds before `now`
+        fn segment(dir: &Path, name: &str, now: SystemTime, age: u64) {
+            let path = dir.join(name);
+            fs::write(&path, name.as_bytes()).unwrap();
+            fs::File::options()
+                .write(true)
+                .open(&path)
+                .unwrap()
+                .set_modified(now - Duration::from_secs(age))
+                .unwrap();
+        }
+
+        #[test]
+        fn test_select_and_concat() {
+            let dir = test_dir("select");
+            let now = SystemTime::now();
+
+            // The ring has wrapped around, so the names aren't in time order
+            segment(&dir, "seg0003.h264", now, 6);
+            segment(&dir, "seg0004.h264", now, 5);
+            segment(&dir, "seg0005.h264", now, 4);
+            segment(&dir, "seg0000.h264", now, 3);
+            segment(&dir, "seg0001.h264", now, 2);
+            segment(&dir, "seg0002.h264", now, 1);
+            fs::write(dir.join("notes.txt"), b"not a segment").unwrap();
+
+            let found = segments(&dir).unwrap();
+            assert_eq!(found.len(), 6);
+
+            let selected = select_segments(&found, now - Duration::from_secs(4));
+            assert_eq!(
+                selected,
+                vec![
+                    dir.join("seg0005.h264"),
+                    dir.join("seg0000.h264"),
+                    dir.join("seg0001.h264"),
+                    dir.join("seg0002.h264"),
+                ]
+            );
+
+            let output = dir.join("out.h264");
+            concat(&selected, &output).unwrap();
+            assert_eq!(
+                fs::read_to_string(&output).unwrap(),
+                "seg0005.h264seg0000.h264seg0001.h264seg0002.h264"
+            );
+
+            fs::remove_dir_all(&dir).unwrap();
+        }
+
+        #[test]
+        fn test_lit_segment() {
+            let now = SystemTime::now();
+            let ended = |secs_ago: f64| now - Duration::from_secs_f64(secs_ago);
+            let ring = |ends: &[(&str, f64)]| -> Vec<(PathBuf, SystemTime)> {
+                ends.iter()
+                    .map(|(name, secs_ago)| (PathBuf::from(name), ended(*secs_ago)))
+                    .collect()
+            };
+
+            // The light came on half way through seg0002, which is still being written
+            let lit_at = ended(0.5);
+            let segments = ring(&[
+                ("seg0000.h264", 2.0),
+                ("seg0001.h264", 1.0),
+                ("seg0002.h264", 0.0),
+            ]);
+            assert_eq!(lit_segment(&segments, lit_at), None);
+
+            // seg0002 has finished, but it was dark for the start of it
+            let segments = ring(&[
+                ("seg0001.h264", 1.5),
+                ("seg0002.h264", 0.5),
+                ("seg0003.h264", 0.0),
+            ]);
+            assert_eq!(lit_segment(&segments, ended(1.0)), None);
+
+            // seg0003 and seg0000 started after the light came on and have finished
+            let segments = ring(&[
+                ("seg0002.h264", 2.5),
+                ("seg0003.h264", 1.5),
+                ("seg0000.h264", 0.5),
+                ("seg0001.h264", 0.0),
+            ]);
+            assert_eq!(
+                lit_segment(&segments, ended(3.0)),
+                Some(PathBuf::from("seg0000.h264"))
+            );
+
+            // Not if the light only came on right before seg0000 started, the camera was
+            // still adjusting
+            assert_eq!(lit_segment(&segments, ended(1.6)), None);
+
+            // Without the light it's just the newest finished one
+            assert_eq!(
+                lit_segment(&segments, SystemTime::UNIX_EPOCH),
+                Some(PathBuf::from("seg0000.h264"))
+            );
+
+            assert_eq!(lit_segment(&[], lit_at), None);
+        }
+
+        #[test]
+        fn test_not_running() {
+            assert!(!is_running());
+            assert!(save_video(Path::new("nowhere.h264"), SystemTime::now()).is_err());
+            assert!(save_still(Path::new("nowhere.jpg"), SystemTime::now()).is_err());
+        }
+    }
+}
@@ -5,6 +5,15 @@ pub mod store;
 pub mod server;
 pub mod watchdog;
 pub mod defaults;
+pub mod vision;
+pub mod notify;
+pub mod mqtt;
+pub mod schedule;
+pub mod rules;
+pub mod security;
+pub mod shutdown;
+pub mod supervisor;
+pub mod metrics;
 
 pub mod prelude {
     pub use crate::drivers::{
@@ -17,6 +26,10 @@ pub mod prelude {
     };
     pub use crate::watchdog;
     pub use crate::server;
+    pub use crate::notify;
+    pub use crate::mqtt;
     pub use crate::store::Store;
     pub use crate::defaults;
+    pub use crate::shutdown;
+    pub use crate::supervisor;
 }
@@ -0,0 +1,254 @@
+//! Prometheus metrics, served at `/metrics`.
+//!
+//! Most are updated as things happen: the watchdog counts door openings and how long the door
+//! was open, the camera times its captures and the store times its writes. The rest (connected
+//! clients and how much is in `img_dir`) are read when the metrics are scraped.
+use std::fs;
+use std::path::Path;
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+use log::*;
+use prometheus::core::Collector;
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, Opts,
+    Registry, TextEncoder,
+};
+
+use crate::defaults;
+use crate::server::Clients;
+
+static METRICS: OnceLock<Metrics> = OnceLock::new();
+
+struct Metrics {
+    registry: Registry,
+    door_opens: IntCounter,
+    door_open_seconds: Histogram,
+    capture_seconds: HistogramVec,
+    capture_failures: IntCounterVec,
+    img_dir_bytes: IntGauge,
+    img_dir_files: IntGauge,
+    clients: IntGauge,
+    db_write_seconds: Histogram,
+    watchdog_lag_seconds: Histogram,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        // The names and buckets are all fixed, so none of these can fail
+        let histogram = |name: &str, help: &str, buckets: Vec<f64>| {
+            Histogram::with_opts(HistogramOpts::new(name, help).buckets(buckets)).expect(name)
+        };
+        let metrics = Metrics {
+            registry: Registry::new(),
+            door_opens: IntCounter::new(
+                "modkit_door_opens_total",
+                "How many times the door opened",
+            )
+            .expect("door opens"),
+            door_open_seconds: histogram(
+                "modkit_door_open_seconds",
+                "How long the door was open for",
+                vec![1.0, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0, 600.0, 1800.0],
+            ),
+            capture_seconds: HistogramVec::new(
+                HistogramOpts::new(
+                    "modkit_capture_seconds",
+                    "How long taking a still or recording a video took, including failures",
+                )
+                .buckets(vec![0.5, 1.0, 2.0, 5.0, 10.0, 20.0, 30.0, 60.0, 120.0]),
+                &["kind"],
+            )
+            .expect("capture seconds"),
+            capture_failures: IntCounterVec::new(
+                Opts::new(
+                    "modkit_capture_failures_total",
+                    "How many stills or videos couldn't be taken",
+                ),
+                &["kind"],
+            )
+            .expect("capture failures"),
+            img_dir_bytes: IntGauge::new(
+                "modkit_img_dir_bytes",
+                "How much space the pictures and videos in MODKIT_IMG_DIR take up",
+            )
+            .expect("img dir bytes"),
+            img_dir_files: IntGauge::new(
+                "modkit_img_dir_files",
+                "How many files are in MODKIT_IMG_DIR",
+            )
+            .expect("img dir files"),
+            clients: IntGauge::new(
+                "modkit_websocket_clients",
+                "How many clients are connected, over websockets or the event stream",
+            )
+            .expect("clients"),
+            db_write_seconds: histogram(
+                "modkit_db_write_seconds",
+                "How long saving an event to the database took",
+                prometheus::exponential_buckets(0.0005, 2.0, 12).expect("db write buckets"),
+            ),
+            watchdog_lag_seconds: histogram(
+                "modkit_watchdog_lag_seconds",
+                "How much later than planned the watchdog got back to checking the door, ie. \
+                 because it was recording a video",
+                vec![0.01, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0],
+            ),
+        };
+
+        let collectors: Vec<Box<dyn Collector>> = vec![
+            Box::new(metrics.door_opens.clone()),
+            Box::new(metrics.door_open_seconds.clone()),
+            Box::new(metrics.capture_seconds.clone()),
+            Box::new(metrics.capture_failures.clone()),
+            Box::new(metrics.img_dir_bytes.clone()),
+            Box::new(metrics.img_dir_files.clone()),
+            Box::new(metrics.clients.clone()),
+            Box::new(metrics.db_write_seconds.clone()),
+            Box::new(metrics.watchdog_lag_seconds.clone()),
+        ];
+        for collector in collectors {
+            metrics
+                .registry
+                .register(collector)
+                .expect("register metric");
+        }
+        // So they show up as 0 before anything's been captured
+        for kind in ["still", "video"] {
+            metrics.capture_seconds.with_label_values(&[kind]);
+            metrics.capture_failures.with_label_values(&[kind]);
+        }
+        metrics
+    }
+}
+
+fn metrics() -> &'static Metrics {
+    METRICS.get_or_init(Metrics::new)
+}
+
+pub fn door_opened() {
+    metrics().door_opens.inc();
+}
+
+/// The door closed after being open for `open_for` seconds
+pub fn door_closed(open_for: u32) {
+    metrics().door_open_seconds.observe(open_for as f64);
+}
+
+/// Times a capture, and counts it if it fails. `kind` is `still` or `video`
+pub fn capture<T, E>(kind: &str, capture: impl FnOnce() -> Result<T, E>) -> Result<T, E> {
+    let started = Instant::now();
+    let result = capture();
+    metrics()
+        .capture_seconds
+        .with_label_values(&[kind])
+        .observe(started.elapsed().as_secs_f64());
+    if result.is_err() {
+        metrics().capture_failures.with_label_values(&[kind]).inc();
+    }
+    result
+}
+
+pub fn db_write(took: Duration) {
+    metrics().db_write_seconds.observe(took.as_secs_f64());
+}
+
+pub fn watchdog_lag(lag: Duration) {
+    metrics().watchdog_lag_seconds.observe(lag.as_secs_f64());
+}
+
+/// How many bytes and files are in a directory, including any under it
+fn dir_usage(dir: &Path) -> std::io::Result<(u64, u64)> {
+    let mut usage = (0, 0);
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            let (bytes, files) = dir_usage(&entry.path())?;
+            usage.0 += bytes;
+            usage.1 += files;
+        } else {
+            usage.0 += metadata.len();
+            usage.1 += 1;
+        }
+    }
+    Ok(usage)
+}
+
+/// Everything, in the Prometheus text format
+pub async fn render(clients: &Clients) -> String {
+    let metrics = metrics();
+
+    let connected = clients
+        .lock()
+        .await
+        .values()
+        .filter(|client| client.sender.is_some())
+        .count();
+    metrics.clients.set(connected as i64);
+
+    // There can be a lot of pictures, don't hold up the server while we add them up
+    let img_dir = defaults::img_dir();
+    match tokio::task::spawn_blocking(move || dir_usage(Path::new(&img_dir))).await {
+        Ok(Ok((bytes, files))) => {
+            metrics.img_dir_bytes.set(bytes as i64);
+            metrics.img_dir_files.set(files as i64);
+        }
+        Ok(Err(e)) => warn!("Couldn't add up the image directory for the metrics: {e}"),
+        Err(e) => warn!("Image directory task failed: {e}"),
+    }
+
+    let mut buffer = Vec::new();
+    if let Err(e) = TextEncoder::new().encode(&metrics.registry.gather(), &mut buffer) {
+        error!("Couldn't encode the metrics: {e}");
+    }
+    String::from_utf8(buffer).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    use tokio::sync::Mutex;
+
+    use super::*;
+
+    #[test]
+    fn test_dir_usage() {
+        let dir = std::env::temp_dir().join(format!("modkit-metrics-{}", std::process::id()));
+        fs::create_dir_all(dir.join("thumbnails")).unwrap();
+        fs::write(dir.join("1.jpg"), b"abc").unwrap();
+        fs::write(dir.join("2.mp4"), b"abcde").unwrap();
+        fs::write(dir.join("thumbnails").join("1.jpg"), b"a").unwrap();
+
+        assert_eq!(dir_usage(&dir).unwrap(), (9, 3));
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(dir_usage(&dir).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_render() {
+        door_opened();
+        door_closed(42);
+        let failed: Result<(), &str> = capture("still", || Err("no camera"));
+        assert!(failed.is_err());
+        db_write(Duration::from_millis(3));
+        watchdog_lag(Duration::from_millis(20));
+
+        let clients: Clients = Arc::new(Mutex::new(HashMap::new()));
+        let text = render(&clients).await;
+        for name in [
+            "modkit_door_opens_total",
+            "modkit_door_open_seconds_bucket",
+            "modkit_capture_seconds_count{kind=\"still\"}",
+            "modkit_capture_failures_total{kind=\"still\"}",
+            "modkit_img_dir_bytes",
+            "modkit_websocket_clients",
+            "modkit_db_write_seconds_sum",
+            "modkit_watchdog_lag_seconds_count",
+        ] {
+            assert!(text.contains(name), "{:?} isn't in {:?}", name, text);
+        }
+    }
+}
@@ -1,8 +1,11 @@
 use std::fmt;
 
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use sqlx::{sqlite::SqliteRow, Row};
 
+use crate::rules::Rule;
+use crate::security::AccessReason;
 use crate::store::StoreError;
 
 use super::Event;
@@ -12,7 +15,7 @@ use super::Event;
 // with.
 
 /// A bundle of data. This could take multiple formats, depending on which device the data is taken from.
-#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, JsonSchema)]
 pub enum Bundle {
     /// The data from a contact sensor. Just open or closed.
     ContactSensor {
@@ -23,6 +26,9 @@ pub enum Bundle {
     },
     Camera {
         file_name: String,
+        /// How long a video is in seconds, including any pre-roll. Not set for stills
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        duration: Option<u32>,
     },
     Light {
         on: bool,
@@ -33,9 +39,94 @@ pub enum Bundle {
     PinResult {
         authorized: bool,
     },
+    /// Sent by a client to correct the mail status by hand
+    SetMailStatus {
+        delivered: bool,
+        /// Who is making the correction, shows up in the history
+        name: Option<String>,
+    },
+    /// Stats about the mail, sent in response to MailSummary
+    MailSummary {
+        /// How many deliveries there have been since the mail was last picked up
+        deliveries_since_pickup: u32,
+        /// Timestamp of the first delivery that hasn't been picked up yet
+        waiting_since: Option<u32>,
+        /// Average time of day of deliveries over the last 30 days, in seconds after midnight
+        average_delivery_time: Option<u32>,
+        /// Whole days since the mail was last picked up
+        days_since_pickup: Option<u32>,
+    },
+    /// Sent with MailDelivered/MailPickedUp when the status was set by hand
+    ManualMailStatus {
+        corrected_by: String,
+    },
     EventHistory {
         events: Vec<Event>,
     },
+    /// Sent with MailDelivered/MailPickedUp when the camera decided which one it was
+    MailClassification {
+        /// How sure the classifier is, from 0 to 1
+        confidence: f32,
+        /// The still taken after the door closed
+        file_name: String,
+    },
+    /// Sent with DoorLeftOpen, and DoorClosed once it's shut again
+    DoorOpenTime {
+        /// Timestamp of when the door opened
+        opened_at: u32,
+        /// How many seconds it's been open
+        open_for: u32,
+    },
+    /// Sent with SuspiciousAccess
+    SuspiciousAccess {
+        reasons: Vec<AccessReason>,
+        /// A still taken when the door opened, if we could get one
+        file_name: Option<String>,
+    },
+    /// Sent with MotionDetected
+    Motion {
+        /// How much of the picture changed, from 0 to 1
+        changed: f32,
+        /// A still taken when the motion was noticed, if we could get one
+        file_name: Option<String>,
+    },
+    /// Whether the mailbox is armed, sent in response to ArmState and SetArmState
+    ArmState {
+        armed: bool,
+        /// Who armed or disarmed it last
+        changed_by: Option<String>,
+        /// Timestamp of when that was
+        changed_at: Option<u32>,
+    },
+    /// Sent by a client to arm or disarm the mailbox. Needs the PIN every time, like an
+    /// alarm keypad
+    SetArmState {
+        armed: bool,
+        pin: u16,
+        /// Who is doing it, shows up in the ArmState
+        name: Option<String>,
+    },
+    /// All the rules, sent in response to Rules, SetRule and DeleteRule
+    Rules {
+        rules: Vec<Rule>,
+    },
+    /// Sent by a client to add a rule, or replace one if it has an id
+    SetRule {
+        rule: Rule,
+    },
+    DeleteRule {
+        id: i64,
+    },
+    /// Sent with SubsystemFailed
+    SubsystemFailed {
+        /// Which part of the daemon failed, ie. `watchdog`
+        subsystem: String,
+        error: String,
+        /// How many times it's been restarted since the daemon started, including this one
+        restarts: u32,
+        /// Seconds until it's started again
+        retry_in: u32,
+    },
 }
 
 impl Bundle {
@@ -55,8 +146,10 @@ impl Bundle {
 
 impl<'r> sqlx::FromRow<'r, SqliteRow> for Bundle {
     fn from_row(row: &'r SqliteRow) -> Result<Self, sqlx::Error> {
-        let json_data = row.try_get("data")?;
+        let json_data: &str = row.try_get("data")?;
         let bundle: Bundle = serde_json::from_str(json_data)
+            // Older rows were written with their quotes doubled up, so give those a shot too
+            .or_else(|_| serde_json::from_str(&json_data.replace(r#""""#, r#"""#)))
             // This is a bit messy but it works for now
             .map_err(|e| StoreError::DecodeError(format!("{e}")).into_sqlx_decode_error())?;
         Ok(bundle)
@@ -71,13 +164,70 @@ impl fmt::Display for Bundle {
             Self::ContactSensor { open } => {
                 return write!(f, "ContactSensor({})", open);
             }
-            Self::Camera { file_name } => return write!(f, "Camera({file_name})"),
+            Self::Camera {
+                file_name,
+                duration: Some(duration),
+            } => write!(f, "Camera({file_name}, {duration}s)"),
+            Self::Camera { file_name, .. } => write!(f, "Camera({file_name})"),
             Self::Light { on } => return write!(f, "Light(on: {on})"),
             Self::Error { msg } => return write!(f, "Error({msg})"),
             Self::PinCheck { pin } => return write!(f, "PinCheck({pin})"),
             Self::PinResult { authorized } => {
                 return write!(f, "PinResult(authorized: {authorized})")
             }
+            Self::MailClassification {
+                confidence,
+                file_name,
+            } => write!(f, "MailClassification({confidence:.2}, {file_name})"),
+            Self::SetMailStatus { delivered, name } => {
+                write!(f, "SetMailStatus(delivered: {delivered}, name: {name:?})")
+            }
+            Self::MailSummary {
+                deliveries_since_pickup,
+                waiting_since,
+                average_delivery_time,
+                days_since_pickup,
+            } => write!(
+                f,
+                "MailSummary(deliveries: {deliveries_since_pickup}, waiting since: {waiting_since:?}, average time: {average_delivery_time:?}, days since pickup: {days_since_pickup:?})"
+            ),
+            Self::ManualMailStatus { corrected_by } => {
+                write!(f, "ManualMailStatus(corrected_by: {corrected_by})")
+            }
+            Self::DoorOpenTime {
+                opened_at,
+                open_for,
+            } => write!(f, "DoorOpenTime(opened at: {opened_at}, open for: {open_for}s)"),
+            Self::SuspiciousAccess { reasons, file_name } => {
+                write!(f, "SuspiciousAccess({reasons:?}, {file_name:?})")
+            }
+            Self::Motion { changed, file_name } => {
+                write!(f, "Motion({changed:.2}, {file_name:?})")
+            }
+            Self::ArmState {
+                armed,
+                changed_by,
+                changed_at,
+            } => write!(
+                f,
+                "ArmState(armed: {armed}, changed by: {changed_by:?}, at: {changed_at:?})"
+            ),
+            // Leave the PIN out of the logs
+            Self::SetArmState { armed, name, .. } => {
+                write!(f, "SetArmState(armed: {armed}, name: {name:?})")
+            }
+            Self::Rules { rules } => write!(f, "Rules({} rules)", rules.len()),
+            Self::SetRule { rule } => write!(f, "SetRule({:?}, {})", rule.id, rule.name),
+            Self::DeleteRule { id } => write!(f, "DeleteRule({id})"),
+            Self::SubsystemFailed {
+                subsystem,
+                error,
+                restarts,
+                retry_in,
+            } => write!(
+                f,
+                "SubsystemFailed({subsystem}: {error}, restart {restarts} in {retry_in}s)"
+            ),
             Self::EventHistory { events } => {
                 // This is a little bit fucked but oh well
                 for e in events {
@@ -101,4 +251,26 @@ mod tests {
             _ => assert!(false),
         }
     }
+
+    #[test]
+    fn test_camera_duration() {
+        let video = Bundle::Camera {
+            file_name: "1234.mp4".to_string(),
+            duration: Some(12),
+        };
+        let json = serde_json::to_string(&video).unwrap();
+        assert_eq!(serde_json::from_str::<Bundle>(&json).unwrap(), video);
+
+        // Stills and older events don't have one
+        let still: Bundle =
+            serde_json::from_str(r#"{"Camera":{"file_name":"1234.jpg"}}"#).unwrap();
+        assert_eq!(
+            still,
+            Bundle::Camera {
+                file_name: "1234.jpg".to_string(),
+                duration: None,
+            }
+        );
+        assert!(!serde_json::to_string(&still).unwrap().contains("duration"));
+    }
 }
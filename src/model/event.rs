@@ -2,6 +2,7 @@
 use std::fmt::Display;
 use std::time::{SystemTime, UNIX_EPOCH};
 
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use sqlx::sqlite::SqliteRow;
 use sqlx::{FromRow, Row};
@@ -13,19 +14,42 @@ use crate::drivers::{camera::camera, light::light, DeviceError};
 use crate::model::Bundle;
 use crate::store::StoreError;
 
+/// The version of the websocket protocol this server speaks.
+///
+/// Version 2 added the optional client supplied `id` on events, which is echoed back on responses.
+/// Version 3 added ServerShutdown, and version 4 added SubsystemFailed.
+pub const PROTOCOL_VERSION: u16 = 4;
+
+/// The oldest protocol version we still accept from a client
+pub const MIN_PROTOCOL_VERSION: u16 = 1;
+
 /// The kind of event being sent
-#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone, JsonSchema)]
 pub enum EventKind {
     // Incoming events
     HealthCheck,
     PollDevice,
     EventHistory,
     MailStatus,
+    MailSummary,
     PinCheck,
+    SetMailStatus,
+    Rules,
+    SetRule,
+    DeleteRule,
+    ArmState,
+    SetArmState,
     // Outgoing events
     MailDelivered,
     MailPickedUp,
     DoorOpened,
+    DoorLeftOpen,
+    DoorClosed,
+    SuspiciousAccess,
+    MotionDetected,
+    ScheduledSnapshot,
+    ServerShutdown,
+    SubsystemFailed,
     PollDeviceResult,
     PinResult,
     Error,
@@ -39,11 +63,25 @@ impl EventKind {
             Self::PollDevice => false,
             Self::EventHistory => false,
             Self::MailStatus => false,
+            Self::MailSummary => false,
             Self::PinCheck => false,
+            Self::SetMailStatus => false,
+            Self::Rules => false,
+            Self::SetRule => false,
+            Self::DeleteRule => false,
+            Self::ArmState => false,
+            Self::SetArmState => false,
             // Outgoing events
             Self::MailDelivered => true,
             Self::MailPickedUp => true,
             Self::DoorOpened => true,
+            Self::DoorLeftOpen => true,
+            Self::DoorClosed => true,
+            Self::SuspiciousAccess => true,
+            Self::MotionDetected => true,
+            Self::ScheduledSnapshot => true,
+            Self::ServerShutdown => true,
+            Self::SubsystemFailed => true,
             Self::PollDeviceResult => true,
             Self::PinResult => true,
             Self::Error => true,
@@ -66,12 +104,26 @@ impl<'r> sqlx::FromRow<'r, SqliteRow> for EventKind {
             "PollDevice" => EventKind::PollDevice,
             "EventHistory" => EventKind::EventHistory,
             "MailStatus" => EventKind::MailStatus,
+            "MailSummary" => EventKind::MailSummary,
             "MailDelivered" => EventKind::MailDelivered,
             "MailPickedUp" => EventKind::MailPickedUp,
             "DoorOpened" => EventKind::DoorOpened,
+            "DoorLeftOpen" => EventKind::DoorLeftOpen,
+            "DoorClosed" => EventKind::DoorClosed,
+            "SuspiciousAccess" => EventKind::SuspiciousAccess,
+            "MotionDetected" => EventKind::MotionDetected,
+            "ScheduledSnapshot" => EventKind::ScheduledSnapshot,
+            "ServerShutdown" => EventKind::ServerShutdown,
+            "SubsystemFailed" => EventKind::SubsystemFailed,
             "PollDeviceResult" => EventKind::PollDeviceResult,
             "PinCheck" => EventKind::PinCheck,
             "PinResult" => EventKind::PinResult,
+            "SetMailStatus" => EventKind::SetMailStatus,
+            "Rules" => EventKind::Rules,
+            "SetRule" => EventKind::SetRule,
+            "DeleteRule" => EventKind::DeleteRule,
+            "ArmState" => EventKind::ArmState,
+            "SetArmState" => EventKind::SetArmState,
             "Error" => EventKind::Error,
             _ => {
                 return Err(
@@ -86,8 +138,14 @@ impl<'r> sqlx::FromRow<'r, SqliteRow> for EventKind {
 }
 
 /// An Event struct, that can be sent to or recieved from a websocket client
-#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, JsonSchema)]
 pub struct Event {
+    /// Optional id supplied by the client. The server copies it onto the response
+    /// so the client can tell which reply belongs to which request.
+    ///
+    /// Only present in protocol version 2 and up, and never stored in the database
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    id: Option<String>,
     /// The event type
     kind: EventKind,
     /// Timestamp of event creation
@@ -109,6 +167,7 @@ impl<'r> FromRow<'r, SqliteRow> for Event {
         let data = Bundle::from_row(&row).ok();
 
         Ok(Event {
+            id: None,
             kind,
             timestamp,
             device,
@@ -125,6 +184,7 @@ impl Event {
             .unwrap()
             .as_secs() as u32;
         Self {
+            id: None,
             kind,
             timestamp,
             device,
@@ -142,6 +202,29 @@ impl Event {
         )
     }
 
+    /// Copies a client supplied id onto this event, builder style.
+    /// Used to tag a response with the id of the request that caused it.
+    pub fn with_id(mut self, id: Option<String>) -> Self {
+        self.id = id;
+        self
+    }
+
+    pub fn id(&self) -> Option<&str> {
+        self.id.as_deref()
+    }
+
+    /// Leaves out a bundle with a PIN in it (PinCheck and SetArmState), builder style.
+    /// Anything saved ends up in the EventHistory, so the PIN can't be
+    pub fn without_pin(mut self) -> Self {
+        if matches!(
+            self.data,
+            Some(Bundle::PinCheck { .. }) | Some(Bundle::SetArmState { .. })
+        ) {
+            self.data = None;
+        }
+        self
+    }
+
     pub fn kind(&self) -> &EventKind {
         &self.kind
     }
@@ -150,6 +233,11 @@ impl Event {
         self.timestamp
     }
 
+    /// Overrides the timestamp, ie. for events that happened at a known time
+    pub fn set_timestamp(&mut self, timestamp: u32) {
+        self.timestamp = timestamp;
+    }
+
     pub fn device_type(&self) -> Option<&DeviceType> {
         self.device.as_ref()
     }
@@ -176,6 +264,12 @@ impl Event {
     // Finds the device type associated with this event, and poll that device,
     // returning a data bundle and setting that data bundle to itself
     pub fn poll_device(&mut self) -> Result<Bundle, DeviceError> {
+        self.poll_device_for(None)
+    }
+
+    // Same as poll_device, but a video is tagged with `trigger` (ie. the DoorOpened
+    // it's a video of) instead of this event
+    pub fn poll_device_for(&mut self, trigger: Option<&Event>) -> Result<Bundle, DeviceError> {
         // Returning a String error is kind of ugly here but it's fine for now
         if self.device.is_none() {
             return Err(DeviceError::DeviceNotFound(self.device.clone()));
@@ -189,9 +283,15 @@ impl Event {
                 }
             }
             DeviceType::Camera => {
-                let file_path = camera::capture_video()?;
+                let video = camera::capture_video(Some(trigger.unwrap_or(self)))?;
                 Bundle::Camera {
-                    file_name: format!("{:?}", file_path.file_name().expect("image file name")),
+                    file_name: video
+                        .path
+                        .file_name()
+                        .expect("image file name")
+                        .to_string_lossy()
+                        .to_string(),
+                    duration: Some(video.duration),
                 }
             }
             DeviceType::Light => {
@@ -236,6 +336,26 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_de_serialize_with_id() {
+        let event: Event =
+            serde_json::from_str(r#"{"id":"abc-1","kind":"MailStatus","device":null,"data":null}"#)
+                .unwrap();
+        assert_eq!(event.id(), Some("abc-1"));
+
+        // Events without an id (protocol version 1) still parse, and don't write one out
+        let event: Event = serde_json::from_str(r#"{"kind":"HealthCheck"}"#).unwrap();
+        assert_eq!(event.id(), None);
+        assert!(!serde_json::to_string(&event).unwrap().contains("\"id\""));
+    }
+
+    #[test]
+    fn test_with_id() {
+        let event = Event::new(EventKind::HealthCheck, None, None).with_id(Some("7".to_string()));
+        assert_eq!(event.id(), Some("7"));
+        assert!(serde_json::to_string(&event).unwrap().contains(r#""id":"7""#));
+    }
+
     #[test]
     fn test_event_is_kind_incoming_outgoing() {
         assert!(EventKind::MailDelivered.is_outgoing());
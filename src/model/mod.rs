@@ -1,5 +1,6 @@
 mod event;
 mod bundle;
+pub mod schema;
 
-pub use event::{Event, EventKind};
+pub use event::{Event, EventKind, PROTOCOL_VERSION, MIN_PROTOCOL_VERSION};
 pub use bundle::Bundle;
\ No newline at end of file
@@ -0,0 +1,76 @@
+//! JSON Schemas for the wire format, so clients can check what an `Event` (and its `EventKind`,
+//! `DeviceType` and `Bundle`) looks like without reading the Rust code. Served at `/schema`.
+//!
+//! A copy of the schema is saved in `schema.json` next to this file, and a test fails if the
+//! types change without `PROTOCOL_VERSION` being bumped. After bumping it, save the new schema
+//! with `MODKIT_UPDATE_SCHEMA=1 cargo test`.
+use schemars::schema::RootSchema;
+use schemars::schema_for;
+use serde::{Deserialize, Serialize};
+
+use super::{Event, PROTOCOL_VERSION};
+
+/// What `/schema` answers with
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ProtocolSchema {
+    pub protocol_version: u16,
+    /// The schema for an `Event`. Everything it uses is in its `definitions`
+    pub event: RootSchema,
+}
+
+pub fn protocol_schema() -> ProtocolSchema {
+    ProtocolSchema {
+        protocol_version: PROTOCOL_VERSION,
+        event: schema_for!(Event),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    fn saved_path() -> PathBuf {
+        PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("src/model/schema.json")
+    }
+
+    #[test]
+    fn test_schema_has_everything() {
+        let schema = protocol_schema().event;
+        for name in ["EventKind", "DeviceType", "Bundle", "Rule", "TimeWindow"] {
+            assert!(
+                schema.definitions.contains_key(name),
+                "{:?} is missing",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn test_wire_format_needs_a_new_protocol_version() {
+        let current = serde_json::to_string_pretty(&protocol_schema()).unwrap() + "\n";
+        let saved = std::fs::read_to_string(saved_path()).unwrap_or_default();
+        if current == saved {
+            return;
+        }
+
+        let saved_version = serde_json::from_str::<ProtocolSchema>(&saved)
+            .map(|s| s.protocol_version)
+            .unwrap_or(0);
+        assert!(
+            saved_version < PROTOCOL_VERSION,
+            "The wire format changed, but PROTOCOL_VERSION is still {:?}. Bump it, then run `MODKIT_UPDATE_SCHEMA=1 cargo test` to save the new schema",
+            PROTOCOL_VERSION
+        );
+
+        if std::env::var("MODKIT_UPDATE_SCHEMA").as_deref() == Ok("1") {
+            std::fs::write(saved_path(), current).unwrap();
+        } else {
+            panic!(
+                "PROTOCOL_VERSION is {:?} but the saved schema is for {:?}. Run `MODKIT_UPDATE_SCHEMA=1 cargo test` to save the new one",
+                PROTOCOL_VERSION, saved_version
+            );
+        }
+    }
+}
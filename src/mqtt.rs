@@ -0,0 +1,533 @@
+//! Publishes the state of the box to an MQTT broker, and takes commands from it.
+//!
+//! This is mostly for Home Assistant. When we connect we publish discovery configs, so the
+//! mailbox shows up with a door sensor, a mail sensor, a light switch, a camera and a capture
+//! button without any setup on the Home Assistant side.
+//!
+//! Set `MODKIT_MQTT_HOST` to turn this on.
+use std::sync::RwLock;
+use std::time::Duration;
+
+use log::*;
+use rumqttc::{AsyncClient, Event as MqttEvent, LastWill, MqttOptions, Packet, QoS};
+use serde_json::json;
+
+use crate::defaults;
+use crate::drivers::camera::camera;
+use crate::drivers::contact_sensor::ContactSensor;
+use crate::drivers::device::DeviceType;
+use crate::drivers::light::light;
+use crate::model::{Bundle, Event, EventKind};
+use crate::server::{self, Clients};
+use crate::shutdown;
+use crate::store::Store;
+
+/// The client of the running `run`. Publishing does nothing while there isn't one.
+/// It's replaced whenever `run` is started again
+static CLIENT: RwLock<Option<AsyncClient>> = RwLock::new(None);
+
+const ON: &str = "ON";
+const OFF: &str = "OFF";
+const PRESS: &str = "PRESS";
+
+/// The topics we publish and subscribe to, all under one prefix
+#[derive(Debug, Clone)]
+pub struct Topics {
+    prefix: String,
+}
+
+impl Topics {
+    pub fn new(prefix: &str) -> Self {
+        Topics {
+            prefix: prefix.trim_end_matches('/').to_string(),
+        }
+    }
+
+    /// `online` or `offline`
+    pub fn availability(&self) -> String {
+        format!("{}/status", self.prefix)
+    }
+
+    /// `ON` when the door is open
+    pub fn door_state(&self) -> String {
+        format!("{}/door/state", self.prefix)
+    }
+
+    /// `ON` when there's mail waiting
+    pub fn mail_state(&self) -> String {
+        format!("{}/mail/state", self.prefix)
+    }
+
+    pub fn light_state(&self) -> String {
+        format!("{}/light/state", self.prefix)
+    }
+
+    /// Send `ON` or `OFF` here to switch the light
+    pub fn light_set(&self) -> String {
+        format!("{}/light/set", self.prefix)
+    }
+
+    /// The latest still, as jpeg bytes
+    pub fn camera_image(&self) -> String {
+        format!("{}/camera/image", self.prefix)
+    }
+
+    /// Send anything here to take a new still
+    pub fn capture(&self) -> String {
+        format!("{}/camera/capture", self.prefix)
+    }
+}
+
+/// Something we were asked to do over MQTT
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Command {
+    Light(bool),
+    Capture,
+}
+
+/// Works out what command (if any) a message on one of our topics is
+pub fn parse_command(topics: &Topics, topic: &str, payload: &[u8]) -> Option<Command> {
+    if topic == topics.light_set() {
+        return match std::str::from_utf8(payload).ok()?.trim() {
+            ON => Some(Command::Light(true)),
+            OFF => Some(Command::Light(false)),
+            _ => None,
+        };
+    }
+
+    if topic == topics.capture() {
+        return Some(Command::Capture);
+    }
+
+    None
+}
+
+/// The Home Assistant discovery configs, as (topic, json payload).
+/// These should be published retained
+pub fn discovery_configs(
+    topics: &Topics,
+    discovery_prefix: &str,
+    device_id: &str,
+) -> Vec<(String, String)> {
+    let device = json!({
+        "identifiers": [device_id],
+        "name": "Modkit Mailbox",
+        "manufacturer": "MailThieves",
+        "model": "modkit",
+    });
+    let topic = |component: &str, object: &str| {
+        format!("{discovery_prefix}/{component}/{device_id}/{object}/config")
+    };
+
+    let configs = vec![
+        (
+            topic("binary_sensor", "door"),
+            json!({
+                "name": "Door",
+                "unique_id": format!("{device_id}_door"),
+                "device_class": "door",
+                "state_topic": topics.door_state(),
+                "payload_on": ON,
+                "payload_off": OFF,
+            }),
+        ),
+        (
+            topic("binary_sensor", "mail"),
+            json!({
+                "name": "Mail",
+                "unique_id": format!("{device_id}_mail"),
+                "icon": "mdi:mailbox",
+                "state_topic": topics.mail_state(),
+                "payload_on": ON,
+                "payload_off": OFF,
+            }),
+        ),
+        (
+            topic("switch", "light"),
+            json!({
+                "name": "Light",
+                "unique_id": format!("{device_id}_light"),
+                "icon": "mdi:lightbulb",
+                "state_topic": topics.light_state(),
+                "command_topic": topics.light_set(),
+                "payload_on": ON,
+                "payload_off": OFF,
+            }),
+        ),
+        (
+            topic("camera", "camera"),
+            json!({
+                "name": "Camera",
+                "unique_id": format!("{device_id}_camera"),
+                "topic": topics.camera_image(),
+            }),
+        ),
+        (
+            topic("button", "capture"),
+            json!({
+                "name": "Take Picture",
+                "unique_id": format!("{device_id}_capture"),
+                "icon": "mdi:camera",
+                "command_topic": topics.capture(),
+                "payload_press": PRESS,
+            }),
+        ),
+    ];
+
+    configs
+        .into_iter()
+        .map(|(topic, mut config)| {
+            config["availability_topic"] = json!(topics.availability());
+            config["device"] = device.clone();
+            (topic, config.to_string())
+        })
+        .collect()
+}
+
+fn on_off(on: bool) -> &'static str {
+    if on {
+        ON
+    } else {
+        OFF
+    }
+}
+
+/// The state topics that change because of an event, as (topic, payload).
+/// Doesn't include pictures, see `still_file`
+pub fn state_updates(topics: &Topics, event: &Event) -> Vec<(String, &'static str)> {
+    match (event.kind(), event.data()) {
+        (EventKind::DoorOpened, Some(Bundle::ContactSensor { open })) => {
+            vec![(topics.door_state(), on_off(*open))]
+        }
+        (EventKind::MailDelivered, _) => vec![(topics.mail_state(), ON)],
+        (EventKind::MailPickedUp, _) => vec![(topics.mail_state(), OFF)],
+        (_, Some(Bundle::Light { on })) => vec![(topics.light_state(), on_off(*on))],
+        _ => vec![],
+    }
+}
+
+/// The still picture attached to an event, if there is one
+fn still_file(event: &Event) -> Option<&str> {
+    match event.data()? {
+        Bundle::MailClassification { file_name, .. } => Some(file_name),
+        Bundle::Motion { file_name, .. } => file_name.as_deref(),
+        _ => None,
+    }
+}
+
+/// Publishes whatever state changed because of this event. Does nothing if MQTT isn't running
+pub fn publish_event(event: &Event) {
+    let client = match CLIENT.read().expect("mqtt client lock").clone() {
+        Some(c) => c,
+        None => return,
+    };
+    let topics = Topics::new(&defaults::mqtt_prefix());
+
+    for (topic, payload) in state_updates(&topics, event) {
+        if let Err(e) = client.try_publish(topic, QoS::AtLeastOnce, true, payload) {
+            error!("Couldn't publish {} over MQTT: {e}", event.kind());
+        }
+    }
+
+    if let Some(file_name) = still_file(event) {
+        publish_still(
+            &client,
+            &topics,
+            &std::path::Path::new(&defaults::img_dir()).join(file_name),
+        );
+    }
+}
+
+fn publish_still(client: &AsyncClient, topics: &Topics, path: &std::path::Path) {
+    match std::fs::read(path) {
+        Ok(bytes) => {
+            if let Err(e) = client.try_publish(topics.camera_image(), QoS::AtMostOnce, true, bytes)
+            {
+                error!("Couldn't publish still over MQTT: {e}");
+            }
+        }
+        Err(e) => error!("Couldn't read still {}: {e}", path.display()),
+    }
+}
+
+/// Sets up the connection options from the environment
+fn options(host: String, topics: &Topics) -> MqttOptions {
+    let mut options = MqttOptions::new(defaults::device_id(), host, defaults::mqtt_port());
+    options
+        .set_keep_alive(Duration::from_secs(30))
+        // Pictures are much bigger than the default limit
+        .set_max_packet_size(2 * 1024 * 1024, 2 * 1024 * 1024)
+        .set_last_will(LastWill::new(
+            topics.availability(),
+            "offline",
+            QoS::AtLeastOnce,
+            true,
+        ));
+    if let Some((user, password)) = defaults::mqtt_credentials() {
+        options.set_credentials(user, password);
+    }
+    options
+}
+
+/// Announces us to Home Assistant, subscribes to commands and publishes the current state.
+/// Called every time we (re)connect to the broker
+async fn on_connect(client: &AsyncClient, topics: &Topics) -> Result<(), rumqttc::ClientError> {
+    for (topic, config) in discovery_configs(
+        topics,
+        &defaults::mqtt_discovery_prefix(),
+        &defaults::device_id(),
+    ) {
+        client
+            .publish(topic, QoS::AtLeastOnce, true, config)
+            .await?;
+    }
+    client
+        .publish(topics.availability(), QoS::AtLeastOnce, true, "online")
+        .await?;
+
+    client
+        .subscribe(topics.light_set(), QoS::AtLeastOnce)
+        .await?;
+    client.subscribe(topics.capture(), QoS::AtLeastOnce).await?;
+
+    // Current state, so Home Assistant isn't stuck on "unknown" until something happens
+    if let Ok(open) = ContactSensor::new().poll() {
+        client
+            .publish(topics.door_state(), QoS::AtLeastOnce, true, on_off(open))
+            .await?;
+    }
+    if let Ok(on) = light::is_on() {
+        client
+            .publish(topics.light_state(), QoS::AtLeastOnce, true, on_off(on))
+            .await?;
+    }
+    if let Ok(store) = Store::connect().await {
+        if let Ok(status) = store.get_mail_status().await {
+            let delivered = status.kind() == &EventKind::MailDelivered;
+            client
+                .publish(
+                    topics.mail_state(),
+                    QoS::AtLeastOnce,
+                    true,
+                    on_off(delivered),
+                )
+                .await?;
+        }
+    }
+
+    Ok(())
+}
+
+async fn handle_command(
+    command: Command,
+    client: &AsyncClient,
+    topics: &Topics,
+    clients: &Clients,
+) {
+    info!("Got MQTT command {:?}", command);
+    match command {
+        Command::Light(on) => match light::set(on) {
+            Ok(_) => {
+                let _ = client
+                    .publish(topics.light_state(), QoS::AtLeastOnce, true, on_off(on))
+                    .await;
+            }
+            Err(e) => error!("Couldn't set the light from MQTT: {e}"),
+        },
+        Command::Capture => {
+            // Taking a picture blocks for a bit
+            match tokio::task::spawn_blocking(|| camera::capture_still(None)).await {
+                Ok(Ok(path)) => {
+                    publish_still(client, topics, &path);
+                    // Let the websocket clients see it too
+                    let event = Event::new(
+                        EventKind::PollDeviceResult,
+                        Some(DeviceType::Camera),
+                        Some(Bundle::Camera {
+                            file_name: path
+                                .file_name()
+                                .expect("image file name")
+                                .to_string_lossy()
+                                .to_string(),
+                            duration: None,
+                        }),
+                    );
+                    server::ws::send_to_clients(&event, clients).await;
+                }
+                Ok(Err(e)) => error!("Couldn't take a picture from MQTT: {e}"),
+                Err(e) => error!("Picture task failed: {e}"),
+            }
+        }
+    }
+}
+
+/// Connects to the broker and handles commands until we shut down, then marks us offline.
+/// Returns right away if `MODKIT_MQTT_HOST` isn't set
+pub async fn run(clients: &Clients) -> Result<(), Box<dyn std::error::Error>> {
+    let host = match defaults::mqtt_host() {
+        Some(host) => host,
+        None => {
+            info!("MODKIT_MQTT_HOST isn't set, not running MQTT");
+            return Ok(());
+        }
+    };
+
+    info!("Running MQTT, connecting to {host}");
+    let topics = Topics::new(&defaults::mqtt_prefix());
+    let (client, mut eventloop) = AsyncClient::new(options(host, &topics), 32);
+    *CLIENT.write().expect("mqtt client lock") = Some(client.clone());
+
+    loop {
+        let polled = tokio::select! {
+            polled = eventloop.poll() => polled,
+            _ = shutdown::stopped() => break,
+        };
+        match polled {
+            Ok(MqttEvent::Incoming(Packet::ConnAck(_))) => {
+                info!("Connected to the MQTT broker");
+                if let Err(e) = on_connect(&client, &topics).await {
+                    error!("Couldn't set up MQTT topics: {e}");
+                }
+            }
+            Ok(MqttEvent::Incoming(Packet::Publish(publish))) => {
+                if let Some(command) = parse_command(&topics, &publish.topic, &publish.payload) {
+                    handle_command(command, &client, &topics, clients).await;
+                }
+            }
+            Ok(_) => {}
+            Err(e) => {
+                // The event loop reconnects the next time we poll it
+                error!("MQTT connection error: {e}");
+                tokio::time::sleep(Duration::from_secs(5)).await;
+            }
+        }
+    }
+
+    // The last will only covers dropping off, so say we're going
+    info!("Disconnecting from the MQTT broker");
+    *CLIENT.write().expect("mqtt client lock") = None;
+    let _ = client
+        .publish(topics.availability(), QoS::AtLeastOnce, true, "offline")
+        .await;
+    let _ = client.disconnect().await;
+    // Those only go out while the event loop runs
+    let _ = tokio::time::timeout(Duration::from_secs(2), async {
+        while eventloop.poll().await.is_ok() {}
+    })
+    .await;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn topics() -> Topics {
+        Topics::new("modkit/")
+    }
+
+    #[test]
+    fn test_parse_command() {
+        let t = topics();
+        assert_eq!(
+            parse_command(&t, "modkit/light/set", b"ON"),
+            Some(Command::Light(true))
+        );
+        assert_eq!(
+            parse_command(&t, "modkit/light/set", b"OFF"),
+            Some(Command::Light(false))
+        );
+        assert_eq!(parse_command(&t, "modkit/light/set", b"maybe"), None);
+        assert_eq!(
+            parse_command(&t, "modkit/camera/capture", b"PRESS"),
+            Some(Command::Capture)
+        );
+        assert_eq!(parse_command(&t, "modkit/door/state", b"ON"), None);
+    }
+
+    #[test]
+    fn test_discovery_configs() {
+        let configs = discovery_configs(&topics(), "homeassistant", "mailbox");
+        assert_eq!(configs.len(), 5);
+
+        let (topic, payload) = &configs[0];
+        assert_eq!(topic, "homeassistant/binary_sensor/mailbox/door/config");
+        let payload: serde_json::Value = serde_json::from_str(payload).unwrap();
+        assert_eq!(payload["state_topic"], "modkit/door/state");
+        assert_eq!(payload["availability_topic"], "modkit/status");
+        assert_eq!(payload["device"]["identifiers"][0], "mailbox");
+
+        // Every entity needs a different unique id
+        let mut ids: Vec<String> = configs
+            .iter()
+            .map(|(_, p)| {
+                serde_json::from_str::<serde_json::Value>(p).unwrap()["unique_id"].to_string()
+            })
+            .collect();
+        ids.sort();
+        ids.dedup();
+        assert_eq!(ids.len(), 5);
+    }
+
+    #[test]
+    fn test_state_updates() {
+        let t = topics();
+        let door = Event::new(
+            EventKind::DoorOpened,
+            Some(DeviceType::ContactSensor),
+            Some(Bundle::ContactSensor { open: false }),
+        );
+        assert_eq!(state_updates(&t, &door), vec![(t.door_state(), OFF)]);
+
+        let mail = Event::new(EventKind::MailDelivered, None, None);
+        assert_eq!(state_updates(&t, &mail), vec![(t.mail_state(), ON)]);
+
+        let light = Event::new(
+            EventKind::PollDeviceResult,
+            Some(DeviceType::Light),
+            Some(Bundle::Light { on: true }),
+        );
+        assert_eq!(state_updates(&t, &light), vec![(t.light_state(), ON)]);
+
+        assert!(state_updates(&t, &Event::new(EventKind::HealthCheck, None, None)).is_empty());
+    }
+
+    // Needs a broker like mosquitto running on localhost:1883
+    // Run with `cargo test -- --ignored`
+    #[tokio::test]
+    #[ignore]
+    async fn test_discovery_with_local_broker() {
+        let t = Topics::new("modkit_test");
+
+        let (client, mut eventloop) =
+            AsyncClient::new(MqttOptions::new("modkit_test_pub", "localhost", 1883), 32);
+        let (listener, mut listener_loop) =
+            AsyncClient::new(MqttOptions::new("modkit_test_sub", "localhost", 1883), 32);
+        listener
+            .subscribe(
+                format!("{}/#", defaults::mqtt_discovery_prefix()),
+                QoS::AtLeastOnce,
+            )
+            .await
+            .unwrap();
+        tokio::spawn(async move { while eventloop.poll().await.is_ok() {} });
+
+        on_connect(&client, &t).await.unwrap();
+
+        // Wait for the door config to come through
+        let found = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                if let Ok(MqttEvent::Incoming(Packet::Publish(p))) = listener_loop.poll().await {
+                    if p.topic.ends_with("/door/config") {
+                        return p;
+                    }
+                }
+            }
+        })
+        .await
+        .expect("Didn't get the discovery config from the broker");
+
+        let config: serde_json::Value = serde_json::from_slice(&found.payload).unwrap();
+        assert_eq!(config["state_topic"], "modkit_test/door/state");
+    }
+}
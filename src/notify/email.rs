@@ -0,0 +1,604 @@
+//! Emails the addresses in `MODKIT_EMAIL_TO` when mail is delivered (or someone suspicious
+//! opens the box), through the SMTP server in `MODKIT_SMTP_HOST`.
+//!
+//! The still from the mail classifier is attached, or if there isn't one, a thumbnail of the
+//! video from when the door opened. Emails can be held back during quiet hours
+//! (`MODKIT_EMAIL_QUIET_HOURS`), or saved up and sent once a day as a digest
+//! (`MODKIT_EMAIL_DIGEST`), except high priority ones which go out right away. Anything that's
+//! due for the same address at the same time goes out as one email.
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use chrono::{DateTime, Local, NaiveTime, TimeZone};
+use lettre::message::header::ContentType;
+use lettre::message::{Attachment, MultiPart, SinglePart};
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use log::*;
+use serde::{Deserialize, Serialize};
+
+use super::{backoff, media_file, now, Notification, NotifyError, Priority, MAX_ATTEMPTS};
+use crate::defaults;
+use crate::drivers::camera::camera;
+use crate::model::{Event, EventKind};
+use crate::schedule::{self, TimeWindow};
+use crate::shutdown;
+use crate::store::{OutboxEntry, Store, StoreError};
+
+/// The outbox channel for emails
+pub const CHANNEL: &str = "email";
+
+/// How far back to look for the video of a delivery, if the event doesn't say which it is
+const VIDEO_WINDOW_SECS: u32 = 10 * 60;
+
+pub type Transport = AsyncSmtpTransport<Tokio1Executor>;
+
+/// What's stored in the outbox for each email
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EmailPayload {
+    pub notification: Notification,
+    /// The name of a picture in `img_dir` to attach
+    pub attachment: Option<String>,
+}
+
+/// Adds an email to the outbox for every configured address, if it's about a delivery or
+/// it's high priority
+pub async fn queue(
+    store: &Store,
+    event: &Event,
+    notification: &Notification,
+) -> Result<(), NotifyError> {
+    if *event.kind() != EventKind::MailDelivered && notification.priority != Priority::High {
+        return Ok(());
+    }
+    let recipients = defaults::email_to();
+    if recipients.is_empty() || defaults::smtp_host().is_none() {
+        return Ok(());
+    }
+
+    // Making a thumbnail runs ffmpeg, don't hold up the watchdog while it does
+    let attachment_event = event.clone();
+    let img_dir = defaults::img_dir();
+    let thumbnail =
+        tokio::task::spawn_blocking(move || attachment_for(&attachment_event, Path::new(&img_dir)));
+    let attachment = match thumbnail.await {
+        Ok(attachment) => attachment,
+        Err(e) => {
+            warn!("Not attaching anything to the email, the thumbnail task failed: {e}");
+            None
+        }
+    };
+
+    let payload = serde_json::to_string(&EmailPayload {
+        notification: notification.clone(),
+        attachment,
+    })?;
+
+    let now = Local::now();
+    let send_at = match notification.priority {
+        Priority::High => now,
+        Priority::Normal => send_time(
+            now,
+            defaults::email_quiet_hours().as_ref(),
+            defaults::email_digest(),
+        ),
+    };
+    for to in recipients {
+        trace!("Queueing email to {to} for {send_at}");
+        store
+            .queue_notification_at(
+                CHANNEL,
+                &to,
+                &payload,
+                now.timestamp() as u32,
+                send_at.timestamp() as u32,
+            )
+            .await?;
+    }
+    Ok(())
+}
+
+/// When to send an email about something that happened at `now`
+pub fn send_time<Tz: TimeZone>(
+    now: DateTime<Tz>,
+    quiet_hours: Option<&TimeWindow>,
+    digest: Option<NaiveTime>,
+) -> DateTime<Tz> {
+    let send_at = match digest {
+        Some(time) => schedule::next_at(now, time),
+        None => now,
+    };
+    match quiet_hours {
+        Some(quiet_hours) => quiet_hours.next_outside(send_at),
+        None => send_at,
+    }
+}
+
+/// The picture to attach for an event: its still if it has one, otherwise a thumbnail of
+/// its video (or the latest video before it)
+fn attachment_for(event: &Event, img_dir: &Path) -> Option<String> {
+    let video = match media_file(event) {
+        Some(file) if file.ends_with(".jpg") => return Some(file.to_string()),
+        Some(file) => img_dir.join(file),
+        None => recent_video(img_dir, event.timestamp())?,
+    };
+
+    match camera::thumbnail(&video) {
+        Ok(thumb) => thumb.file_name().map(|f| f.to_string_lossy().to_string()),
+        Err(e) => {
+            warn!("Not attaching anything to the email: {e}");
+            None
+        }
+    }
+}
+
+/// The latest video in `img_dir` recorded in the few minutes before `timestamp`.
+/// Videos are named after the time they were taken
+fn recent_video(img_dir: &Path, timestamp: u32) -> Option<PathBuf> {
+    std::fs::read_dir(img_dir)
+        .ok()?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        // It's left as .h264 if it couldn't be converted
+        .filter(|path| {
+            path.extension()
+                .is_some_and(|ext| ext == "mp4" || ext == "h264")
+        })
+        .filter_map(|path| {
+            let taken: u32 = path.file_stem()?.to_str()?.parse().ok()?;
+            Some((taken, path))
+        })
+        .filter(|(taken, _)| *taken <= timestamp && timestamp - taken <= VIDEO_WINDOW_SECS)
+        .max_by_key(|(taken, _)| *taken)
+        .map(|(_, path)| path)
+}
+
+/// Sets up the SMTP connection from the environment. None if `MODKIT_SMTP_HOST` isn't set
+pub fn transport() -> Result<Option<Transport>, NotifyError> {
+    let host = match defaults::smtp_host() {
+        Some(host) => host,
+        None => return Ok(None),
+    };
+
+    let mut builder = match defaults::smtp_tls().as_str() {
+        "tls" => Transport::relay(&host)?,
+        "none" => Transport::builder_dangerous(&host),
+        _ => Transport::starttls_relay(&host)?,
+    };
+    if let Some(port) = defaults::smtp_port() {
+        builder = builder.port(port);
+    }
+    if let Some((user, password)) = defaults::smtp_credentials() {
+        builder = builder.credentials(Credentials::new(user, password));
+    }
+    Ok(Some(builder.timeout(Some(Duration::from_secs(30))).build()))
+}
+
+/// Puts together one email about all the `payloads`
+pub fn build_message(
+    from: &str,
+    to: &str,
+    payloads: &[EmailPayload],
+    img_dir: &Path,
+) -> Result<Message, NotifyError> {
+    let urgent = payloads
+        .iter()
+        .find(|p| p.notification.priority == Priority::High);
+    let subject = if let Some(urgent) = urgent {
+        format!("Mailbox alert: {}", describe_kind(&urgent.notification.kind))
+    } else if payloads.len() == 1 {
+        "Mail was delivered".to_string()
+    } else {
+        format!("Mail was delivered {} times", payloads.len())
+    };
+
+    let mut body = String::new();
+    for payload in payloads {
+        body += &format!(
+            "{} at {}",
+            describe_kind(&payload.notification.kind),
+            describe_time(payload.notification.timestamp)
+        );
+        if let Some(url) = &payload.notification.media_url {
+            body += &format!(" ({url})");
+        }
+        body.push('\n');
+    }
+
+    let mut parts = MultiPart::mixed().singlepart(SinglePart::plain(body));
+    for name in payloads.iter().filter_map(|p| p.attachment.as_ref()) {
+        match std::fs::read(img_dir.join(name)) {
+            Ok(bytes) => {
+                // A valid constant, this can't fail
+                let jpeg = ContentType::parse("image/jpeg").expect("jpeg content type");
+                parts = parts.singlepart(Attachment::new(name.clone()).body(bytes, jpeg));
+            }
+            Err(e) => warn!("Couldn't attach {name} to the email: {e}"),
+        }
+    }
+
+    Ok(Message::builder()
+        .from(from.parse()?)
+        .to(to.parse()?)
+        .subject(subject)
+        .multipart(parts)?)
+}
+
+fn describe_kind(kind: &EventKind) -> String {
+    match kind {
+        EventKind::MailDelivered => "Mail was delivered".to_string(),
+        EventKind::MailPickedUp => "Mail was picked up".to_string(),
+        EventKind::DoorOpened => "The mailbox was opened".to_string(),
+        EventKind::DoorLeftOpen => "The mailbox door was left open".to_string(),
+        EventKind::DoorClosed => "The mailbox door was closed".to_string(),
+        EventKind::SuspiciousAccess => "Someone opened the mailbox suspiciously".to_string(),
+        EventKind::MotionDetected => "Something moved outside the mailbox".to_string(),
+        other => other.to_string(),
+    }
+}
+
+/// ie. `14:05 on Tue 3 Mar`
+fn describe_time(timestamp: u32) -> String {
+    match Local.timestamp_opt(timestamp as i64, 0).single() {
+        Some(time) => time.format("%H:%M on %a %-d %b").to_string(),
+        None => timestamp.to_string(),
+    }
+}
+
+/// Sends everything in the outbox that's due, one email per address, and reschedules
+/// what fails
+pub async fn send_due(
+    store: &Store,
+    transport: &Transport,
+    from: &str,
+    img_dir: &Path,
+    now: u32,
+) -> Result<(), StoreError> {
+    let mut by_recipient: BTreeMap<String, Vec<(OutboxEntry, EmailPayload)>> = BTreeMap::new();
+    for entry in store.due_notifications(CHANNEL, now).await? {
+        match serde_json::from_str(&entry.payload) {
+            Ok(payload) => by_recipient
+                .entry(entry.target.clone())
+                .or_default()
+                .push((entry, payload)),
+            Err(e) => {
+                error!("Dropping an email notification we can't read: {e}");
+                store.remove_notification(entry.id).await?;
+            }
+        }
+    }
+
+    for (to, entries) in by_recipient {
+        let payloads: Vec<EmailPayload> = entries.iter().map(|(_, p)| p.clone()).collect();
+        let result = match build_message(from, &to, &payloads, img_dir) {
+            Ok(message) => transport
+                .send(message)
+                .await
+                .map(|_| ())
+                .map_err(NotifyError::from),
+            Err(e) => Err(e),
+        };
+
+        match result {
+            Ok(_) => {
+                info!("Emailed {to} about {} deliveries", payloads.len());
+                for (entry, _) in entries {
+                    store.remove_notification(entry.id).await?;
+                }
+            }
+            Err(e) => {
+                for (entry, _) in entries {
+                    let attempts = entry.attempts + 1;
+                    if attempts >= MAX_ATTEMPTS {
+                        error!("Giving up on emailing {to} after {attempts} tries: {e}");
+                        store.remove_notification(entry.id).await?;
+                    } else {
+                        let delay = backoff(attempts);
+                        warn!("Email to {to} failed ({e}), trying again in {delay}s");
+                        store
+                            .retry_notification(entry.id, attempts, now + delay)
+                            .await?;
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Runs a continuous loop that sends queued emails, until we shut down. Returns right away if
+/// there's no SMTP server set up
+pub async fn run() -> Result<(), Box<dyn std::error::Error>> {
+    let transport = match transport()? {
+        Some(transport) => transport,
+        None => {
+            info!("MODKIT_SMTP_HOST isn't set, not sending emails");
+            return Ok(());
+        }
+    };
+
+    info!("Running the email notifier");
+    let store = Store::connect().await?;
+    let from = defaults::email_from();
+    let img_dir = PathBuf::from(defaults::img_dir());
+
+    loop {
+        if let Err(e) = send_due(&store, &transport, &from, &img_dir, now()).await {
+            error!("Couldn't read the email outbox: {e}");
+        }
+        tokio::select! {
+            _ = tokio::time::sleep(Duration::from_secs(5)) => {}
+            _ = shutdown::stopped() => break,
+        }
+    }
+
+    // Anything left stays in the outbox for next time
+    info!("Stopped the email notifier");
+    store.close().await;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+    use tokio::sync::mpsc;
+
+    use super::*;
+    use crate::model::Bundle;
+    use crate::schedule::tests::utc;
+    use crate::store::TEST_DB;
+
+    // Starts a local SMTP server that stands in for a real one. It passes along the
+    // DATA of every email it gets. If `reject` is set it refuses every recipient.
+    async fn stand_in(reject: bool) -> (Transport, mpsc::UnboundedReceiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (tx, rx) = mpsc::unbounded_channel();
+
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let tx = tx.clone();
+                tokio::spawn(async move {
+                    let (read, mut write) = stream.into_split();
+                    let mut lines = BufReader::new(read).lines();
+                    write.write_all(b"220 localhost ESMTP\r\n").await.unwrap();
+
+                    while let Ok(Some(line)) = lines.next_line().await {
+                        let command = line.to_uppercase();
+                        let reply: &[u8] = if command.starts_with("RCPT") && reject {
+                            b"550 No such user\r\n"
+                        } else if command.starts_with("DATA") {
+                            write.write_all(b"354 Go ahead\r\n").await.unwrap();
+                            let mut data = String::new();
+                            while let Ok(Some(line)) = lines.next_line().await {
+                                if line == "." {
+                                    break;
+                                }
+                                data += &line;
+                                data.push('\n');
+                            }
+                            tx.send(data).unwrap();
+                            b"250 Queued\r\n"
+                        } else if command.starts_with("QUIT") {
+                            write.write_all(b"221 Bye\r\n").await.unwrap();
+                            break;
+                        } else {
+                            b"250 OK\r\n"
+                        };
+                        write.write_all(reply).await.unwrap();
+                    }
+                });
+            }
+        });
+
+        let transport = Transport::builder_dangerous("127.0.0.1").port(port).build();
+        (transport, rx)
+    }
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("modkit-email-{name}"));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn payload(timestamp: u32, attachment: Option<&str>) -> EmailPayload {
+        EmailPayload {
+            notification: Notification {
+                kind: EventKind::MailDelivered,
+                timestamp,
+                media_url: None,
+                priority: Priority::Normal,
+            },
+            attachment: attachment.map(|a| a.to_string()),
+        }
+    }
+
+    fn json(payload: &EmailPayload) -> String {
+        serde_json::to_string(payload).unwrap()
+    }
+
+    #[test]
+    fn test_send_time() {
+        let at = |day, hour| utc(day, hour, 0);
+        let quiet = TimeWindow::parse("22:00-07:00").unwrap();
+        let six = NaiveTime::from_hms_opt(18, 0, 0).unwrap();
+        let eleven = NaiveTime::from_hms_opt(23, 0, 0).unwrap();
+
+        // Straight away
+        assert_eq!(send_time(at(1, 12), None, None), at(1, 12));
+        assert_eq!(send_time(at(1, 12), Some(&quiet), None), at(1, 12));
+
+        // After quiet hours
+        assert_eq!(send_time(at(1, 23), Some(&quiet), None), at(2, 7));
+        assert_eq!(send_time(at(2, 3), Some(&quiet), None), at(2, 7));
+
+        // At the next digest
+        assert_eq!(send_time(at(1, 12), None, Some(six)), at(1, 18));
+        assert_eq!(send_time(at(1, 19), None, Some(six)), at(2, 18));
+
+        // A digest during quiet hours waits until they're over
+        assert_eq!(send_time(at(1, 12), Some(&quiet), Some(eleven)), at(2, 7));
+    }
+
+    #[test]
+    fn test_recent_video() {
+        let dir = test_dir("recent");
+        for name in ["100.mp4", "500.mp4", "900.mp4", "550.jpg", "notes.mp4"] {
+            std::fs::write(dir.join(name), b"").unwrap();
+        }
+
+        assert_eq!(recent_video(&dir, 600), Some(dir.join("500.mp4")));
+        assert_eq!(recent_video(&dir, 900), Some(dir.join("900.mp4")));
+        assert_eq!(recent_video(&dir, 50), None);
+        assert_eq!(recent_video(&dir, 900 + VIDEO_WINDOW_SECS + 1), None);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_attachment_for_still() {
+        let event = Event::new(
+            EventKind::MailDelivered,
+            None,
+            Some(Bundle::MailClassification {
+                confidence: 0.9,
+                file_name: "1234.jpg".to_string(),
+            }),
+        );
+        assert_eq!(
+            attachment_for(&event, Path::new("/nowhere")),
+            Some("1234.jpg".to_string())
+        );
+    }
+
+    #[test]
+    fn test_build_message() {
+        let dir = test_dir("build");
+        std::fs::write(dir.join("1234.jpg"), b"not really a jpeg").unwrap();
+
+        let single = build_message(
+            "box@example.com",
+            "me@example.com",
+            &[payload(1234, Some("1234.jpg"))],
+            &dir,
+        )
+        .unwrap();
+        let single = String::from_utf8(single.formatted()).unwrap();
+        assert!(single.contains("Subject: Mail was delivered\r\n"));
+        assert!(single.contains("To: me@example.com"));
+        assert!(single.contains("1234.jpg"));
+        assert!(single.contains("image/jpeg"));
+
+        // A missing attachment is left off instead of failing
+        let digest = build_message(
+            "box@example.com",
+            "me@example.com",
+            &[payload(1234, None), payload(5678, Some("missing.jpg"))],
+            &dir,
+        )
+        .unwrap();
+        let digest = String::from_utf8(digest.formatted()).unwrap();
+        assert!(digest.contains("Subject: Mail was delivered 2 times"));
+        assert!(!digest.contains("image/jpeg"));
+
+        // Anything urgent takes over the subject
+        let mut suspicious = payload(5678, None);
+        suspicious.notification.kind = EventKind::SuspiciousAccess;
+        suspicious.notification.priority = Priority::High;
+        let urgent = build_message(
+            "box@example.com",
+            "me@example.com",
+            &[payload(1234, None), suspicious],
+            &dir,
+        )
+        .unwrap();
+        let urgent = String::from_utf8(urgent.formatted()).unwrap();
+        assert!(urgent.contains("Subject: Mailbox alert: Someone opened the mailbox suspiciously"));
+        assert!(urgent.contains("Someone opened the mailbox suspiciously"));
+
+        assert!(build_message("box@example.com", "not an address", &[], &dir).is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_send_due() {
+        let _db = TEST_DB.lock().await;
+        let store = Store::connect().await.unwrap();
+        store.nuke().await.unwrap();
+        let (transport, mut received) = stand_in(false).await;
+        let dir = test_dir("send");
+
+        for (to, send_at) in [
+            ("a@example.com", 100),
+            ("a@example.com", 100),
+            ("b@example.com", 100),
+            ("b@example.com", 200),
+        ] {
+            store
+                .queue_notification_at(CHANNEL, to, &json(&payload(50, None)), 50, send_at)
+                .await
+                .unwrap();
+        }
+
+        send_due(&store, &transport, "box@example.com", &dir, 100)
+            .await
+            .unwrap();
+
+        // Both of a's go out as one digest, b's later one waits
+        let first = received.recv().await.unwrap();
+        assert!(first.contains("To: a@example.com"));
+        assert!(first.contains("Mail was delivered 2 times"));
+        let second = received.recv().await.unwrap();
+        assert!(second.contains("To: b@example.com"));
+        assert!(second.contains("Subject: Mail was delivered\n"));
+
+        let waiting = store.due_notifications(CHANNEL, 200).await.unwrap();
+        assert_eq!(waiting.len(), 1);
+        assert_eq!(waiting[0].target, "b@example.com");
+
+        store.nuke().await.unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_send_due_rejected() {
+        let _db = TEST_DB.lock().await;
+        let store = Store::connect().await.unwrap();
+        store.nuke().await.unwrap();
+        let (transport, _received) = stand_in(true).await;
+        let dir = test_dir("rejected");
+
+        store
+            .queue_notification(CHANNEL, "a@example.com", &json(&payload(50, None)), 100)
+            .await
+            .unwrap();
+        store
+            .queue_notification(CHANNEL, "a@example.com", "not json", 100)
+            .await
+            .unwrap();
+
+        send_due(&store, &transport, "box@example.com", &dir, 100)
+            .await
+            .unwrap();
+
+        // The unreadable one is dropped, the other waits to be tried again
+        assert!(store
+            .due_notifications(CHANNEL, 100)
+            .await
+            .unwrap()
+            .is_empty());
+        let waiting = store
+            .due_notifications(CHANNEL, 100 + backoff(1))
+            .await
+            .unwrap();
+        assert_eq!(waiting.len(), 1);
+        assert_eq!(waiting[0].attempts, 1);
+
+        store.nuke().await.unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,225 @@
+//! Tells people about events outside of the connected websocket clients.
+//!
+//! Notifications aren't sent right away. They're written to the `Outbox` table and the
+//! notifier for each channel picks them up from there, so nothing is lost if the network
+//! is down or the box restarts.
+use log::*;
+use serde::{Deserialize, Serialize};
+
+use crate::defaults;
+use crate::model::{Bundle, Event, EventKind};
+use crate::store::{Store, StoreError};
+
+pub mod email;
+pub mod webhook;
+
+/// Give up on a notification after this many failed tries
+pub const MAX_ATTEMPTS: u32 = 10;
+
+/// Wait this long after the first failure, doubling each time after that
+const BASE_DELAY_SECS: u32 = 5;
+/// But never wait longer than an hour
+const MAX_DELAY_SECS: u32 = 60 * 60;
+
+#[derive(thiserror::Error, Debug)]
+pub enum NotifyError {
+    #[error("HTTP error: {0}")]
+    HttpError(#[from] reqwest::Error),
+    #[error("Got status {0} back from the notification target")]
+    BadStatus(u16),
+    #[error("Couldn't serialize the notification: {0}")]
+    SerializeError(#[from] serde_json::Error),
+    #[error(transparent)]
+    StoreError(#[from] StoreError),
+    #[error("Bad email address: {0}")]
+    AddressError(#[from] lettre::address::AddressError),
+    #[error("Couldn't build the email: {0}")]
+    EmailError(#[from] lettre::error::Error),
+    #[error("SMTP error: {0}")]
+    SmtpError(#[from] lettre::transport::smtp::Error),
+}
+
+/// How urgent a notification is
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+pub enum Priority {
+    #[default]
+    Normal,
+    /// Sent right away, even during quiet hours or when a rule suppresses the event
+    High,
+}
+
+impl Priority {
+    pub fn of(kind: &EventKind) -> Self {
+        match kind {
+            EventKind::SuspiciousAccess => Priority::High,
+            _ => Priority::Normal,
+        }
+    }
+}
+
+/// What gets sent out about an event
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Notification {
+    pub kind: EventKind,
+    pub timestamp: u32,
+    /// Where to find the picture or video that goes with the event, if there is one
+    pub media_url: Option<String>,
+    #[serde(default)]
+    pub priority: Priority,
+}
+
+impl Notification {
+    pub fn from_event(event: &Event) -> Self {
+        Notification {
+            kind: event.kind().clone(),
+            timestamp: event.timestamp(),
+            media_url: media_file(event).and_then(media_url),
+            priority: Priority::of(event.kind()),
+        }
+    }
+}
+
+/// Whether an event is worth telling anyone about
+pub fn should_notify(event: &Event) -> bool {
+    match event.kind() {
+        EventKind::MailDelivered | EventKind::MailPickedUp => true,
+        EventKind::DoorLeftOpen | EventKind::DoorClosed => true,
+        EventKind::SuspiciousAccess => true,
+        EventKind::MotionDetected => true,
+        // The watchdog sends DoorOpened when the door closes too, just with `open: false`
+        EventKind::DoorOpened => matches!(
+            event.data(),
+            Some(Bundle::ContactSensor { open: true })
+        ),
+        _ => false,
+    }
+}
+
+/// Queues up notifications about an event for every notifier, whatever kind it is.
+/// `priority` can raise it above the usual priority for the kind, ie. when the box is armed.
+///
+/// Errors are only logged; a broken notifier shouldn't stop the watchdog.
+pub async fn queue(store: &Store, event: &Event, priority: Priority) {
+    let mut notification = Notification::from_event(event);
+    if priority == Priority::High {
+        notification.priority = Priority::High;
+    }
+    if let Err(e) = webhook::queue(store, &notification).await {
+        error!("Couldn't queue webhook notifications for {}: {e}", event.kind());
+    }
+    if let Err(e) = email::queue(store, event, &notification).await {
+        error!("Couldn't queue email notifications for {}: {e}", event.kind());
+    }
+}
+
+/// The file name of the picture or video attached to an event
+pub(crate) fn media_file(event: &Event) -> Option<&str> {
+    match event.data()? {
+        Bundle::MailClassification { file_name, .. } => Some(file_name),
+        Bundle::SuspiciousAccess { file_name, .. } => file_name.as_deref(),
+        Bundle::Motion { file_name, .. } => file_name.as_deref(),
+        // These used to be written with {:?}, so older ones have quotes around them
+        Bundle::Camera { file_name, .. } => Some(file_name.trim_matches('"')),
+        _ => None,
+    }
+}
+
+/// Where a captured file can be downloaded from, if `MODKIT_MEDIA_URL` is set
+pub fn media_url(file_name: &str) -> Option<String> {
+    defaults::media_url().map(|base| format!("{}/{}", base.trim_end_matches('/'), file_name))
+}
+
+/// How long to wait before trying again after `attempts` failures
+pub fn backoff(attempts: u32) -> u32 {
+    let exponent = attempts.saturating_sub(1).min(16);
+    BASE_DELAY_SECS
+        .saturating_mul(2u32.pow(exponent))
+        .min(MAX_DELAY_SECS)
+}
+
+/// The current unix timestamp, the same format as `Event::timestamp`
+pub(crate) fn now() -> u32 {
+    chrono::Utc::now().timestamp() as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_should_notify() {
+        assert!(should_notify(&Event::new(EventKind::MailDelivered, None, None)));
+        assert!(should_notify(&Event::new(EventKind::MailPickedUp, None, None)));
+        assert!(should_notify(&Event::new(
+            EventKind::DoorOpened,
+            None,
+            Some(Bundle::ContactSensor { open: true })
+        )));
+
+        // The door closing
+        assert!(!should_notify(&Event::new(
+            EventKind::DoorOpened,
+            None,
+            Some(Bundle::ContactSensor { open: false })
+        )));
+        assert!(should_notify(&Event::new(EventKind::DoorLeftOpen, None, None)));
+        assert!(should_notify(&Event::new(EventKind::DoorClosed, None, None)));
+        assert!(should_notify(&Event::new(EventKind::SuspiciousAccess, None, None)));
+        assert!(should_notify(&Event::new(EventKind::MotionDetected, None, None)));
+        assert!(!should_notify(&Event::new(EventKind::HealthCheck, None, None)));
+        assert!(!should_notify(&Event::new(EventKind::PollDeviceResult, None, None)));
+    }
+
+    #[test]
+    fn test_backoff() {
+        assert_eq!(backoff(1), 5);
+        assert_eq!(backoff(2), 10);
+        assert_eq!(backoff(3), 20);
+        assert_eq!(backoff(100), MAX_DELAY_SECS);
+    }
+
+    #[test]
+    fn test_notification_from_event() {
+        let event = Event::new(
+            EventKind::MailDelivered,
+            None,
+            Some(Bundle::MailClassification {
+                confidence: 0.9,
+                file_name: "1234.jpg".to_string(),
+            }),
+        );
+        let notification = Notification::from_event(&event);
+        assert_eq!(notification.kind, EventKind::MailDelivered);
+        assert_eq!(notification.timestamp, event.timestamp());
+        assert_eq!(notification.priority, Priority::Normal);
+        assert_eq!(media_file(&event), Some("1234.jpg"));
+
+        let event = Event::new(
+            EventKind::PollDeviceResult,
+            None,
+            Some(Bundle::Camera {
+                file_name: r#""1234.mp4""#.to_string(),
+                duration: None,
+            }),
+        );
+        assert_eq!(media_file(&event), Some("1234.mp4"));
+
+        let event = Event::new(
+            EventKind::SuspiciousAccess,
+            None,
+            Some(Bundle::SuspiciousAccess {
+                reasons: vec![],
+                file_name: Some("5678.jpg".to_string()),
+            }),
+        );
+        assert_eq!(media_file(&event), Some("5678.jpg"));
+        assert_eq!(Notification::from_event(&event).priority, Priority::High);
+    }
+
+    #[test]
+    fn test_old_notifications_have_normal_priority() {
+        let old = r#"{"kind":"MailDelivered","timestamp":1234,"media_url":null}"#;
+        let notification: Notification = serde_json::from_str(old).unwrap();
+        assert_eq!(notification.priority, Priority::Normal);
+    }
+}
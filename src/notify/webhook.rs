@@ -0,0 +1,236 @@
+//! POSTs notifications as JSON to the urls in `MODKIT_WEBHOOK_URLS`.
+//!
+//! If `MODKIT_WEBHOOK_SECRET` is set, each request has an `X-Modkit-Signature` header with
+//! `sha256=<hex HMAC of the body>`, so the receiver can check it actually came from the box.
+//! Failed deliveries are retried with exponential backoff.
+use std::time::Duration;
+
+use hmac::{Hmac, Mac};
+use log::*;
+use sha2::Sha256;
+
+use super::{backoff, now, Notification, NotifyError, MAX_ATTEMPTS};
+use crate::defaults;
+use crate::shutdown;
+use crate::store::{Store, StoreError};
+
+/// The outbox channel for webhooks
+pub const CHANNEL: &str = "webhook";
+
+pub const SIGNATURE_HEADER: &str = "X-Modkit-Signature";
+
+/// Adds a notification to the outbox for every configured webhook url
+pub async fn queue(store: &Store, notification: &Notification) -> Result<(), NotifyError> {
+    let urls = defaults::webhook_urls();
+    if urls.is_empty() {
+        return Ok(());
+    }
+
+    let payload = serde_json::to_string(notification)?;
+    for url in urls {
+        trace!("Queueing {:?} webhook to {url}", notification.kind);
+        store.queue_notification(CHANNEL, &url, &payload, now()).await?;
+    }
+    Ok(())
+}
+
+/// The value of the signature header for a request body
+pub fn sign(secret: &str, body: &[u8]) -> String {
+    // HMAC takes keys of any length, so this can't fail
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC key");
+    mac.update(body);
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+/// Sends one payload. Anything other than a 2xx response is an error
+pub async fn deliver(
+    client: &reqwest::Client,
+    url: &str,
+    payload: &str,
+    secret: Option<&str>,
+) -> Result<(), NotifyError> {
+    let mut request = client
+        .post(url)
+        .header("Content-Type", "application/json")
+        .body(payload.to_string());
+
+    if let Some(secret) = secret {
+        request = request.header(SIGNATURE_HEADER, sign(secret, payload.as_bytes()));
+    }
+
+    let response = request.send().await?;
+    if !response.status().is_success() {
+        return Err(NotifyError::BadStatus(response.status().as_u16()));
+    }
+    Ok(())
+}
+
+/// Tries to send everything in the outbox that's due, and reschedules what fails
+pub async fn send_due(
+    store: &Store,
+    client: &reqwest::Client,
+    secret: Option<&str>,
+    now: u32,
+) -> Result<(), StoreError> {
+    for entry in store.due_notifications(CHANNEL, now).await? {
+        match deliver(client, &entry.target, &entry.payload, secret).await {
+            Ok(_) => {
+                info!("Sent webhook to {}", entry.target);
+                store.remove_notification(entry.id).await?;
+            }
+            Err(e) => {
+                let attempts = entry.attempts + 1;
+                if attempts >= MAX_ATTEMPTS {
+                    error!(
+                        "Giving up on webhook to {} after {attempts} tries: {e}",
+                        entry.target
+                    );
+                    store.remove_notification(entry.id).await?;
+                } else {
+                    let delay = backoff(attempts);
+                    warn!(
+                        "Webhook to {} failed ({e}), trying again in {delay}s",
+                        entry.target
+                    );
+                    store
+                        .retry_notification(entry.id, attempts, now + delay)
+                        .await?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Runs a continuous loop that sends queued webhooks, until we shut down
+pub async fn run() -> Result<(), Box<dyn std::error::Error>> {
+    info!("Running the webhook notifier");
+    let store = Store::connect().await?;
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(10))
+        .build()?;
+    let secret = defaults::webhook_secret();
+
+    loop {
+        if let Err(e) = send_due(&store, &client, secret.as_deref(), now()).await {
+            error!("Couldn't read the webhook outbox: {e}");
+        }
+        tokio::select! {
+            _ = tokio::time::sleep(Duration::from_secs(1)) => {}
+            _ = shutdown::stopped() => break,
+        }
+    }
+
+    // Anything left stays in the outbox for next time
+    info!("Stopped the webhook notifier");
+    store.close().await;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::mpsc;
+    use warp::hyper::body::Bytes;
+    use warp::hyper::StatusCode;
+    use warp::Filter;
+
+    use super::*;
+    use crate::model::EventKind;
+    use crate::store::TEST_DB;
+
+    // Starts a local HTTP server that stands in for a webhook receiver. It answers every
+    // request with `status` and passes along the signature header and body it got.
+    fn stand_in(status: u16) -> (String, mpsc::UnboundedReceiver<(Option<String>, Bytes)>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let route = warp::post()
+            .and(warp::header::optional::<String>(SIGNATURE_HEADER))
+            .and(warp::body::bytes())
+            .map(move |signature, body| {
+                tx.send((signature, body)).unwrap();
+                warp::reply::with_status("", StatusCode::from_u16(status).unwrap())
+            });
+        let (addr, server) = warp::serve(route).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        (format!("http://{addr}/hook"), rx)
+    }
+
+    fn payload() -> String {
+        serde_json::to_string(&Notification {
+            kind: EventKind::MailDelivered,
+            timestamp: 1234,
+            media_url: None,
+            priority: Default::default(),
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_sign() {
+        let signature = sign("secret", b"hello");
+        assert!(signature.starts_with("sha256="));
+
+        let mut mac = Hmac::<Sha256>::new_from_slice(b"secret").unwrap();
+        mac.update(b"hello");
+        let expected = hex::decode(signature.trim_start_matches("sha256=")).unwrap();
+        assert!(mac.verify_slice(&expected).is_ok());
+
+        assert_ne!(signature, sign("other secret", b"hello"));
+    }
+
+    #[tokio::test]
+    async fn test_deliver_signed() {
+        let (url, mut received) = stand_in(200);
+        let client = reqwest::Client::new();
+
+        deliver(&client, &url, &payload(), Some("secret"))
+            .await
+            .unwrap();
+
+        let (signature, body) = received.recv().await.unwrap();
+        assert_eq!(body, payload().as_bytes());
+        assert_eq!(signature, Some(sign("secret", payload().as_bytes())));
+    }
+
+    #[tokio::test]
+    async fn test_deliver_bad_status() {
+        let (url, _received) = stand_in(500);
+        let client = reqwest::Client::new();
+
+        let result = deliver(&client, &url, &payload(), None).await;
+        assert!(matches!(result, Err(NotifyError::BadStatus(500))));
+    }
+
+    #[tokio::test]
+    async fn test_send_due() {
+        let _db = TEST_DB.lock().await;
+        let store = Store::connect().await.unwrap();
+        store.nuke().await.unwrap();
+        let client = reqwest::Client::new();
+
+        let (good_url, mut received) = stand_in(200);
+        let (bad_url, _) = stand_in(503);
+        store
+            .queue_notification(CHANNEL, &good_url, &payload(), 100)
+            .await
+            .unwrap();
+        store
+            .queue_notification(CHANNEL, &bad_url, &payload(), 100)
+            .await
+            .unwrap();
+
+        send_due(&store, &client, None, 100).await.unwrap();
+        assert!(received.recv().await.is_some());
+
+        // The good one is gone, the bad one is waiting to be tried again
+        assert!(store.due_notifications(CHANNEL, 100).await.unwrap().is_empty());
+        let waiting = store
+            .due_notifications(CHANNEL, 100 + backoff(1))
+            .await
+            .unwrap();
+        assert_eq!(waiting.len(), 1);
+        assert_eq!(waiting[0].target, bad_url);
+        assert_eq!(waiting[0].attempts, 1);
+
+        store.nuke().await.unwrap();
+    }
+}
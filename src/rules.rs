@@ -0,0 +1,337 @@
+//! Rules that decide what happens with each event, on top of the defaults.
+//!
+//! A rule has a list of conditions and a list of actions. When an event comes up, every
+//! enabled rule whose conditions all match the event has its actions applied. Rules are stored
+//! in the `Rules` table and edited over the websocket with `Rules`, `SetRule` and `DeleteRule`.
+//!
+//! With no rules, events go to every client and notifier like they always have. A rule with
+//! `Suppress` stops that (ie. quiet hours), and `Notify` sends a notification for an event that
+//! normally wouldn't get one.
+use std::str::FromStr;
+
+use chrono::{DateTime, Datelike, TimeZone, Weekday};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sqlx::sqlite::SqliteRow;
+use sqlx::{FromRow, Row};
+
+use crate::drivers::device::DeviceType;
+use crate::model::{Event, EventKind};
+use crate::schedule::TimeWindow;
+use crate::store::StoreError;
+
+/// Something that has to be true about an event (or the box) for a rule to apply
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub enum Condition {
+    /// The event is any of these kinds
+    Kind { kinds: Vec<EventKind> },
+    /// The event is about this device
+    Device { device: DeviceType },
+    /// The event happened during this time of day, ie. `22:00-07:00`
+    TimeOfDay { window: TimeWindow },
+    /// The event happened on one of these days, ie. `["Sat", "Sun"]`
+    Weekday { days: Vec<String> },
+    /// Whether there's mail waiting in the box
+    MailWaiting { waiting: bool },
+    /// The door is open and has been for at least this long
+    DoorOpenFor { seconds: u32 },
+}
+
+/// What to do when a rule matches
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub enum Action {
+    /// Send notifications for the event, even if it wouldn't normally get them
+    Notify,
+    /// Take a still
+    Capture,
+    /// Turn the light on for a while
+    LightOn { seconds: u32 },
+    /// Don't send the event to clients or notifiers. It's still saved
+    Suppress,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct Rule {
+    /// Set by the database. Leave it out to make a new rule, set it to replace one
+    #[serde(default)]
+    pub id: Option<i64>,
+    pub name: String,
+    #[serde(default = "enabled")]
+    pub enabled: bool,
+    /// All of these have to match. A rule without conditions matches everything
+    pub conditions: Vec<Condition>,
+    pub actions: Vec<Action>,
+}
+
+fn enabled() -> bool {
+    true
+}
+
+/// What the box looks like when an event comes up. `now` is passed in so rules can be
+/// tested without waiting for the right time of day
+#[derive(Debug, Clone)]
+pub struct Context<Tz: TimeZone> {
+    pub now: DateTime<Tz>,
+    pub mail_waiting: bool,
+    /// Timestamp of when the door opened, if it's open
+    pub door_opened_at: Option<u32>,
+}
+
+/// What all the matching rules add up to
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Outcome {
+    pub notify: bool,
+    pub capture: bool,
+    /// The longest any rule wants the light on for
+    pub light_on: Option<u32>,
+    pub suppress: bool,
+    /// The names of the rules that matched
+    pub matched: Vec<String>,
+}
+
+impl Condition {
+    pub fn matches<Tz: TimeZone>(&self, event: &Event, context: &Context<Tz>) -> bool {
+        match self {
+            Self::Kind { kinds } => kinds.contains(event.kind()),
+            Self::Device { device } => event.device_type() == Some(device),
+            Self::TimeOfDay { window } => window.contains(context.now.time()),
+            Self::Weekday { days } => {
+                let today = context.now.weekday();
+                days.iter()
+                    .any(|day| Weekday::from_str(day).is_ok_and(|day| day == today))
+            }
+            Self::MailWaiting { waiting } => context.mail_waiting == *waiting,
+            Self::DoorOpenFor { seconds } => match context.door_opened_at {
+                Some(opened_at) => {
+                    let now = context.now.timestamp() as u32;
+                    now.saturating_sub(opened_at) >= *seconds
+                }
+                None => false,
+            },
+        }
+    }
+}
+
+impl Rule {
+    pub fn matches<Tz: TimeZone>(&self, event: &Event, context: &Context<Tz>) -> bool {
+        self.enabled && self.conditions.iter().all(|c| c.matches(event, context))
+    }
+}
+
+/// Runs every rule against an event
+pub fn evaluate<Tz: TimeZone>(rules: &[Rule], event: &Event, context: &Context<Tz>) -> Outcome {
+    let mut outcome = Outcome::default();
+
+    for rule in rules.iter().filter(|rule| rule.matches(event, context)) {
+        outcome.matched.push(rule.name.clone());
+        for action in &rule.actions {
+            match action {
+                Action::Notify => outcome.notify = true,
+                Action::Capture => outcome.capture = true,
+                Action::LightOn { seconds } => {
+                    outcome.light_on = Some(outcome.light_on.unwrap_or(0).max(*seconds))
+                }
+                Action::Suppress => outcome.suppress = true,
+            }
+        }
+    }
+
+    outcome
+}
+
+impl<'r> FromRow<'r, SqliteRow> for Rule {
+    fn from_row(row: &'r SqliteRow) -> Result<Self, sqlx::Error> {
+        let decode = |e: serde_json::Error| {
+            StoreError::DecodeError(format!("Couldn't decode rule: {e}")).into_sqlx_decode_error()
+        };
+
+        Ok(Rule {
+            id: Some(row.try_get("ID")?),
+            name: row.try_get("name")?,
+            enabled: row.try_get("enabled")?,
+            conditions: serde_json::from_str(row.try_get("conditions")?).map_err(decode)?,
+            actions: serde_json::from_str(row.try_get("actions")?).map_err(decode)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{FixedOffset, NaiveDate};
+
+    use super::*;
+    use crate::model::Bundle;
+
+    // A simulated clock: 2023-03-04 is a Saturday
+    fn at(day: u32, hour: u32, minute: u32) -> DateTime<FixedOffset> {
+        let date = NaiveDate::from_ymd_opt(2023, 3, day).unwrap();
+        FixedOffset::east_opt(3600)
+            .unwrap()
+            .from_local_datetime(&date.and_hms_opt(hour, minute, 0).unwrap())
+            .unwrap()
+    }
+
+    fn context(now: DateTime<FixedOffset>) -> Context<FixedOffset> {
+        Context {
+            now,
+            mail_waiting: false,
+            door_opened_at: None,
+        }
+    }
+
+    fn door_opened() -> Event {
+        Event::new(
+            EventKind::DoorOpened,
+            Some(DeviceType::ContactSensor),
+            Some(Bundle::ContactSensor { open: true }),
+        )
+    }
+
+    fn rule(conditions: Vec<Condition>, actions: Vec<Action>) -> Rule {
+        Rule {
+            id: None,
+            name: "test".to_string(),
+            enabled: true,
+            conditions,
+            actions,
+        }
+    }
+
+    fn quiet_hours() -> Rule {
+        rule(
+            vec![Condition::TimeOfDay {
+                window: TimeWindow::parse("22:00-07:00").unwrap(),
+            }],
+            vec![Action::Suppress],
+        )
+    }
+
+    #[test]
+    fn test_no_rules() {
+        let outcome = evaluate(&[], &door_opened(), &context(at(4, 12, 0)));
+        assert_eq!(outcome, Outcome::default());
+    }
+
+    #[test]
+    fn test_time_of_day() {
+        let rules = [quiet_hours()];
+
+        let outcome = evaluate(&rules, &door_opened(), &context(at(4, 23, 30)));
+        assert!(outcome.suppress);
+        assert_eq!(outcome.matched, vec!["test".to_string()]);
+
+        assert!(evaluate(&rules, &door_opened(), &context(at(5, 6, 59))).suppress);
+        assert!(!evaluate(&rules, &door_opened(), &context(at(5, 7, 0))).suppress);
+        assert!(!evaluate(&rules, &door_opened(), &context(at(4, 12, 0))).suppress);
+    }
+
+    #[test]
+    fn test_weekday() {
+        let rules = [rule(
+            vec![Condition::Weekday {
+                days: vec![
+                    "Sat".to_string(),
+                    "sunday".to_string(),
+                    "nonsense".to_string(),
+                ],
+            }],
+            vec![Action::Suppress],
+        )];
+
+        // Saturday, Sunday, Monday
+        assert!(evaluate(&rules, &door_opened(), &context(at(4, 12, 0))).suppress);
+        assert!(evaluate(&rules, &door_opened(), &context(at(5, 12, 0))).suppress);
+        assert!(!evaluate(&rules, &door_opened(), &context(at(6, 12, 0))).suppress);
+    }
+
+    #[test]
+    fn test_kind_and_device() {
+        let rules = [rule(
+            vec![
+                Condition::Kind {
+                    kinds: vec![EventKind::DoorOpened, EventKind::MailPickedUp],
+                },
+                Condition::Device {
+                    device: DeviceType::ContactSensor,
+                },
+            ],
+            vec![Action::Capture, Action::LightOn { seconds: 10 }],
+        )];
+
+        let outcome = evaluate(&rules, &door_opened(), &context(at(4, 12, 0)));
+        assert!(outcome.capture);
+        assert_eq!(outcome.light_on, Some(10));
+
+        // Right kind, wrong device
+        let picked_up = Event::new(EventKind::MailPickedUp, None, None);
+        assert_eq!(
+            evaluate(&rules, &picked_up, &context(at(4, 12, 0))),
+            Outcome::default()
+        );
+    }
+
+    #[test]
+    fn test_mail_waiting() {
+        let rules = [rule(
+            vec![Condition::MailWaiting { waiting: true }],
+            vec![Action::Notify],
+        )];
+
+        let mut context = context(at(4, 12, 0));
+        assert!(!evaluate(&rules, &door_opened(), &context).notify);
+        context.mail_waiting = true;
+        assert!(evaluate(&rules, &door_opened(), &context).notify);
+    }
+
+    #[test]
+    fn test_door_open_for() {
+        let rules = [rule(
+            vec![Condition::DoorOpenFor { seconds: 60 }],
+            vec![Action::Notify],
+        )];
+
+        let now = at(4, 12, 0);
+        let mut context = context(now);
+        assert!(!evaluate(&rules, &door_opened(), &context).notify);
+
+        let now = now.timestamp() as u32;
+        context.door_opened_at = Some(now - 30);
+        assert!(!evaluate(&rules, &door_opened(), &context).notify);
+        context.door_opened_at = Some(now - 60);
+        assert!(evaluate(&rules, &door_opened(), &context).notify);
+    }
+
+    #[test]
+    fn test_combining_rules() {
+        let mut disabled = quiet_hours();
+        disabled.enabled = false;
+        let rules = [
+            disabled,
+            rule(vec![], vec![Action::LightOn { seconds: 5 }]),
+            rule(
+                vec![],
+                vec![Action::LightOn { seconds: 30 }, Action::Notify],
+            ),
+        ];
+
+        let outcome = evaluate(&rules, &door_opened(), &context(at(4, 23, 0)));
+        assert!(!outcome.suppress);
+        assert!(outcome.notify);
+        assert_eq!(outcome.light_on, Some(30));
+        assert_eq!(outcome.matched.len(), 2);
+    }
+
+    #[test]
+    fn test_json() {
+        let json = r#"{
+            "name": "Quiet hours",
+            "conditions": [{"TimeOfDay": {"window": "22:00-07:00"}}],
+            "actions": ["Suppress"]
+        }"#;
+        let parsed: Rule = serde_json::from_str(json).unwrap();
+        assert_eq!(parsed.id, None);
+        assert!(parsed.enabled);
+        assert_eq!(parsed.conditions, quiet_hours().conditions);
+        assert_eq!(parsed.actions, vec![Action::Suppress]);
+    }
+}
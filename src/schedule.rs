@@ -0,0 +1,419 @@
+//! Times of day, for things like quiet hours, and cron-like schedules
+use std::convert::TryFrom;
+use std::fmt;
+
+use chrono::{
+    DateTime, Datelike, Duration, NaiveDate, NaiveDateTime, NaiveTime, TimeZone, Timelike,
+};
+use schemars::gen::SchemaGenerator;
+use schemars::schema::Schema;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/// A stretch of the day, ie. `22:00-07:00`. It can wrap around midnight.
+/// The start is included, the end isn't
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct TimeWindow {
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+}
+
+impl TimeWindow {
+    /// Parses `HH:MM-HH:MM`
+    pub fn parse(s: &str) -> Option<Self> {
+        let (start, end) = s.split_once('-')?;
+        Some(TimeWindow {
+            start: parse_time(start)?,
+            end: parse_time(end)?,
+        })
+    }
+
+    pub fn contains(&self, time: NaiveTime) -> bool {
+        if self.start <= self.end {
+            self.start <= time && time < self.end
+        } else {
+            // Wraps around midnight
+            time >= self.start || time < self.end
+        }
+    }
+
+    /// The first moment at or after `now` that's outside the window
+    pub fn next_outside<Tz: TimeZone>(&self, now: DateTime<Tz>) -> DateTime<Tz> {
+        if !self.contains(now.time()) {
+            return now;
+        }
+        next_at(now, self.end)
+    }
+}
+
+impl fmt::Display for TimeWindow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}-{}",
+            self.start.format("%H:%M"),
+            self.end.format("%H:%M")
+        )
+    }
+}
+
+// So it's written as `22:00-07:00` in json
+impl TryFrom<String> for TimeWindow {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        Self::parse(&s).ok_or(format!("{s:?} isn't a time window like `22:00-07:00`"))
+    }
+}
+
+impl From<TimeWindow> for String {
+    fn from(window: TimeWindow) -> Self {
+        window.to_string()
+    }
+}
+
+impl JsonSchema for TimeWindow {
+    fn schema_name() -> String {
+        "TimeWindow".to_string()
+    }
+
+    fn json_schema(gen: &mut SchemaGenerator) -> Schema {
+        let mut schema = String::json_schema(gen).into_object();
+        schema.string().pattern = Some(r"^\d{1,2}:\d{2}-\d{1,2}:\d{2}$".to_string());
+        schema.metadata().examples = vec!["22:00-07:00".into()];
+        schema.into()
+    }
+}
+
+/// Parses `HH:MM`
+pub fn parse_time(s: &str) -> Option<NaiveTime> {
+    NaiveTime::parse_from_str(s.trim(), "%H:%M").ok()
+}
+
+/// The next time the clock reads `time`, at or after `now`
+pub fn next_at<Tz: TimeZone>(now: DateTime<Tz>, time: NaiveTime) -> DateTime<Tz> {
+    let today = now.date_naive().and_time(time);
+    let candidate = if now.naive_local() <= today {
+        today
+    } else {
+        today + Duration::days(1)
+    };
+
+    // If that time doesn't exist (a DST gap) just go an hour later
+    now.timezone()
+        .from_local_datetime(&candidate)
+        .earliest()
+        .unwrap_or_else(|| now.clone() + (candidate - now.naive_local()) + Duration::hours(1))
+}
+
+/// A cron-like schedule, ie. `0 8-18 * * *` for every hour during the day.
+///
+/// The fields are the minute, hour, day of the month, month and day of the week (0 or 7 is
+/// Sunday). Each one can be `*`, a number, a range like `8-18`, a step like `*/15` or `8-18/2`,
+/// or a list of those separated by commas. Like cron, if both days are set then either one
+/// matching is enough
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct CronSchedule {
+    /// What it was parsed from, to write it back out
+    source: String,
+    // Each of these has a bit set for every value that matches
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    /// Whether the days of the month or week were left as `*`
+    any_day: bool,
+    any_weekday: bool,
+}
+
+impl CronSchedule {
+    /// Parses the five fields of a cron line
+    pub fn parse(s: &str) -> Option<Self> {
+        let fields: Vec<&str> = s.split_whitespace().collect();
+        if fields.len() != 5 {
+            return None;
+        }
+
+        // Sunday can be 0 or 7
+        let mut weekdays = parse_field(fields[4], 0, 7)?;
+        if weekdays & 1 << 7 != 0 {
+            weekdays = (weekdays | 1) & !(1 << 7);
+        }
+
+        Some(CronSchedule {
+            source: fields.join(" "),
+            minutes: parse_field(fields[0], 0, 59)?,
+            hours: parse_field(fields[1], 0, 23)?,
+            days: parse_field(fields[2], 1, 31)?,
+            months: parse_field(fields[3], 1, 12)?,
+            weekdays,
+            any_day: fields[2].starts_with('*'),
+            any_weekday: fields[4].starts_with('*'),
+        })
+    }
+
+    /// Whether the schedule goes off during this minute
+    pub fn matches(&self, time: &NaiveDateTime) -> bool {
+        has(self.minutes, time.minute())
+            && has(self.hours, time.hour())
+            && has(self.months, time.month())
+            && self.matches_day(time.date())
+    }
+
+    fn matches_day(&self, date: NaiveDate) -> bool {
+        let day = has(self.days, date.day());
+        let weekday = has(self.weekdays, date.weekday().num_days_from_sunday());
+        match (self.any_day, self.any_weekday) {
+            (true, true) => true,
+            (true, false) => weekday,
+            (false, true) => day,
+            (false, false) => day || weekday,
+        }
+    }
+
+    /// The next time the schedule goes off, after `now`. None if it never will,
+    /// ie. the 30th of February
+    pub fn next_after<Tz: TimeZone>(&self, now: DateTime<Tz>) -> Option<DateTime<Tz>> {
+        let mut time = now.naive_local().with_second(0)?.with_nanosecond(0)? + Duration::minutes(1);
+        // Long enough for the 29th of February to come around
+        let limit = time + Duration::days(366 * 5);
+
+        while time < limit {
+            // Skip whole months, days and hours that don't match, so this doesn't take long
+            if !has(self.months, time.month()) {
+                let (year, month) = match time.month() {
+                    12 => (time.year() + 1, 1),
+                    month => (time.year(), month + 1),
+                };
+                time = NaiveDate::from_ymd_opt(year, month, 1)?.and_hms_opt(0, 0, 0)?;
+            } else if !self.matches_day(time.date()) {
+                time = time.date().succ_opt()?.and_hms_opt(0, 0, 0)?;
+            } else if !has(self.hours, time.hour()) {
+                time = time.date().and_hms_opt(time.hour(), 0, 0)? + Duration::hours(1);
+            } else if !has(self.minutes, time.minute()) {
+                time += Duration::minutes(1);
+            } else {
+                // If that time doesn't exist (a DST gap) keep looking
+                match now.timezone().from_local_datetime(&time).earliest() {
+                    Some(next) => return Some(next),
+                    None => time += Duration::minutes(1),
+                }
+            }
+        }
+        None
+    }
+}
+
+impl fmt::Display for CronSchedule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+// So it's written as `0 8-18 * * *` in json
+impl TryFrom<String> for CronSchedule {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        Self::parse(&s).ok_or(format!("{s:?} isn't a schedule like `0 8-18 * * *`"))
+    }
+}
+
+impl From<CronSchedule> for String {
+    fn from(schedule: CronSchedule) -> Self {
+        schedule.to_string()
+    }
+}
+
+/// Parses one field of a cron line into a bit for every value that matches
+fn parse_field(field: &str, min: u32, max: u32) -> Option<u64> {
+    let mut values = 0;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse().ok().filter(|step| *step > 0)?),
+            None => (part, 1),
+        };
+        let (start, end) = match range.split_once('-') {
+            _ if range == "*" => (min, max),
+            Some((start, end)) => (start.parse().ok()?, end.parse().ok()?),
+            // `5/15` means from 5 on, every 15
+            None if step > 1 => (range.parse().ok()?, max),
+            None => {
+                let value = range.parse().ok()?;
+                (value, value)
+            }
+        };
+        if start < min || end > max || start > end {
+            return None;
+        }
+        for value in (start..=end).step_by(step) {
+            values |= 1 << value;
+        }
+    }
+    Some(values)
+}
+
+fn has(values: u64, value: u32) -> bool {
+    values & 1 << value != 0
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use chrono::{NaiveDate, Utc};
+
+    use super::*;
+
+    // A time in March 2023
+    pub(crate) fn utc(day: u32, hour: u32, minute: u32) -> DateTime<Utc> {
+        let date = NaiveDate::from_ymd_opt(2023, 3, day).unwrap();
+        Utc.from_utc_datetime(&date.and_hms_opt(hour, minute, 0).unwrap())
+    }
+
+    fn at(h: u32, m: u32) -> NaiveTime {
+        NaiveTime::from_hms_opt(h, m, 0).unwrap()
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            TimeWindow::parse("22:00-07:30"),
+            Some(TimeWindow {
+                start: at(22, 0),
+                end: at(7, 30)
+            })
+        );
+        assert_eq!(TimeWindow::parse("22:00"), None);
+        assert_eq!(TimeWindow::parse("25:00-07:00"), None);
+    }
+
+    #[test]
+    fn test_json() {
+        let window = TimeWindow::parse("22:00-07:30").unwrap();
+        let json = serde_json::to_string(&window).unwrap();
+        assert_eq!(json, r#""22:00-07:30""#);
+        assert_eq!(serde_json::from_str::<TimeWindow>(&json).unwrap(), window);
+        assert!(serde_json::from_str::<TimeWindow>(r#""soon""#).is_err());
+    }
+
+    #[test]
+    fn test_contains() {
+        let day = TimeWindow::parse("09:00-17:00").unwrap();
+        assert!(day.contains(at(9, 0)));
+        assert!(day.contains(at(12, 0)));
+        assert!(!day.contains(at(17, 0)));
+        assert!(!day.contains(at(3, 0)));
+
+        let night = TimeWindow::parse("22:00-07:00").unwrap();
+        assert!(night.contains(at(23, 0)));
+        assert!(night.contains(at(2, 0)));
+        assert!(!night.contains(at(7, 0)));
+        assert!(!night.contains(at(12, 0)));
+    }
+
+    #[test]
+    fn test_next_outside() {
+        let night = TimeWindow::parse("22:00-07:00").unwrap();
+
+        let noon = utc(1, 12, 0);
+        assert_eq!(night.next_outside(noon), noon);
+
+        let late = utc(1, 23, 0);
+        assert_eq!(night.next_outside(late), utc(2, 7, 0));
+
+        let early = utc(2, 1, 0);
+        assert_eq!(night.next_outside(early), utc(2, 7, 0));
+    }
+
+    #[test]
+    fn test_next_at() {
+        let now = utc(1, 12, 0);
+        assert_eq!(next_at(now, at(18, 0)), utc(1, 18, 0));
+        assert_eq!(next_at(now, at(8, 0)), utc(2, 8, 0));
+        assert_eq!(next_at(now, at(12, 0)), now);
+    }
+
+    #[test]
+    fn test_cron_parse() {
+        let daytime = CronSchedule::parse("0 8-18 * * *").unwrap();
+        assert_eq!(daytime.minutes, 1);
+        assert_eq!(daytime.hours, 0b1111111111100000000);
+        assert!(daytime.any_day && daytime.any_weekday);
+
+        let steps = CronSchedule::parse("*/15 0-6/2,12 * * 1-5").unwrap();
+        assert_eq!(steps.minutes, 1 | 1 << 15 | 1 << 30 | 1 << 45);
+        assert_eq!(steps.hours, 1 | 1 << 2 | 1 << 4 | 1 << 6 | 1 << 12);
+        assert_eq!(steps.weekdays, 0b111110);
+        assert!(!steps.any_weekday);
+
+        // Sunday both ways
+        assert_eq!(CronSchedule::parse("0 0 * * 7").unwrap().weekdays, 1);
+        assert_eq!(CronSchedule::parse("0 0 * * 0").unwrap().weekdays, 1);
+
+        assert_eq!(CronSchedule::parse("0 8-18 * *"), None);
+        assert_eq!(CronSchedule::parse("60 * * * *"), None);
+        assert_eq!(CronSchedule::parse("0 18-8 * * *"), None);
+        assert_eq!(CronSchedule::parse("*/0 * * * *"), None);
+        assert_eq!(CronSchedule::parse("0 0 0 * *"), None);
+        assert_eq!(CronSchedule::parse("hourly"), None);
+    }
+
+    #[test]
+    fn test_cron_json() {
+        let schedule = CronSchedule::parse("0  8-18 * *   *").unwrap();
+        let json = serde_json::to_string(&schedule).unwrap();
+        assert_eq!(json, r#""0 8-18 * * *""#);
+        assert_eq!(
+            serde_json::from_str::<CronSchedule>(&json).unwrap(),
+            schedule
+        );
+        assert!(serde_json::from_str::<CronSchedule>(r#""sometimes""#).is_err());
+    }
+
+    #[test]
+    fn test_cron_next_after() {
+        // 1 March 2023 was a Wednesday
+        let daytime = CronSchedule::parse("0 8-18 * * *").unwrap();
+        assert_eq!(daytime.next_after(utc(1, 12, 0)), Some(utc(1, 13, 0)));
+        assert_eq!(daytime.next_after(utc(1, 12, 30)), Some(utc(1, 13, 0)));
+        assert_eq!(daytime.next_after(utc(1, 18, 0)), Some(utc(2, 8, 0)));
+        assert_eq!(daytime.next_after(utc(1, 3, 0)), Some(utc(1, 8, 0)));
+
+        let quarters = CronSchedule::parse("*/15 * * * *").unwrap();
+        assert_eq!(quarters.next_after(utc(1, 12, 7)), Some(utc(1, 12, 15)));
+        assert_eq!(quarters.next_after(utc(1, 23, 50)), Some(utc(2, 0, 0)));
+
+        let weekends = CronSchedule::parse("30 9 * * 6,0").unwrap();
+        assert_eq!(weekends.next_after(utc(1, 12, 0)), Some(utc(4, 9, 30)));
+        assert_eq!(weekends.next_after(utc(4, 9, 30)), Some(utc(5, 9, 30)));
+
+        // The 1st of the month or a Monday
+        let either = CronSchedule::parse("0 0 1 * 1").unwrap();
+        assert_eq!(either.next_after(utc(1, 12, 0)), Some(utc(6, 0, 0)));
+
+        // Into April, then the next year
+        let monthly = CronSchedule::parse("0 0 1 * *").unwrap();
+        assert_eq!(
+            monthly.next_after(utc(1, 12, 0)).unwrap().date_naive(),
+            NaiveDate::from_ymd_opt(2023, 4, 1).unwrap()
+        );
+        let new_year = CronSchedule::parse("0 0 1 1 *").unwrap();
+        assert_eq!(
+            new_year.next_after(utc(1, 12, 0)).unwrap().date_naive(),
+            NaiveDate::from_ymd_opt(2024, 1, 1).unwrap()
+        );
+
+        let never = CronSchedule::parse("0 0 30 2 *").unwrap();
+        assert_eq!(never.next_after(utc(1, 12, 0)), None);
+    }
+
+    #[test]
+    fn test_cron_matches() {
+        let daytime = CronSchedule::parse("0 8-18 * * *").unwrap();
+        assert!(daytime.matches(&utc(1, 8, 0).naive_utc()));
+        assert!(!daytime.matches(&utc(1, 8, 1).naive_utc()));
+        assert!(!daytime.matches(&utc(1, 19, 0).naive_utc()));
+    }
+}
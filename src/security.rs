@@ -0,0 +1,187 @@
+//! Security mode: flags door openings that don't look like a normal delivery or pickup.
+//!
+//! An opening is suspicious if it's outside the delivery windows, if the door has been opened
+//! a lot in a short time, or if it's right after the mail was picked up (someone going back
+//! for what's left, or a thief following the owner). The watchdog sends a `SuspiciousAccess`
+//! event for these, with a still, and it's notified with a high priority.
+use std::collections::VecDeque;
+
+use chrono::{DateTime, TimeZone};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::defaults;
+use crate::schedule::TimeWindow;
+
+/// Why an opening looks suspicious
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub enum AccessReason {
+    /// Nobody delivers mail at this time of day
+    OutsideDeliveryWindow,
+    /// The door was opened this many times in the repeat window
+    RepeatedOpening { count: u32 },
+    /// The door was opened this many seconds after the mail was picked up
+    AfterPickup { seconds: u32 },
+}
+
+#[derive(Debug, Clone)]
+pub struct SecurityMonitor {
+    /// When we expect the door to be opened. Empty means any time
+    delivery_windows: Vec<TimeWindow>,
+    /// This many openings...
+    repeat_count: u32,
+    /// ...within this many seconds is suspicious
+    repeat_window: u32,
+    /// Openings this many seconds after a pickup are suspicious
+    after_pickup: u32,
+    /// Timestamps of openings inside the repeat window
+    recent_openings: VecDeque<u32>,
+    last_pickup: Option<u32>,
+}
+
+impl SecurityMonitor {
+    pub fn new(
+        delivery_windows: Vec<TimeWindow>,
+        repeat_count: u32,
+        repeat_window: u32,
+        after_pickup: u32,
+    ) -> Self {
+        SecurityMonitor {
+            delivery_windows,
+            repeat_count,
+            repeat_window,
+            after_pickup,
+            recent_openings: VecDeque::new(),
+            last_pickup: None,
+        }
+    }
+
+    /// Uses the settings from the environment, see `defaults`
+    pub fn from_env() -> Self {
+        Self::new(
+            defaults::delivery_windows(),
+            defaults::security_repeat_count(),
+            defaults::security_repeat_window(),
+            defaults::security_after_pickup(),
+        )
+    }
+
+    /// Records that the mail was picked up
+    pub fn picked_up(&mut self, timestamp: u32) {
+        self.last_pickup = Some(timestamp);
+    }
+
+    /// Records the door opening, and returns everything that's suspicious about it.
+    /// An empty list means it looks fine
+    pub fn opened<Tz: TimeZone>(&mut self, now: DateTime<Tz>) -> Vec<AccessReason> {
+        let timestamp = now.timestamp() as u32;
+        let mut reasons = Vec::new();
+
+        if !self.delivery_windows.is_empty()
+            && !self.delivery_windows.iter().any(|w| w.contains(now.time()))
+        {
+            reasons.push(AccessReason::OutsideDeliveryWindow);
+        }
+
+        self.recent_openings.push_back(timestamp);
+        while let Some(first) = self.recent_openings.front() {
+            if timestamp.saturating_sub(*first) > self.repeat_window {
+                self.recent_openings.pop_front();
+            } else {
+                break;
+            }
+        }
+        let count = self.recent_openings.len() as u32;
+        if self.repeat_count > 0 && count >= self.repeat_count {
+            reasons.push(AccessReason::RepeatedOpening { count });
+        }
+
+        if let Some(pickup) = self.last_pickup {
+            let seconds = timestamp.saturating_sub(pickup);
+            if seconds <= self.after_pickup {
+                reasons.push(AccessReason::AfterPickup { seconds });
+            }
+        }
+
+        reasons
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{FixedOffset, NaiveDate};
+
+    use super::*;
+
+    fn at(hour: u32, minute: u32, second: u32) -> DateTime<FixedOffset> {
+        let date = NaiveDate::from_ymd_opt(2023, 3, 6).unwrap();
+        FixedOffset::west_opt(5 * 3600)
+            .unwrap()
+            .from_local_datetime(&date.and_hms_opt(hour, minute, second).unwrap())
+            .unwrap()
+    }
+
+    fn monitor() -> SecurityMonitor {
+        SecurityMonitor::new(vec![TimeWindow::parse("09:00-17:00").unwrap()], 3, 600, 300)
+    }
+
+    #[test]
+    fn test_normal_opening() {
+        assert!(monitor().opened(at(11, 0, 0)).is_empty());
+    }
+
+    #[test]
+    fn test_outside_delivery_window() {
+        let mut monitor = monitor();
+        assert_eq!(
+            monitor.opened(at(2, 30, 0)),
+            vec![AccessReason::OutsideDeliveryWindow]
+        );
+        assert_eq!(
+            monitor.opened(at(17, 0, 0)),
+            vec![AccessReason::OutsideDeliveryWindow]
+        );
+
+        // No windows means any time is fine
+        let mut anytime = SecurityMonitor::new(vec![], 3, 600, 300);
+        assert!(anytime.opened(at(2, 30, 0)).is_empty());
+    }
+
+    #[test]
+    fn test_repeated_opening() {
+        let mut monitor = monitor();
+        assert!(monitor.opened(at(11, 0, 0)).is_empty());
+        assert!(monitor.opened(at(11, 4, 0)).is_empty());
+        assert_eq!(
+            monitor.opened(at(11, 8, 0)),
+            vec![AccessReason::RepeatedOpening { count: 3 }]
+        );
+
+        // The first two have dropped out of the window by now
+        assert!(monitor.opened(at(11, 18, 30)).is_empty());
+    }
+
+    #[test]
+    fn test_after_pickup() {
+        let mut monitor = monitor();
+        monitor.picked_up(at(12, 0, 0).timestamp() as u32);
+        assert_eq!(
+            monitor.opened(at(12, 2, 0)),
+            vec![AccessReason::AfterPickup { seconds: 120 }]
+        );
+        assert!(monitor.opened(at(12, 30, 0)).is_empty());
+    }
+
+    #[test]
+    fn test_several_reasons() {
+        let mut monitor = monitor();
+        monitor.picked_up(at(20, 0, 0).timestamp() as u32);
+        assert_eq!(
+            monitor.opened(at(20, 1, 0)),
+            vec![
+                AccessReason::OutsideDeliveryWindow,
+                AccessReason::AfterPickup { seconds: 60 }
+            ]
+        );
+    }
+}
@@ -26,12 +26,19 @@ pub type Clients = Arc<Mutex<HashMap<String, Client>>>;
 #[derive(Clone, Debug)]
 pub struct Client {
     pub client_id: String,
+    /// The protocol version agreed on when the client registered
+    pub protocol_version: u16,
+    /// Whether this client has logged in with the right PIN
+    pub authorized: bool,
     pub sender: Option<mpsc::UnboundedSender<std::result::Result<Message, warp::Error>>>,
 }
 
 /// Functions of the websocket
 pub mod ws {
     use crate::defaults;
+    use crate::drivers::camera::camera;
+    use crate::drivers::device::DeviceType;
+    use crate::drivers::light::light;
 
     use super::*;
 
@@ -41,17 +48,20 @@ pub mod ws {
         for (id, client) in lock.iter() {
             info!("Sending to client {id}");
             if let Some(sender) = &client.sender {
-                sender
-                    .send(Ok(event.clone().to_msg()))
-                    .expect("Couldn't send message to client");
+                // It's removed from the list once it notices, no need to panic
+                if sender.send(Ok(event.clone().to_msg())).is_err() {
+                    warn!("Couldn't send to client {id}, it probably disconnected");
+                }
             }
         }
         drop(lock);
     }
 
     /// handles an incoming Event through the websocket
-    /// and returns a response Event
-    pub async fn handle_message(msg: Message) -> Event {
+    /// and returns a response Event.
+    ///
+    /// If the incoming event has an `id`, the response carries the same `id`
+    pub async fn handle_message(msg: Message, client: &mut Client) -> Event {
         // Capture the msg if we can get one
         let msg = match msg.to_str() {
             Ok(m) => m,
@@ -79,9 +89,12 @@ pub mod ws {
             }
         };
 
+        // Hold on to the client's id so we can tag the response with it
+        let id = event.id().map(String::from);
+
         // Filter out outgoing events; they shouldn't be allowed
         if event.kind().is_outgoing() {
-            return wrong_way();
+            return wrong_way().with_id(id);
         }
 
         // Populate a timestamp so that incoming events have one when saving
@@ -93,7 +106,7 @@ pub mod ws {
         match Store::connect().await {
             Ok(store) => {
                 store
-                    .write_event(event.clone())
+                    .write_event(event.clone().without_pin())
                     .await
                     .expect("Couldn't write event to the database");
             }
@@ -105,12 +118,26 @@ pub mod ws {
             }
         }
 
-        match event.kind() {
+        let response = match event.kind() {
             EventKind::HealthCheck => handle_health_check(&event),
             EventKind::PollDevice => handle_poll_device(&mut event),
             EventKind::EventHistory => handle_event_history().await,
-            EventKind::PinCheck => handle_pin_check(&event),
+            EventKind::PinCheck => {
+                let response = handle_pin_check(&event);
+                // Remember if they logged in, some events need it
+                if let Some(Bundle::PinResult { authorized }) = response.data() {
+                    client.authorized = *authorized;
+                }
+                response
+            }
             EventKind::MailStatus => handle_mail_status().await,
+            EventKind::MailSummary => handle_mail_summary().await,
+            EventKind::SetMailStatus => handle_set_mail_status(&event, client).await,
+            EventKind::Rules => handle_rules().await,
+            EventKind::SetRule => handle_set_rule(&event, client).await,
+            EventKind::DeleteRule => handle_delete_rule(&event, client).await,
+            EventKind::ArmState => handle_arm_state().await,
+            EventKind::SetArmState => handle_set_arm_state(&event, client).await,
             // We already filtered out outgoing events, so this must mean we added a new
             // type of incoming event and didn't write a handler for it
             _ => {
@@ -123,7 +150,9 @@ pub mod ws {
                     "Unplanned incoming event or rogue outgoing event, this shouldn't happen!",
                 )
             }
-        }
+        };
+
+        response.with_id(id)
     }
 
     pub fn wrong_way() -> Event {
@@ -183,25 +212,222 @@ pub mod ws {
     }
 
     pub async fn handle_event_history() -> Event {
+        let db = match Store::connect().await {
+            Ok(db) => db,
+            Err(e) => return Event::error(&format!("{e}")),
+        };
+        match db.get_all_events().await {
+            // Older versions saved the PINs
+            Ok(events) => Event::new(
+                EventKind::EventHistory,
+                None,
+                Some(Bundle::EventHistory {
+                    events: events.into_iter().map(Event::without_pin).collect(),
+                }),
+            ),
+            Err(e) => Event::error(&format!("{e}")),
+        }
+    }
+
+    pub async fn handle_mail_summary() -> Event {
         let db = Store::connect()
             .await
             .expect("Couldn't access the database!");
-        let events = db
-            .get_all_events()
+        match db.get_mail_summary().await {
+            Ok(summary) => Event::new(EventKind::MailSummary, None, Some(summary)),
+            Err(e) => Event::error(&format!("{e}")),
+        }
+    }
+
+    /// Corrects the mail status by hand. The client has to be logged in
+    pub async fn handle_set_mail_status(event: &Event, client: &Client) -> Event {
+        if !client.authorized {
+            return not_authorized();
+        }
+
+        match event.data() {
+            Some(Bundle::SetMailStatus { delivered, name }) => {
+                let corrected_by = name
+                    .clone()
+                    .unwrap_or_else(|| format!("client {}", client.client_id));
+                set_mail_status(*delivered, corrected_by).await
+            }
+            _ => Event::error("Please provide a SetMailStatus bundle (`delivered`, `name`)"),
+        }
+    }
+
+    /// Writes a MailDelivered or MailPickedUp event that's flagged as manual,
+    /// which becomes the new mail status
+    pub async fn set_mail_status(delivered: bool, corrected_by: String) -> Event {
+        let kind = if delivered {
+            EventKind::MailDelivered
+        } else {
+            EventKind::MailPickedUp
+        };
+        info!("{corrected_by} is setting the mail status to {kind}");
+
+        let event = Event::new(kind, None, Some(Bundle::ManualMailStatus { corrected_by }));
+
+        let db = match Store::connect().await {
+            Ok(db) => db,
+            Err(e) => return Event::error(&format!("{e}")),
+        };
+        if let Err(e) = db.write_event(event.clone()).await {
+            return Event::error(&format!("{e}"));
+        }
+
+        event
+    }
+
+    pub async fn handle_rules() -> Event {
+        let db = match Store::connect().await {
+            Ok(db) => db,
+            Err(e) => return Event::error(&format!("{e}")),
+        };
+        rules_event(&db).await
+    }
+
+    /// Adds or replaces a rule. The client has to be logged in
+    pub async fn handle_set_rule(event: &Event, client: &Client) -> Event {
+        if !client.authorized {
+            return not_authorized();
+        }
+
+        let rule = match event.data() {
+            Some(Bundle::SetRule { rule }) => rule,
+            _ => return Event::error("Please provide a SetRule bundle (`rule`)"),
+        };
+        let db = match Store::connect().await {
+            Ok(db) => db,
+            Err(e) => return Event::error(&format!("{e}")),
+        };
+        match db.save_rule(rule).await {
+            Ok(saved) => info!("Saved rule {:?} ({})", saved.id, saved.name),
+            Err(e) => return Event::error(&format!("{e}")),
+        }
+        rules_event(&db).await
+    }
+
+    /// Deletes a rule. The client has to be logged in
+    pub async fn handle_delete_rule(event: &Event, client: &Client) -> Event {
+        if !client.authorized {
+            return not_authorized();
+        }
+
+        let id = match event.data() {
+            Some(Bundle::DeleteRule { id }) => *id,
+            _ => return Event::error("Please provide a DeleteRule bundle (`id`)"),
+        };
+        let db = match Store::connect().await {
+            Ok(db) => db,
+            Err(e) => return Event::error(&format!("{e}")),
+        };
+        match db.delete_rule(id).await {
+            Ok(true) => info!("Deleted rule {id}"),
+            Ok(false) => return Event::error(&format!("There's no rule with id {id}")),
+            Err(e) => return Event::error(&format!("{e}")),
+        }
+        rules_event(&db).await
+    }
+
+    pub async fn handle_arm_state() -> Event {
+        let db = match Store::connect().await {
+            Ok(db) => db,
+            Err(e) => return Event::error(&format!("{e}")),
+        };
+        match db.get_arm_state().await {
+            Ok(state) => Event::new(EventKind::ArmState, None, Some(state)),
+            Err(e) => Event::error(&format!("{e}")),
+        }
+    }
+
+    /// Arms or disarms the mailbox. The bundle has to have the PIN, even if the
+    /// client is logged in
+    pub async fn handle_set_arm_state(event: &Event, client: &Client) -> Event {
+        let (armed, name) = match event.data() {
+            Some(Bundle::SetArmState { armed, pin, name }) => {
+                if *pin != defaults::pin() {
+                    return Event::error("Wrong PIN");
+                }
+                (*armed, name)
+            }
+            _ => {
+                return Event::error("Please provide a SetArmState bundle (`armed`, `pin`, `name`)")
+            }
+        };
+        let changed_by = name
+            .clone()
+            .unwrap_or_else(|| format!("client {}", client.client_id));
+
+        let db = match Store::connect().await {
+            Ok(db) => db,
+            Err(e) => return Event::error(&format!("{e}")),
+        };
+        match db
+            .set_arm_state(armed, &changed_by, event.timestamp())
             .await
-            .expect("Couldn't get events from the db");
+        {
+            Ok(state) => {
+                info!(
+                    "{changed_by} {} the mailbox",
+                    if armed { "armed" } else { "disarmed" }
+                );
+                Event::new(EventKind::ArmState, None, Some(state))
+            }
+            Err(e) => Event::error(&format!("{e}")),
+        }
+    }
 
-        Event::new(
-            EventKind::EventHistory,
+    /// Turns the light on or off. Answers with its state like a `PollDevice` would
+    pub fn set_light(on: bool) -> Event {
+        if let Err(e) = light::set(on) {
+            return e.into();
+        }
+        handle_poll_device(&mut Event::new(
+            EventKind::PollDevice,
+            Some(DeviceType::Light),
             None,
-            Some(Bundle::EventHistory { events }),
-        )
+        ))
+    }
+
+    /// Takes a still now. Answers with a `Camera` bundle
+    pub async fn capture_still() -> Event {
+        // Taking a picture blocks for a bit
+        match tokio::task::spawn_blocking(|| camera::capture_still(None)).await {
+            Ok(Ok(path)) => Event::new(
+                EventKind::PollDeviceResult,
+                Some(DeviceType::Camera),
+                Some(Bundle::Camera {
+                    file_name: path
+                        .file_name()
+                        .expect("image file name")
+                        .to_string_lossy()
+                        .to_string(),
+                    duration: None,
+                }),
+            ),
+            Ok(Err(e)) => e.into(),
+            Err(e) => Event::error(&format!("Picture task failed: {e}")),
+        }
+    }
+
+    /// A Rules event with every rule in the db
+    async fn rules_event(db: &Store) -> Event {
+        match db.get_rules().await {
+            Ok(rules) => Event::new(EventKind::Rules, None, Some(Bundle::Rules { rules })),
+            Err(e) => Event::error(&format!("{e}")),
+        }
+    }
+
+    pub fn not_authorized() -> Event {
+        Event::error("You need to log in with the PIN first")
     }
 
     pub async fn handle_mail_status() -> Event {
-        let db = Store::connect()
-            .await
-            .expect("Couldn't access the database!");
+        let db = match Store::connect().await {
+            Ok(db) => db,
+            Err(e) => return Event::error(&format!("{e}")),
+        };
         match db.get_mail_status().await {
             Ok(event) => return event,
             Err(e) => return Event::error(&format!("{e}")),
@@ -214,11 +440,131 @@ pub mod ws {
 pub mod http {
     use local_ip_address::linux::local_ip;
 
+    use crate::defaults;
+    use crate::drivers::device::DeviceType;
+    use crate::metrics;
+    use crate::model::schema::protocol_schema;
+    use crate::supervisor;
+
     use super::*;
 
     #[derive(Debug, Serialize, Deserialize)]
     pub(crate) struct RegisterResponse {
         url: String,
+        /// The protocol version the client should speak on the websocket
+        pub(crate) protocol_version: u16,
+    }
+
+    /// Query parameters accepted by `/register`
+    #[derive(Debug, Deserialize)]
+    pub(crate) struct RegisterQuery {
+        /// The newest protocol version the client understands.
+        /// Clients that don't send one are assumed to speak version 1
+        protocol_version: Option<u16>,
+    }
+
+    /// Body of `POST /mail/status`
+    #[derive(Debug, Serialize, Deserialize)]
+    pub(crate) struct SetMailStatusRequest {
+        delivered: bool,
+        /// Who is making the correction. Defaults to the caller's address
+        name: Option<String>,
+    }
+
+    /// Body of `POST /devices/light`
+    #[derive(Debug, Serialize, Deserialize)]
+    pub(crate) struct LightRequest {
+        on: bool,
+    }
+
+    /// Query parameters accepted by `/events/stream`
+    #[derive(Debug, Deserialize)]
+    pub(crate) struct StreamQuery {
+        /// Browsers can't send headers with an EventSource, so the PIN can go here too
+        pin: Option<u16>,
+    }
+
+    /// How often to send a comment down an idle event stream, so proxies don't close it
+    const KEEPALIVE: std::time::Duration = std::time::Duration::from_secs(15);
+
+    /// The header HTTP clients use to send the PIN
+    pub const PIN_HEADER: &str = "x-modkit-pin";
+
+    /// Checks a PIN sent in the `X-Modkit-Pin` header
+    pub fn pin_authorized(pin: Option<u16>) -> bool {
+        pin == Some(defaults::pin())
+    }
+
+    pub fn mail_route() -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+        warp::path!("mail" / "status")
+            .and(warp::post())
+            .and(warp::header::optional::<u16>(PIN_HEADER))
+            .and(warp::addr::remote())
+            .and(warp::body::json())
+            .and_then(set_mail_status_handler)
+    }
+
+    /// The REST API. Each route does the same as an event through the websocket and answers
+    /// with the same event as json. Errors are `Error` events with a matching status code.
+    /// Routes that change something, or that give away the history, need the PIN in the
+    /// `X-Modkit-Pin` header
+    pub fn api_routes(
+        ws_clients: &Clients,
+    ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+        let events = warp::path!("events")
+            .and(warp::get())
+            .and(warp::header::optional::<u16>(PIN_HEADER))
+            .and_then(events_handler);
+        let mail_status = warp::path!("mail" / "status")
+            .and(warp::get())
+            .and_then(mail_status_handler);
+        let device = warp::path!("devices" / String)
+            .and(warp::get())
+            .and_then(device_handler);
+        let light = warp::path!("devices" / "light")
+            .and(warp::post())
+            .and(warp::header::optional::<u16>(PIN_HEADER))
+            .and(warp::body::json())
+            .and(with_clients(ws_clients.clone()))
+            .and_then(light_handler);
+        let capture = warp::path!("capture")
+            .and(warp::post())
+            .and(warp::header::optional::<u16>(PIN_HEADER))
+            .and(with_clients(ws_clients.clone()))
+            .and_then(capture_handler);
+        let stream = warp::path!("events" / "stream")
+            .and(warp::get())
+            .and(warp::header::optional::<u16>(PIN_HEADER))
+            .and(warp::query::<StreamQuery>())
+            .and(warp::header::optional::<u32>("last-event-id"))
+            .and(with_clients(ws_clients.clone()))
+            .and_then(event_stream_handler);
+        let schema = warp::path!("schema")
+            .and(warp::get())
+            .map(|| json(&protocol_schema()));
+        let health = warp::path!("health").and(warp::get()).map(health_reply);
+        let metrics = warp::path!("metrics")
+            .and(warp::get())
+            .and(with_clients(ws_clients.clone()))
+            .and_then(metrics_handler);
+
+        events
+            .or(stream)
+            .or(mail_status)
+            .or(device)
+            .or(light)
+            .or(capture)
+            .or(schema)
+            .or(health)
+            .or(metrics)
+    }
+
+    /// Picks the protocol version to use with a client, or None if we can't talk to it
+    pub fn negotiate_protocol(requested: u16) -> Option<u16> {
+        if requested < MIN_PROTOCOL_VERSION {
+            return None;
+        }
+        Some(requested.min(PROTOCOL_VERSION))
     }
 
     pub fn register_route(
@@ -227,6 +573,7 @@ pub mod http {
         let register = warp::path("register");
         let register_routes = register
             .and(warp::get())
+            .and(warp::query::<RegisterQuery>())
             .and(with_clients(ws_clients.clone()))
             .and_then(register_handler)
             .or(register
@@ -247,11 +594,16 @@ pub mod http {
             .and_then(connect_client)
     }
 
-    /// Starts up the webserver
+    /// Starts up the webserver, and runs it until we shut down
     pub async fn run(ws_clients: &Clients) {
         info!("Running the WebSocket server");
 
-        let routes = register_route(&ws_clients).or(ws_route(&ws_clients)).with(
+        let routes = register_route(ws_clients)
+            .or(ws_route(ws_clients))
+            .or(mail_route())
+            .or(api_routes(ws_clients))
+            .recover(handle_rejection)
+            .with(
             warp::cors()
                 .allow_any_origin()
                 .allow_headers(vec![
@@ -269,11 +621,17 @@ pub mod http {
                     "Sec-Fetch-Mode",
                     "Sec-Fetch-Site",
                     "User-Agent",
+                    "X-Modkit-Pin",
                 ])
                 .allow_methods(vec!["GET", "OPTIONS", "POST", "DELETE"]),
         );
 
-        warp::serve(routes).run(([0, 0, 0, 0], 3012)).await
+        // Stops taking new connections when we shut down, and waits for the open ones to finish.
+        // The websockets and event streams are hung up on by `shutdown::announce`
+        let (_, server) = warp::serve(routes)
+            .bind_with_graceful_shutdown(([0, 0, 0, 0], 3012), crate::shutdown::stopped());
+        server.await;
+        info!("Stopped the WebSocket server");
     }
 
     // Attaches Clients to a warp route
@@ -284,10 +642,27 @@ pub mod http {
     }
 
     // Register a new client and return the ws address with the client id in it
-    pub async fn register_handler(clients: Clients) -> Result<impl Reply, Rejection> {
+    pub(crate) async fn register_handler(
+        query: RegisterQuery,
+        clients: Clients,
+    ) -> Result<warp::reply::Response, Rejection> {
+        let requested = query.protocol_version.unwrap_or(1);
+        let protocol_version = match negotiate_protocol(requested) {
+            Some(v) => v,
+            None => {
+                error!("Client asked for unsupported protocol version {requested}");
+                let err = Event::error(&format!(
+                    "Unsupported protocol version {requested}, this server speaks {MIN_PROTOCOL_VERSION} through {PROTOCOL_VERSION}"
+                ));
+                return Ok(
+                    warp::reply::with_status(json(&err), StatusCode::BAD_REQUEST).into_response(),
+                );
+            }
+        };
+
         let uuid = Uuid::new_v4().simple().to_string();
-        register_client(uuid.clone(), clients.clone()).await;
-        info!("Just registered a client with id: {}", uuid);
+        register_client(uuid.clone(), protocol_version, clients.clone()).await;
+        info!("Just registered a client with id: {} (protocol version {protocol_version})", uuid);
         info!("All clients: {:#?}", clients);
 
         // Get our local IP so that the interface knows where to connect
@@ -296,15 +671,268 @@ pub mod http {
 
         Ok(json(&RegisterResponse {
             url: format!("ws://{ip}:3012/ws/{}", uuid),
-        }))
+            protocol_version,
+        })
+        .into_response())
+    }
+
+    // Corrects the mail status, the same as a SetMailStatus event through the websocket
+    pub(crate) async fn set_mail_status_handler(
+        pin: Option<u16>,
+        addr: Option<std::net::SocketAddr>,
+        body: SetMailStatusRequest,
+    ) -> Result<impl Reply, Rejection> {
+        if !pin_authorized(pin) {
+            return Ok(warp::reply::with_status(
+                json(&ws::not_authorized()),
+                StatusCode::UNAUTHORIZED,
+            ));
+        }
+
+        let corrected_by = body.name.unwrap_or_else(|| match addr {
+            Some(addr) => format!("http {}", addr.ip()),
+            None => "http".to_string(),
+        });
+
+        let event = ws::set_mail_status(body.delivered, corrected_by).await;
+        let status = match event.kind() {
+            EventKind::Error => StatusCode::INTERNAL_SERVER_ERROR,
+            _ => StatusCode::OK,
+        };
+        Ok(warp::reply::with_status(json(&event), status))
+    }
+
+    /// Answers with an event as json. It gets `error_status` if it's an Error
+    fn event_reply(event: Event, error_status: StatusCode) -> warp::reply::Response {
+        let status = match event.kind() {
+            EventKind::Error => error_status,
+            _ => StatusCode::OK,
+        };
+        warp::reply::with_status(json(&event), status).into_response()
+    }
+
+    fn unauthorized() -> warp::reply::Response {
+        event_reply(ws::not_authorized(), StatusCode::UNAUTHORIZED)
+    }
+
+    // The same as an EventHistory event. It's everything that's happened, so it needs the PIN
+    pub(crate) async fn events_handler(
+        pin: Option<u16>,
+    ) -> Result<warp::reply::Response, Rejection> {
+        if !pin_authorized(pin) {
+            return Ok(unauthorized());
+        }
+
+        let event = ws::handle_event_history().await;
+        Ok(event_reply(event, StatusCode::INTERNAL_SERVER_ERROR))
+    }
+
+    // The same as a MailStatus event. There isn't one until mail's been delivered or picked up
+    pub(crate) async fn mail_status_handler() -> Result<warp::reply::Response, Rejection> {
+        let event = ws::handle_mail_status().await;
+        Ok(event_reply(event, StatusCode::NOT_FOUND))
+    }
+
+    // 503 while anything is waiting to be restarted, so it works as a plain up/down check too
+    pub(crate) fn health_reply() -> warp::reply::Response {
+        let health = supervisor::health();
+        let status = if health.healthy {
+            StatusCode::OK
+        } else {
+            StatusCode::SERVICE_UNAVAILABLE
+        };
+        warp::reply::with_status(json(&health), status).into_response()
+    }
+
+    // For Prometheus to scrape
+    pub(crate) async fn metrics_handler(clients: Clients) -> Result<impl Reply, Rejection> {
+        Ok(warp::reply::with_header(
+            metrics::render(&clients).await,
+            "Content-Type",
+            "text/plain; version=0.0.4",
+        ))
+    }
+
+    // The same as a PollDevice event
+    pub(crate) async fn device_handler(device: String) -> Result<warp::reply::Response, Rejection> {
+        let device: DeviceType = match device.parse() {
+            Ok(device) => device,
+            Err(e) => return Ok(event_reply(Event::error(&e), StatusCode::NOT_FOUND)),
+        };
+
+        // The camera records a video, which blocks for a while
+        let event = tokio::task::spawn_blocking(move || {
+            ws::handle_poll_device(&mut Event::new(EventKind::PollDevice, Some(device), None))
+        })
+        .await
+        .unwrap_or_else(|e| Event::error(&format!("Device task failed: {e}")));
+        Ok(event_reply(event, StatusCode::SERVICE_UNAVAILABLE))
+    }
+
+    // Turns the light on or off, and lets the websocket clients know
+    pub(crate) async fn light_handler(
+        pin: Option<u16>,
+        body: LightRequest,
+        clients: Clients,
+    ) -> Result<warp::reply::Response, Rejection> {
+        if !pin_authorized(pin) {
+            return Ok(unauthorized());
+        }
+
+        let event = ws::set_light(body.on);
+        if event.kind() != &EventKind::Error {
+            ws::send_to_clients(&event, &clients).await;
+        }
+        Ok(event_reply(event, StatusCode::SERVICE_UNAVAILABLE))
+    }
+
+    // Takes a still, and lets the websocket clients see it too
+    pub(crate) async fn capture_handler(
+        pin: Option<u16>,
+        clients: Clients,
+    ) -> Result<warp::reply::Response, Rejection> {
+        if !pin_authorized(pin) {
+            return Ok(unauthorized());
+        }
+
+        let event = ws::capture_still().await;
+        if event.kind() != &EventKind::Error {
+            ws::send_to_clients(&event, &clients).await;
+        }
+        Ok(event_reply(event, StatusCode::SERVICE_UNAVAILABLE))
+    }
+
+    // Streams events as Server-Sent Events. The stream is registered as a client, so it gets
+    // everything the websocket clients do. Each event's id is its timestamp; reconnecting with
+    // `Last-Event-ID` first sends the saved events from that second on, so the last one
+    // might come twice
+    pub(crate) async fn event_stream_handler(
+        pin: Option<u16>,
+        query: StreamQuery,
+        last_event_id: Option<u32>,
+        clients: Clients,
+    ) -> Result<warp::reply::Response, Rejection> {
+        if !pin_authorized(pin.or(query.pin)) {
+            return Ok(unauthorized());
+        }
+
+        // Register before looking up the missed events, so nothing falls in between
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let id = Uuid::new_v4().simple().to_string();
+        clients.lock().await.insert(
+            id.clone(),
+            Client {
+                client_id: id.clone(),
+                protocol_version: PROTOCOL_VERSION,
+                authorized: true,
+                sender: Some(sender),
+            },
+        );
+        info!("{id} is streaming events");
+
+        let missed = match last_event_id {
+            Some(since) => missed_events(since).await,
+            None => Vec::new(),
+        };
+        let live = UnboundedReceiverStream::new(receiver).filter_map(|msg| async move {
+            msg.ok().and_then(|msg| msg.to_str().ok().map(String::from))
+        });
+
+        // Unregisters the stream when it's dropped, ie. the client went away
+        let guard = StreamGuard { id, clients };
+        let stream = futures::stream::iter(missed).chain(live).map(move |json| {
+            let _ = &guard;
+            Ok::<_, Infallible>(sse_event(json))
+        });
+
+        let stream = warp::sse::keep_alive()
+            .interval(KEEPALIVE)
+            .text("keepalive")
+            .stream(stream);
+        Ok(warp::sse::reply(stream).into_response())
+    }
+
+    /// Saved events that went out to clients since a timestamp, as json
+    async fn missed_events(since: u32) -> Vec<String> {
+        let events = match Store::connect().await {
+            Ok(db) => db.get_events_since(since).await,
+            Err(e) => Err(e),
+        };
+        match events {
+            Ok(events) => events
+                .into_iter()
+                .filter(|e| e.kind().is_outgoing())
+                .filter_map(|e| serde_json::to_string(&e).ok())
+                .collect(),
+            Err(e) => {
+                error!("Couldn't look up the events a stream missed: {e}");
+                Vec::new()
+            }
+        }
+    }
+
+    /// An event as json, with its timestamp as the id
+    fn sse_event(json: String) -> warp::sse::Event {
+        let timestamp = serde_json::from_str::<serde_json::Value>(&json)
+            .ok()
+            .and_then(|event| event["timestamp"].as_u64());
+        let event = warp::sse::Event::default();
+        match timestamp {
+            Some(timestamp) => event.id(timestamp.to_string()).data(json),
+            None => event.data(json),
+        }
+    }
+
+    struct StreamGuard {
+        id: String,
+        clients: Clients,
+    }
+
+    impl Drop for StreamGuard {
+        fn drop(&mut self) {
+            let id = self.id.clone();
+            let clients = self.clients.clone();
+            tokio::spawn(async move {
+                clients.lock().await.remove(&id);
+                info!("{id} stopped streaming events");
+            });
+        }
+    }
+
+    /// Turns anything that didn't match a route into an `Error` event, so HTTP clients
+    /// always get json back
+    pub async fn handle_rejection(err: Rejection) -> Result<impl Reply, Infallible> {
+        let (status, msg) = if err.is_not_found() {
+            (StatusCode::NOT_FOUND, "Not found".to_string())
+        } else if let Some(e) = err.find::<warp::filters::body::BodyDeserializeError>() {
+            (StatusCode::BAD_REQUEST, format!("Bad request body: {e}"))
+        } else if let Some(e) = err.find::<warp::reject::InvalidHeader>() {
+            (StatusCode::BAD_REQUEST, format!("{e}"))
+        } else if let Some(e) = err.find::<warp::reject::InvalidQuery>() {
+            (StatusCode::BAD_REQUEST, format!("{e}"))
+        } else if err.find::<warp::reject::MethodNotAllowed>().is_some() {
+            (
+                StatusCode::METHOD_NOT_ALLOWED,
+                "Method not allowed".to_string(),
+            )
+        } else if let Some(e) = err.find::<warp::reject::UnsupportedMediaType>() {
+            (StatusCode::UNSUPPORTED_MEDIA_TYPE, format!("{e}"))
+        } else {
+            // ie. connecting to the websocket without upgrading
+            (StatusCode::BAD_REQUEST, format!("Bad request: {err:?}"))
+        };
+
+        Ok(warp::reply::with_status(json(&Event::error(&msg)), status))
     }
 
     // Registers a client, adding them to the client list
-    pub async fn register_client(uuid: String, clients: Clients) {
+    pub async fn register_client(uuid: String, protocol_version: u16, clients: Clients) {
         clients.lock().await.insert(
             uuid.clone(),
             Client {
                 client_id: uuid,
+                protocol_version,
+                authorized: false,
                 sender: None,
             },
         );
@@ -344,13 +972,19 @@ pub mod http {
             };
 
             // Call the handler and get the response
-            let response = handle_message(msg).await.to_msg();
+            let mut response = handle_message(msg, &mut client).await;
+            // Version 1 clients don't know about ids
+            if client.protocol_version < 2 {
+                response = response.with_id(None);
+            }
+            let response = response.to_msg();
 
             // If the client is still connected, send the response
-            let c = clients.lock().await;
-            match c.get(&id) {
-                Some(client) => {
-                    if let Some(sender) = &client.sender {
+            let mut c = clients.lock().await;
+            match c.get_mut(&id) {
+                Some(registered) => {
+                    registered.authorized = client.authorized;
+                    if let Some(sender) = &registered.sender {
                         sender.send(Ok(response)).unwrap();
                     }
                 }
@@ -389,7 +1023,9 @@ pub mod http {
 
 #[cfg(test)]
 mod tests {
+    use crate::defaults;
     use crate::drivers::device::DeviceType;
+    use crate::drivers::light::light;
 
     use super::*;
 
@@ -398,6 +1034,16 @@ mod tests {
         return Arc::new(Mutex::new(HashMap::new()));
     }
 
+    // Helper function, a client that isn't connected to anything
+    fn client(authorized: bool) -> Client {
+        Client {
+            client_id: "test".to_string(),
+            protocol_version: PROTOCOL_VERSION,
+            authorized,
+            sender: None,
+        }
+    }
+
     // Helper function, gets the ws url
     // Not actually using this right now
     #[allow(unused)]
@@ -424,6 +1070,27 @@ mod tests {
         let body = std::str::from_utf8(response.body()).unwrap();
         // Make sure the websocket url is in it
         assert!(body.contains("ws://") || body.contains("wss://"));
+        // Clients that don't ask for a version get version 1
+        assert!(body.contains(r#""protocol_version":1"#));
+    }
+
+    #[tokio::test]
+    async fn test_register_route_negotiates_protocol() {
+        let filter = http::register_route(&clients());
+
+        let response = warp::test::request()
+            .path(&format!("/register?protocol_version={}", PROTOCOL_VERSION + 1))
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 200);
+        let body: http::RegisterResponse = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body.protocol_version, PROTOCOL_VERSION);
+
+        let response = warp::test::request()
+            .path("/register?protocol_version=0")
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 400);
     }
 
     // I tried to write a test for the websocket but goddamn it's complicated
@@ -483,6 +1150,7 @@ mod tests {
 
     #[tokio::test]
     async fn test_handle_event_history_when_db_empty() {
+        let _db = crate::store::TEST_DB.lock().await;
         let store = Store::connect().await.unwrap();
 
         store.nuke().await.unwrap();
@@ -504,6 +1172,7 @@ mod tests {
 
     #[tokio::test]
     async fn test_event_history_when_db_not_empty() {
+        let _db = crate::store::TEST_DB.lock().await;
         let store = Store::connect().await.unwrap();
         store.nuke().await.unwrap();
         // Write some event
@@ -526,6 +1195,7 @@ mod tests {
 
     #[tokio::test]
     async fn test_handle_mail_status_when_db_empty() {
+        let _db = crate::store::TEST_DB.lock().await;
         let store = Store::connect().await.unwrap();
         store.nuke().await.unwrap();
 
@@ -535,6 +1205,7 @@ mod tests {
 
     #[tokio::test]
     async fn test_handle_mail_status_when_db_not_empty() {
+        let _db = crate::store::TEST_DB.lock().await;
         let store = Store::connect().await.unwrap();
         store.nuke().await.unwrap();
 
@@ -547,11 +1218,548 @@ mod tests {
         assert_eq!(outgoing.kind(), &EventKind::MailDelivered);
     }
 
+    #[tokio::test]
+    async fn test_handle_mail_summary() {
+        let _db = crate::store::TEST_DB.lock().await;
+        let store = Store::connect().await.unwrap();
+        store.nuke().await.unwrap();
+
+        store
+            .write_event(Event::new(EventKind::MailDelivered, None, None))
+            .await
+            .unwrap();
+
+        let outgoing = ws::handle_mail_summary().await;
+        assert_eq!(outgoing.kind(), &EventKind::MailSummary);
+        assert!(matches!(
+            outgoing.data(),
+            Some(Bundle::MailSummary {
+                deliveries_since_pickup: 1,
+                days_since_pickup: None,
+                ..
+            })
+        ));
+
+        store.nuke().await.unwrap();
+    }
+
     #[test]
     fn test_handle_wrong_way() {
         let outgoing = ws::wrong_way();
         assert_eq!(outgoing.kind(), &EventKind::Error);
     }
 
-    // TODO: Write some test for handle_message() that create a ws::Message (from warp) as input
+    #[tokio::test]
+    async fn test_handle_message_echoes_id() {
+        let _db = crate::store::TEST_DB.lock().await;
+        let mut client = client(false);
+        let msg = Message::text(r#"{"id":"req-42","kind":"HealthCheck"}"#);
+        let outgoing = ws::handle_message(msg, &mut client).await;
+        assert_eq!(outgoing.kind(), &EventKind::HealthCheck);
+        assert_eq!(outgoing.id(), Some("req-42"));
+
+        // Errors get the id too
+        let msg = Message::text(r#"{"id":"req-43","kind":"MailDelivered"}"#);
+        let outgoing = ws::handle_message(msg, &mut client).await;
+        assert_eq!(outgoing.kind(), &EventKind::Error);
+        assert_eq!(outgoing.id(), Some("req-43"));
+
+        let msg = Message::text(r#"{"kind":"HealthCheck"}"#);
+        assert_eq!(ws::handle_message(msg, &mut client).await.id(), None);
+    }
+
+    #[tokio::test]
+    async fn test_pin_check_authorizes_client() {
+        let _db = crate::store::TEST_DB.lock().await;
+        let mut client = client(false);
+
+        let msg = Message::text(r#"{"kind":"PinCheck","data":{"PinCheck":{"pin":8888}}}"#);
+        ws::handle_message(msg, &mut client).await;
+        assert!(!client.authorized);
+
+        let msg = Message::text(r#"{"kind":"PinCheck","data":{"PinCheck":{"pin":6245}}}"#);
+        ws::handle_message(msg, &mut client).await;
+        assert!(client.authorized);
+    }
+
+    #[tokio::test]
+    async fn test_pin_not_in_history() {
+        let _db = crate::store::TEST_DB.lock().await;
+        let store = Store::connect().await.unwrap();
+        store.nuke().await.unwrap();
+        let mut client = client(false);
+
+        let pin = defaults::pin();
+        for msg in [
+            format!(r#"{{"kind":"PinCheck","data":{{"PinCheck":{{"pin":{pin}}}}}}}"#),
+            format!(
+                r#"{{"kind":"SetArmState","data":{{"SetArmState":{{"armed":true,"pin":{pin},"name":"Luke"}}}}}}"#
+            ),
+        ] {
+            ws::handle_message(Message::text(msg), &mut client).await;
+        }
+        assert!(store.is_armed().await.unwrap());
+
+        let history = ws::handle_event_history().await;
+        let events = match history.data() {
+            Some(Bundle::EventHistory { events }) => events,
+            other => panic!("{:?}", other),
+        };
+        // They're recorded, just without the PIN
+        let kinds: Vec<&EventKind> = events.iter().map(|e| e.kind()).collect();
+        assert!(kinds.contains(&&EventKind::PinCheck));
+        assert!(kinds.contains(&&EventKind::SetArmState));
+        let json = serde_json::to_string(&history).unwrap();
+        assert!(!json.contains(r#""pin""#), "{:?}", json);
+
+        store.nuke().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_handle_set_mail_status() {
+        let _db = crate::store::TEST_DB.lock().await;
+        let store = Store::connect().await.unwrap();
+        store.nuke().await.unwrap();
+
+        let incoming = Event::new(
+            EventKind::SetMailStatus,
+            None,
+            Some(Bundle::SetMailStatus {
+                delivered: true,
+                name: Some("Luke".to_string()),
+            }),
+        );
+
+        // Has to be logged in
+        let outgoing = ws::handle_set_mail_status(&incoming, &client(false)).await;
+        assert_eq!(outgoing.kind(), &EventKind::Error);
+        assert!(store.get_mail_status().await.is_err());
+
+        let outgoing = ws::handle_set_mail_status(&incoming, &client(true)).await;
+        assert_eq!(outgoing.kind(), &EventKind::MailDelivered);
+
+        let status = store.get_mail_status().await.unwrap();
+        assert_eq!(status.kind(), &EventKind::MailDelivered);
+        assert_eq!(
+            status.data(),
+            Some(&Bundle::ManualMailStatus {
+                corrected_by: "Luke".to_string()
+            })
+        );
+
+        store.nuke().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_handle_rules() {
+        use crate::rules::{Action, Condition, Rule};
+
+        let _db = crate::store::TEST_DB.lock().await;
+        let store = Store::connect().await.unwrap();
+        store.nuke().await.unwrap();
+
+        let rule = Rule {
+            id: None,
+            name: "Quiet hours".to_string(),
+            enabled: true,
+            conditions: vec![Condition::TimeOfDay {
+                window: crate::schedule::TimeWindow::parse("22:00-07:00").unwrap(),
+            }],
+            actions: vec![Action::Suppress],
+        };
+        let set_rule = Event::new(
+            EventKind::SetRule,
+            None,
+            Some(Bundle::SetRule { rule: rule.clone() }),
+        );
+
+        // Has to be logged in
+        let outgoing = ws::handle_set_rule(&set_rule, &client(false)).await;
+        assert_eq!(outgoing.kind(), &EventKind::Error);
+        assert!(store.get_rules().await.unwrap().is_empty());
+
+        let outgoing = ws::handle_set_rule(&set_rule, &client(true)).await;
+        assert_eq!(outgoing.kind(), &EventKind::Rules);
+        let id = match outgoing.data() {
+            Some(Bundle::Rules { rules }) => {
+                assert_eq!(rules.len(), 1);
+                assert_eq!(rules[0].name, rule.name);
+                rules[0].id.unwrap()
+            }
+            other => panic!("{:?}", other),
+        };
+
+        let listed = ws::handle_rules().await;
+        assert_eq!(listed.data(), outgoing.data());
+
+        let delete = Event::new(EventKind::DeleteRule, None, Some(Bundle::DeleteRule { id }));
+        assert_eq!(
+            ws::handle_delete_rule(&delete, &client(false)).await.kind(),
+            &EventKind::Error
+        );
+        let outgoing = ws::handle_delete_rule(&delete, &client(true)).await;
+        assert_eq!(outgoing.data(), Some(&Bundle::Rules { rules: vec![] }));
+
+        // It's gone now
+        let outgoing = ws::handle_delete_rule(&delete, &client(true)).await;
+        assert_eq!(outgoing.kind(), &EventKind::Error);
+
+        store.nuke().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_handle_set_arm_state() {
+        let _db = crate::store::TEST_DB.lock().await;
+        let store = Store::connect().await.unwrap();
+        store.nuke().await.unwrap();
+
+        let set = |armed, pin| {
+            Event::new(
+                EventKind::SetArmState,
+                None,
+                Some(Bundle::SetArmState {
+                    armed,
+                    pin,
+                    name: Some("Luke".to_string()),
+                }),
+            )
+        };
+
+        // Being logged in isn't enough, it needs the right PIN
+        let outgoing =
+            ws::handle_set_arm_state(&set(true, defaults::pin() + 1), &client(true)).await;
+        assert_eq!(outgoing.kind(), &EventKind::Error);
+        assert!(!store.is_armed().await.unwrap());
+
+        let outgoing = ws::handle_set_arm_state(&set(true, defaults::pin()), &client(false)).await;
+        assert_eq!(outgoing.kind(), &EventKind::ArmState);
+        assert!(matches!(
+            outgoing.data(),
+            Some(Bundle::ArmState { armed: true, changed_by: Some(name), .. }) if name == "Luke"
+        ));
+        assert!(store.is_armed().await.unwrap());
+
+        let state = ws::handle_arm_state().await;
+        assert_eq!(state.data(), outgoing.data());
+
+        ws::handle_set_arm_state(&set(false, defaults::pin()), &client(false)).await;
+        assert!(!store.is_armed().await.unwrap());
+
+        store.nuke().await.unwrap();
+    }
+
+    // Helper function, the REST API the way `run` serves it
+    fn api() -> impl Filter<Extract = (impl Reply,), Error = Infallible> + Clone {
+        http::mail_route()
+            .or(http::api_routes(&clients()))
+            .recover(http::handle_rejection)
+    }
+
+    // Helper function, gets the event out of a response
+    fn response_event(response: &warp::http::Response<warp::hyper::body::Bytes>) -> Event {
+        serde_json::from_slice(response.body()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_api_events() {
+        let _db = crate::store::TEST_DB.lock().await;
+        let store = Store::connect().await.unwrap();
+        store.nuke().await.unwrap();
+        store
+            .write_event(Event::new(EventKind::MailDelivered, None, None))
+            .await
+            .unwrap();
+
+        // Not without the PIN
+        let response = warp::test::request().path("/events").reply(&api()).await;
+        assert_eq!(response.status(), 401);
+        assert_eq!(response_event(&response).kind(), &EventKind::Error);
+
+        let response = warp::test::request()
+            .path("/events")
+            .header(http::PIN_HEADER, "6245")
+            .reply(&api())
+            .await;
+        assert_eq!(response.status(), 200);
+        match response_event(&response).data() {
+            Some(Bundle::EventHistory { events }) => assert_eq!(events.len(), 1),
+            other => panic!("{:?}", other),
+        }
+
+        store.nuke().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_api_mail_status() {
+        let _db = crate::store::TEST_DB.lock().await;
+        let store = Store::connect().await.unwrap();
+        store.nuke().await.unwrap();
+
+        // Nothing's happened yet
+        let response = warp::test::request()
+            .path("/mail/status")
+            .reply(&api())
+            .await;
+        assert_eq!(response.status(), 404);
+        assert_eq!(response_event(&response).kind(), &EventKind::Error);
+
+        store
+            .write_event(Event::new(EventKind::MailDelivered, None, None))
+            .await
+            .unwrap();
+        let response = warp::test::request()
+            .path("/mail/status")
+            .reply(&api())
+            .await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(response_event(&response).kind(), &EventKind::MailDelivered);
+
+        store.nuke().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_api_devices() {
+        let response = warp::test::request()
+            .path("/devices/contact_sensor")
+            .reply(&api())
+            .await;
+        assert_eq!(response.status(), 200);
+        let event = response_event(&response);
+        assert_eq!(event.kind(), &EventKind::PollDeviceResult);
+        assert!(matches!(event.data(), Some(Bundle::ContactSensor { .. })));
+
+        let response = warp::test::request()
+            .path("/devices/toaster")
+            .reply(&api())
+            .await;
+        assert_eq!(response.status(), 404);
+        assert_eq!(response_event(&response).kind(), &EventKind::Error);
+    }
+
+    #[tokio::test]
+    async fn test_api_light() {
+        let _light = light::TEST_LIGHT.lock().await;
+        let request = || {
+            warp::test::request()
+                .method("POST")
+                .path("/devices/light")
+                .json(&serde_json::json!({ "on": false }))
+        };
+
+        let response = request().reply(&api()).await;
+        assert_eq!(response.status(), 401);
+        assert_eq!(response_event(&response).kind(), &EventKind::Error);
+
+        let response = request()
+            .header(http::PIN_HEADER, "6245")
+            .reply(&api())
+            .await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(
+            response_event(&response).data(),
+            Some(&Bundle::Light { on: false })
+        );
+
+        // Not a light request
+        let response = warp::test::request()
+            .method("POST")
+            .path("/devices/light")
+            .header(http::PIN_HEADER, "6245")
+            .json(&serde_json::json!({ "brightness": 11 }))
+            .reply(&api())
+            .await;
+        assert_eq!(response.status(), 400);
+        assert_eq!(response_event(&response).kind(), &EventKind::Error);
+    }
+
+    #[tokio::test]
+    async fn test_api_capture() {
+        let _img = crate::drivers::camera::TEST_IMG_DIR.lock().await;
+        std::fs::create_dir_all(defaults::img_dir()).unwrap();
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/capture")
+            .reply(&api())
+            .await;
+        assert_eq!(response.status(), 401);
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/capture")
+            .header(http::PIN_HEADER, "6245")
+            .reply(&api())
+            .await;
+        assert_eq!(response.status(), 200);
+        match response_event(&response).data() {
+            Some(Bundle::Camera {
+                file_name,
+                duration: None,
+            }) => {
+                let path = std::path::Path::new(&defaults::img_dir()).join(file_name);
+                assert!(path.exists());
+                std::fs::remove_file(path).unwrap();
+            }
+            other => panic!("{:?}", other),
+        }
+    }
+
+    // Helper function, reads from a response body until some text shows up
+    async fn read_until(body: &mut warp::hyper::Body, text: &str) -> String {
+        use warp::hyper::body::HttpBody;
+
+        let mut read = String::new();
+        while !read.contains(text) {
+            let chunk = tokio::time::timeout(std::time::Duration::from_secs(5), body.data())
+                .await
+                .expect("timed out reading the stream")
+                .expect("the stream ended")
+                .unwrap();
+            read.push_str(std::str::from_utf8(&chunk).unwrap());
+        }
+        read
+    }
+
+    #[tokio::test]
+    async fn test_api_event_stream() {
+        let _db = crate::store::TEST_DB.lock().await;
+        let store = Store::connect().await.unwrap();
+        store.nuke().await.unwrap();
+
+        let at = |kind, timestamp| {
+            let mut event = Event::new(kind, None, None);
+            event.set_timestamp(timestamp);
+            event
+        };
+        store
+            .write_event(at(EventKind::MailPickedUp, 50))
+            .await
+            .unwrap();
+        store
+            .write_event(at(EventKind::DoorOpened, 100))
+            .await
+            .unwrap();
+        // Incoming events are saved too, but they never went out to clients
+        store
+            .write_event(at(EventKind::HealthCheck, 100))
+            .await
+            .unwrap();
+
+        let clients = clients();
+        let filter = http::api_routes(&clients).recover(http::handle_rejection);
+
+        let response = warp::test::request()
+            .path("/events/stream")
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 401);
+        assert!(clients.lock().await.is_empty());
+
+        let response = http::event_stream_handler(
+            None,
+            serde_json::from_str(r#"{"pin":6245}"#).unwrap(),
+            Some(100),
+            clients.clone(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(clients.lock().await.len(), 1);
+        let mut body = response.into_body();
+
+        let missed = read_until(&mut body, "DoorOpened").await;
+        assert!(missed.contains("id:100"));
+        assert!(!missed.contains("MailPickedUp"));
+        assert!(!missed.contains("HealthCheck"));
+
+        // Then everything the websocket clients get
+        ws::send_to_clients(&Event::new(EventKind::MailDelivered, None, None), &clients).await;
+        read_until(&mut body, "MailDelivered").await;
+
+        // Hanging up unregisters it
+        drop(body);
+        for _ in 0..50 {
+            if clients.lock().await.is_empty() {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        assert!(clients.lock().await.is_empty());
+
+        store.nuke().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_api_schema() {
+        let response = warp::test::request().path("/schema").reply(&api()).await;
+        assert_eq!(response.status(), 200);
+        let schema: crate::model::schema::ProtocolSchema =
+            serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(schema.protocol_version, PROTOCOL_VERSION);
+        assert!(schema.event.definitions.contains_key("Bundle"));
+    }
+
+    #[tokio::test]
+    async fn test_api_health() {
+        let response = warp::test::request().path("/health").reply(&api()).await;
+        let health: crate::supervisor::Health = serde_json::from_slice(response.body()).unwrap();
+        // Other tests might have something restarting right now
+        let expected = if health.healthy { 200 } else { 503 };
+        assert_eq!(response.status(), expected);
+    }
+
+    #[tokio::test]
+    async fn test_api_metrics() {
+        let response = warp::test::request().path("/metrics").reply(&api()).await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(
+            response.headers()["content-type"],
+            "text/plain; version=0.0.4"
+        );
+        let text = std::str::from_utf8(response.body()).unwrap();
+        assert!(text.contains("modkit_websocket_clients"), "{:?}", text);
+    }
+
+    #[tokio::test]
+    async fn test_api_not_found() {
+        let response = warp::test::request().path("/nowhere").reply(&api()).await;
+        assert_eq!(response.status(), 404);
+        assert_eq!(response_event(&response).kind(), &EventKind::Error);
+    }
+
+    #[tokio::test]
+    async fn test_mail_route() {
+        let _db = crate::store::TEST_DB.lock().await;
+        let store = Store::connect().await.unwrap();
+        store.nuke().await.unwrap();
+
+        let filter = http::mail_route();
+
+        // No PIN
+        let response = warp::test::request()
+            .method("POST")
+            .path("/mail/status")
+            .json(&serde_json::json!({ "delivered": false }))
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 401);
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/mail/status")
+            .header(http::PIN_HEADER, "6245")
+            .json(&serde_json::json!({ "delivered": false }))
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 200);
+
+        let status = store.get_mail_status().await.unwrap();
+        assert_eq!(status.kind(), &EventKind::MailPickedUp);
+        assert!(matches!(
+            status.data(),
+            Some(Bundle::ManualMailStatus { .. })
+        ));
+
+        store.nuke().await.unwrap();
+    }
 }
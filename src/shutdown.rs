@@ -0,0 +1,188 @@
+//! Stopping the daemon cleanly on SIGTERM or SIGINT.
+//!
+//! Every long running loop (the server, the watchdog, the notifiers and MQTT) checks
+//! [`is_stopping`] or waits on [`stopped`], so once [`stop`] is called they all wind down on
+//! their own. A video that's being recorded is cut short and kept.
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use log::*;
+use tokio::signal::unix::{signal, Signal, SignalKind};
+use tokio::sync::Notify;
+
+use crate::drivers::light::light;
+use crate::model::{Event, EventKind};
+use crate::server::Clients;
+use crate::store::Store;
+
+static STOPPING: AtomicBool = AtomicBool::new(false);
+static STOPPED: Notify = Notify::const_new();
+
+/// Held by tests that stop everything, or that would notice
+#[cfg(test)]
+pub(crate) static TEST_STOP: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+/// Whether we've been told to shut down
+pub fn is_stopping() -> bool {
+    STOPPING.load(Ordering::SeqCst)
+}
+
+/// Tells everything to shut down
+pub fn stop() {
+    STOPPING.store(true, Ordering::SeqCst);
+    STOPPED.notify_waiters();
+}
+
+/// Waits until we've been told to shut down. Returns right away if we already have
+pub async fn stopped() {
+    loop {
+        // Made before checking, so a stop() in between isn't missed
+        let notified = STOPPED.notified();
+        if is_stopping() {
+            return;
+        }
+        notified.await;
+    }
+}
+
+/// Waits for either signal, and says which one it was
+async fn next_signal(term: &mut Signal, int: &mut Signal) -> &'static str {
+    tokio::select! {
+        _ = term.recv() => "SIGTERM",
+        _ = int.recv() => "SIGINT",
+    }
+}
+
+/// Waits for SIGTERM or SIGINT, then tells everything to stop and lets the clients know.
+/// A second signal exits right away, in case something is stuck.
+///
+/// Meant to be spawned as its own task, so it still runs while the watchdog is blocked on the
+/// camera
+pub async fn on_signal(clients: Clients) {
+    let (mut term, mut int) = match (
+        signal(SignalKind::terminate()),
+        signal(SignalKind::interrupt()),
+    ) {
+        (Ok(term), Ok(int)) => (term, int),
+        (Err(e), _) | (_, Err(e)) => {
+            error!("Couldn't listen for signals: {e}");
+            return;
+        }
+    };
+
+    let name = next_signal(&mut term, &mut int).await;
+    info!("Got {name}, shutting down");
+    stop();
+    announce(&clients).await;
+
+    let name = next_signal(&mut term, &mut int).await;
+    warn!("Got {name} again, exiting without cleaning up");
+    std::process::exit(1);
+}
+
+/// Sends a ServerShutdown to every client, then hangs up on them so the server can stop.
+/// Clients older than protocol version 3 don't know about ServerShutdown, so they just get
+/// hung up on
+pub async fn announce(clients: &Clients) {
+    let event = Event::new(EventKind::ServerShutdown, None, None);
+    // Dropping the senders closes the websockets and ends the event streams
+    for (id, client) in clients.lock().await.iter_mut() {
+        if let Some(sender) = client.sender.take() {
+            if client.protocol_version >= 3 && sender.send(Ok(event.clone().to_msg())).is_err() {
+                warn!("Couldn't tell client {id} we're shutting down");
+            }
+        }
+    }
+}
+
+/// The last step, once everything else has stopped. Makes sure the light is off, records the
+/// shutdown and closes the database so everything is written out
+pub async fn finish() {
+    if let Err(e) = light::set(false) {
+        error!("Couldn't turn the light off: {e}");
+    }
+
+    match Store::connect().await {
+        Ok(store) => {
+            if let Err(e) = store
+                .write_event(Event::new(EventKind::ServerShutdown, None, None))
+                .await
+            {
+                error!("Couldn't record the shutdown: {e}");
+            }
+            store.close().await;
+        }
+        Err(e) => error!("Couldn't connect to the database to record the shutdown: {e}"),
+    }
+    info!("Shut down");
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use tokio::sync::{mpsc, Mutex};
+
+    use super::*;
+    use crate::server::Client;
+
+    #[tokio::test]
+    async fn test_stop() {
+        let _stop = TEST_STOP.lock().await;
+        let waiting = tokio::spawn(stopped());
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!waiting.is_finished());
+
+        stop();
+        assert!(is_stopping());
+        tokio::time::timeout(Duration::from_secs(1), waiting)
+            .await
+            .expect("stopped() should return after stop()")
+            .unwrap();
+        // And it doesn't wait at all once we're stopping
+        tokio::time::timeout(Duration::from_millis(10), stopped())
+            .await
+            .unwrap();
+
+        // Other tests record videos, which would be cut short
+        STOPPING.store(false, Ordering::SeqCst);
+    }
+
+    #[tokio::test]
+    async fn test_announce() {
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        let clients: Clients = Arc::new(Mutex::new(HashMap::new()));
+        clients.lock().await.insert(
+            "test".to_string(),
+            Client {
+                client_id: "test".to_string(),
+                protocol_version: 3,
+                authorized: false,
+                sender: Some(sender),
+            },
+        );
+        let (old_sender, mut old_receiver) = mpsc::unbounded_channel();
+        clients.lock().await.insert(
+            "old".to_string(),
+            Client {
+                client_id: "old".to_string(),
+                protocol_version: 2,
+                authorized: false,
+                sender: Some(old_sender),
+            },
+        );
+
+        announce(&clients).await;
+
+        let msg = receiver.recv().await.unwrap().unwrap();
+        let event: Event = serde_json::from_str(msg.to_str().unwrap()).unwrap();
+        assert_eq!(event.kind(), &EventKind::ServerShutdown);
+        // Hung up afterwards
+        assert!(receiver.recv().await.is_none());
+        assert!(clients.lock().await["test"].sender.is_none());
+
+        // Older clients are just hung up on
+        assert!(old_receiver.recv().await.is_none());
+    }
+}
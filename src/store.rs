@@ -1,9 +1,13 @@
 use std::env;
+use std::time::Instant;
+use chrono::{Local, TimeZone, Timelike};
 use log::*;
 
 use sqlx::SqlitePool;
 
-use crate::model::{Event, EventKind};
+use crate::metrics;
+use crate::model::{Bundle, Event, EventKind};
+use crate::rules::Rule;
 
 pub const DB_LOCATION: &'static str = "sqlite:modkit.db";
 
@@ -21,6 +25,10 @@ pub enum StoreError {
         "A mail status event (MailDelivered/MailPickedUp) could not be found in the database: {0}"
     )]
     MailStatusNotFound(sqlx::Error),
+    #[error("Couldn't run database migrations: {0}")]
+    MigrateError(#[from] sqlx::migrate::MigrateError),
+    #[error("Could not encode value for the database: {0}")]
+    EncodeError(#[from] serde_json::Error),
 }
 
 impl StoreError {
@@ -29,8 +37,44 @@ impl StoreError {
     }
 }
 
+/// The tests all share one database file, so the ones that use it take turns
+#[cfg(test)]
+pub(crate) static TEST_DB: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
 pub struct Store(SqlitePool);
 
+/// A notification waiting in the outbox to be sent
+#[derive(Debug, Clone, PartialEq, sqlx::FromRow)]
+pub struct OutboxEntry {
+    #[sqlx(rename = "ID")]
+    pub id: i64,
+    /// Which notifier sends this, ie. `webhook`
+    pub channel: String,
+    /// Where it's going, ie. a url
+    pub target: String,
+    /// The serialized notification
+    pub payload: String,
+    /// How many times we've tried to send it already
+    pub attempts: u32,
+    /// Timestamp of when to try sending it next
+    pub next_attempt: u32,
+    pub created: u32,
+}
+
+/// A picture or video in the media catalog
+#[derive(Debug, Clone, PartialEq, sqlx::FromRow)]
+pub struct MediaEntry {
+    #[sqlx(rename = "ID")]
+    pub id: i64,
+    /// In `MODKIT_IMG_DIR`
+    pub file_name: String,
+    /// `still` or `video`
+    pub kind: String,
+    /// What it was captured for, ie. `ScheduledSnapshot`
+    pub source: String,
+    pub captured_at: u32,
+}
+
 impl Store {
     /// Connects to a Sqlite database.
     pub async fn connect() -> Result<Self, StoreError> {
@@ -40,6 +84,18 @@ impl Store {
         Ok(Store(pool))
     }
 
+    /// Brings the database schema up to date with the files in `migrations/`
+    pub async fn migrate(&self) -> Result<(), StoreError> {
+        sqlx::migrate!().run(&self.0).await?;
+        Ok(())
+    }
+
+    /// Waits for any queries in flight, then closes every connection so it's all written out.
+    /// Used when shutting down
+    pub async fn close(&self) {
+        self.0.close().await;
+    }
+
     /// Borrows the connection pool
     #[allow(unused)]
     pub fn borrow_pool(&self) -> &SqlitePool {
@@ -55,12 +111,35 @@ impl Store {
         Ok(events)
     }
 
+    /// Events from a timestamp on, in the order they were saved
+    pub async fn get_events_since(&self, timestamp: u32) -> Result<Vec<Event>, StoreError> {
+        let mut connection = self.0.acquire().await?;
+        let events =
+            sqlx::query_as::<_, Event>("SELECT * FROM Events WHERE timestamp >= ? ORDER BY ID;")
+                .bind(timestamp)
+                .fetch_all(&mut connection)
+                .await?;
+        Ok(events)
+    }
+
     #[allow(unused)]
     pub async fn nuke(&self) -> Result<(), StoreError> {
         let mut connection = self.0.acquire().await?;
         sqlx::query("DELETE FROM Events;")
             .execute(&mut connection)
             .await?;
+        sqlx::query("DELETE FROM Outbox;")
+            .execute(&mut connection)
+            .await?;
+        sqlx::query("DELETE FROM Rules;")
+            .execute(&mut connection)
+            .await?;
+        sqlx::query("DELETE FROM ArmState;")
+            .execute(&mut connection)
+            .await?;
+        sqlx::query("DELETE FROM Media;")
+            .execute(&mut connection)
+            .await?;
         Ok(())
     }
 
@@ -83,6 +162,242 @@ impl Store {
         latest
     }
 
+    /// Whether the mailbox is armed, as an ArmState bundle. It's disarmed until someone arms it
+    pub async fn get_arm_state(&self) -> Result<Bundle, StoreError> {
+        let mut connection = self.0.acquire().await?;
+        let row: Option<(bool, Option<String>, Option<u32>)> =
+            sqlx::query_as("SELECT armed, changed_by, changed_at FROM ArmState WHERE ID = 1;")
+                .fetch_optional(&mut connection)
+                .await?;
+
+        let (armed, changed_by, changed_at) = row.unwrap_or((false, None, None));
+        Ok(Bundle::ArmState {
+            armed,
+            changed_by,
+            changed_at,
+        })
+    }
+
+    pub async fn is_armed(&self) -> Result<bool, StoreError> {
+        Ok(matches!(
+            self.get_arm_state().await?,
+            Bundle::ArmState { armed: true, .. }
+        ))
+    }
+
+    /// Arms or disarms the mailbox. Returns the new ArmState bundle
+    pub async fn set_arm_state(
+        &self,
+        armed: bool,
+        changed_by: &str,
+        now: u32,
+    ) -> Result<Bundle, StoreError> {
+        let mut connection = self.0.acquire().await?;
+        sqlx::query(
+            "INSERT OR REPLACE INTO ArmState (ID, armed, changed_by, changed_at) VALUES (1, ?, ?, ?);",
+        )
+        .bind(armed)
+        .bind(changed_by)
+        .bind(now)
+        .execute(&mut connection)
+        .await?;
+
+        Ok(Bundle::ArmState {
+            armed,
+            changed_by: Some(changed_by.to_string()),
+            changed_at: Some(now),
+        })
+    }
+
+    /// Works out a MailSummary bundle from all the MailDelivered/MailPickedUp events
+    pub async fn get_mail_summary(&self) -> Result<Bundle, StoreError> {
+        let mut connection = self.0.acquire().await?;
+
+        let events = sqlx::query_as::<_, Event>(
+            r#"
+            SELECT * FROM Events
+            WHERE kind = 'MailDelivered'
+            OR kind = 'MailPickedUp'
+            ORDER BY ID;"#,
+        )
+        .fetch_all(&mut connection)
+        .await?;
+
+        Ok(summarize_mail(&events, Local::now().timestamp() as u32))
+    }
+
+    /// Adds a notification to the outbox, to be sent as soon as possible
+    pub async fn queue_notification(
+        &self,
+        channel: &str,
+        target: &str,
+        payload: &str,
+        now: u32,
+    ) -> Result<(), StoreError> {
+        self.queue_notification_at(channel, target, payload, now, now)
+            .await
+    }
+
+    /// Adds a notification to the outbox that won't be sent before `send_at`
+    pub async fn queue_notification_at(
+        &self,
+        channel: &str,
+        target: &str,
+        payload: &str,
+        now: u32,
+        send_at: u32,
+    ) -> Result<(), StoreError> {
+        let mut connection = self.0.acquire().await?;
+        sqlx::query(
+            "INSERT INTO Outbox (channel, target, payload, attempts, next_attempt, created) VALUES (?, ?, ?, 0, ?, ?);",
+        )
+        .bind(channel)
+        .bind(target)
+        .bind(payload)
+        .bind(send_at)
+        .bind(now)
+        .execute(&mut connection)
+        .await?;
+        Ok(())
+    }
+
+    /// Notifications for a channel that are ready to be (re)sent, oldest first
+    pub async fn due_notifications(
+        &self,
+        channel: &str,
+        now: u32,
+    ) -> Result<Vec<OutboxEntry>, StoreError> {
+        let mut connection = self.0.acquire().await?;
+        let entries = sqlx::query_as::<_, OutboxEntry>(
+            "SELECT * FROM Outbox WHERE channel = ? AND next_attempt <= ? ORDER BY ID;",
+        )
+        .bind(channel)
+        .bind(now)
+        .fetch_all(&mut connection)
+        .await?;
+        Ok(entries)
+    }
+
+    /// Takes a notification out of the outbox, once it's sent or we've given up on it
+    pub async fn remove_notification(&self, id: i64) -> Result<(), StoreError> {
+        let mut connection = self.0.acquire().await?;
+        sqlx::query("DELETE FROM Outbox WHERE ID = ?;")
+            .bind(id)
+            .execute(&mut connection)
+            .await?;
+        Ok(())
+    }
+
+    /// Records a failed attempt to send a notification, and when to try again
+    pub async fn retry_notification(
+        &self,
+        id: i64,
+        attempts: u32,
+        next_attempt: u32,
+    ) -> Result<(), StoreError> {
+        let mut connection = self.0.acquire().await?;
+        sqlx::query("UPDATE Outbox SET attempts = ?, next_attempt = ? WHERE ID = ?;")
+            .bind(attempts)
+            .bind(next_attempt)
+            .bind(id)
+            .execute(&mut connection)
+            .await?;
+        Ok(())
+    }
+
+    /// All the rules, in the order they were made
+    pub async fn get_rules(&self) -> Result<Vec<Rule>, StoreError> {
+        let mut connection = self.0.acquire().await?;
+        let rules = sqlx::query_as::<_, Rule>("SELECT * FROM Rules ORDER BY ID;")
+            .fetch_all(&mut connection)
+            .await?;
+        Ok(rules)
+    }
+
+    /// Adds a rule, or replaces the one with the same id. Returns the rule with its id
+    pub async fn save_rule(&self, rule: &Rule) -> Result<Rule, StoreError> {
+        let conditions = serde_json::to_string(&rule.conditions)?;
+        let actions = serde_json::to_string(&rule.actions)?;
+        let mut connection = self.0.acquire().await?;
+
+        let id = match rule.id {
+            Some(id) => {
+                sqlx::query("UPDATE Rules SET name = ?, enabled = ?, conditions = ?, actions = ? WHERE ID = ?;")
+                    .bind(&rule.name)
+                    .bind(rule.enabled)
+                    .bind(&conditions)
+                    .bind(&actions)
+                    .bind(id)
+                    .execute(&mut connection)
+                    .await?;
+                id
+            }
+            None => sqlx::query(
+                "INSERT INTO Rules (name, enabled, conditions, actions) VALUES (?, ?, ?, ?);",
+            )
+            .bind(&rule.name)
+            .bind(rule.enabled)
+            .bind(&conditions)
+            .bind(&actions)
+            .execute(&mut connection)
+            .await?
+            .last_insert_rowid(),
+        };
+
+        Ok(Rule {
+            id: Some(id),
+            ..rule.clone()
+        })
+    }
+
+    /// Deletes a rule. Returns false if there wasn't one with that id
+    pub async fn delete_rule(&self, id: i64) -> Result<bool, StoreError> {
+        let mut connection = self.0.acquire().await?;
+        let result = sqlx::query("DELETE FROM Rules WHERE ID = ?;")
+            .bind(id)
+            .execute(&mut connection)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Adds a captured file to the media catalog. Returns its entry
+    pub async fn add_media(
+        &self,
+        file_name: &str,
+        kind: &str,
+        source: &str,
+        captured_at: u32,
+    ) -> Result<MediaEntry, StoreError> {
+        let mut connection = self.0.acquire().await?;
+        let id = sqlx::query(
+            "INSERT INTO Media (file_name, kind, source, captured_at) VALUES (?, ?, ?, ?);",
+        )
+        .bind(file_name)
+        .bind(kind)
+        .bind(source)
+        .bind(captured_at)
+        .execute(&mut connection)
+        .await?
+        .last_insert_rowid();
+
+        Ok(MediaEntry {
+            id,
+            file_name: file_name.to_string(),
+            kind: kind.to_string(),
+            source: source.to_string(),
+            captured_at,
+        })
+    }
+
+    /// Everything in the media catalog, oldest first
+    pub async fn get_media(&self) -> Result<Vec<MediaEntry>, StoreError> {
+        let mut connection = self.0.acquire().await?;
+        let media = sqlx::query_as::<_, MediaEntry>("SELECT * FROM Media ORDER BY ID;")
+            .fetch_all(&mut connection)
+            .await?;
+        Ok(media)
+    }
+
     /// Write a single event to the db
     pub async fn write_event(&self, event: Event) -> Result<(), StoreError> {
         // We want to silently skip writing the EventHistory event because all it does is return
@@ -105,15 +420,14 @@ impl Store {
             None => format!("None"),
         };
         // Event data (if any)
+        // This is a bound parameter, so it doesn't need any escaping
         let data = match event.data() {
-            Some(d) => {
-                let unescaped = d.to_json().unwrap();
-                unescaped.replace(r#"""#, r#""""#)
-            }
+            Some(d) => d.to_json().unwrap(),
             None => format!("None"),
         };
 
         // Insert into table
+        let started = Instant::now();
         sqlx::query!(
             "INSERT INTO Events (kind, timestamp, device, data) VALUES (?, ?, ?, ?);",
             event_kind,
@@ -123,14 +437,54 @@ impl Store {
         )
         .execute(&mut connection)
         .await?;
+        metrics::db_write(started.elapsed());
 
         Ok(())
     }
 }
 
+/// Builds a MailSummary bundle from mail events (oldest first), as of `now`
+pub fn summarize_mail(events: &[Event], now: u32) -> Bundle {
+    const DAY: u32 = 60 * 60 * 24;
+
+    let last_pickup = events
+        .iter()
+        .rposition(|e| e.kind() == &EventKind::MailPickedUp);
+
+    // Everything delivered after the last pickup is still waiting in the box
+    let waiting: Vec<&Event> = events[last_pickup.map(|i| i + 1).unwrap_or(0)..]
+        .iter()
+        .filter(|e| e.kind() == &EventKind::MailDelivered)
+        .collect();
+
+    // Corrections made by hand happen whenever someone notices, not when the mail came,
+    // so leave them out of the average
+    let recent_times: Vec<u32> = events
+        .iter()
+        .filter(|e| e.kind() == &EventKind::MailDelivered)
+        .filter(|e| e.timestamp() + 30 * DAY >= now)
+        .filter(|e| !matches!(e.data(), Some(Bundle::ManualMailStatus { .. })))
+        .filter_map(|e| Local.timestamp_opt(e.timestamp() as i64, 0).single())
+        .map(|t| t.num_seconds_from_midnight())
+        .collect();
+
+    let average_delivery_time = match recent_times.len() {
+        0 => None,
+        n => Some((recent_times.iter().map(|t| *t as u64).sum::<u64>() / n as u64) as u32),
+    };
+
+    Bundle::MailSummary {
+        deliveries_since_pickup: waiting.len() as u32,
+        waiting_since: waiting.first().map(|e| e.timestamp()),
+        average_delivery_time,
+        days_since_pickup: last_pickup
+            .map(|i| now.saturating_sub(events[i].timestamp()) / DAY),
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::{drivers::device::DeviceType, model::EventKind};
+    use crate::drivers::device::DeviceType;
 
     use super::*;
 
@@ -142,6 +496,7 @@ mod tests {
 
     #[tokio::test]
     async fn test_get_all_events() {
+        let _db = TEST_DB.lock().await;
         let store = Store::connect().await.unwrap();
         
         store.nuke().await.unwrap();
@@ -164,6 +519,7 @@ mod tests {
 
     #[tokio::test]
     async fn test_write_event() {
+        let _db = TEST_DB.lock().await;
         let store = Store::connect().await.unwrap();
         
         store.nuke().await.unwrap();
@@ -176,6 +532,7 @@ mod tests {
 
     #[tokio::test]
     async fn test_get_latest_mail_status() {
+        let _db = TEST_DB.lock().await;
         let store = Store::connect().await.unwrap();
         
         store.nuke().await.unwrap();
@@ -199,4 +556,236 @@ mod tests {
 
         assert!(store.get_mail_status().await.is_err());
     }
+
+    #[tokio::test]
+    async fn test_manual_correction_is_latest_mail_status() {
+        let _db = TEST_DB.lock().await;
+        let store = Store::connect().await.unwrap();
+        store.nuke().await.unwrap();
+
+        store
+            .write_event(Event::new(EventKind::MailDelivered, None, None))
+            .await
+            .unwrap();
+        store
+            .write_event(Event::new(
+                EventKind::MailPickedUp,
+                None,
+                Some(Bundle::ManualMailStatus {
+                    corrected_by: "tester".to_string(),
+                }),
+            ))
+            .await
+            .unwrap();
+
+        let latest = store.get_mail_status().await.unwrap();
+        assert_eq!(latest.kind(), &EventKind::MailPickedUp);
+        assert_eq!(
+            latest.data(),
+            Some(&Bundle::ManualMailStatus {
+                corrected_by: "tester".to_string()
+            })
+        );
+
+        store.nuke().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_arm_state() {
+        let _db = TEST_DB.lock().await;
+        let store = Store::connect().await.unwrap();
+        store.nuke().await.unwrap();
+
+        assert_eq!(
+            store.get_arm_state().await.unwrap(),
+            Bundle::ArmState {
+                armed: false,
+                changed_by: None,
+                changed_at: None
+            }
+        );
+        assert!(!store.is_armed().await.unwrap());
+
+        let armed = store.set_arm_state(true, "Luke", 100).await.unwrap();
+        assert_eq!(store.get_arm_state().await.unwrap(), armed);
+        assert!(store.is_armed().await.unwrap());
+
+        store.set_arm_state(false, "Leia", 200).await.unwrap();
+        assert_eq!(
+            store.get_arm_state().await.unwrap(),
+            Bundle::ArmState {
+                armed: false,
+                changed_by: Some("Leia".to_string()),
+                changed_at: Some(200)
+            }
+        );
+
+        store.nuke().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_rules() {
+        use crate::rules::{Action, Condition};
+
+        let _db = TEST_DB.lock().await;
+        let store = Store::connect().await.unwrap();
+        store.nuke().await.unwrap();
+
+        let rule = Rule {
+            id: None,
+            name: "Weekend light".to_string(),
+            enabled: true,
+            conditions: vec![Condition::Weekday {
+                days: vec!["Sat".to_string(), "Sun".to_string()],
+            }],
+            actions: vec![Action::LightOn { seconds: 30 }],
+        };
+        let saved = store.save_rule(&rule).await.unwrap();
+        assert!(saved.id.is_some());
+        assert_eq!(store.get_rules().await.unwrap(), vec![saved.clone()]);
+
+        // Saving it again with the id replaces it
+        let disabled = Rule {
+            enabled: false,
+            ..saved.clone()
+        };
+        store.save_rule(&disabled).await.unwrap();
+        assert_eq!(store.get_rules().await.unwrap(), vec![disabled]);
+
+        assert!(store.delete_rule(saved.id.unwrap()).await.unwrap());
+        assert!(!store.delete_rule(saved.id.unwrap()).await.unwrap());
+        assert!(store.get_rules().await.unwrap().is_empty());
+
+        store.nuke().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_outbox() {
+        let _db = TEST_DB.lock().await;
+        let store = Store::connect().await.unwrap();
+        store.nuke().await.unwrap();
+
+        store
+            .queue_notification("webhook", "http://localhost/hook", "{}", 100)
+            .await
+            .unwrap();
+        store
+            .queue_notification("other", "somewhere", "{}", 100)
+            .await
+            .unwrap();
+
+        // Not due yet
+        assert!(store.due_notifications("webhook", 99).await.unwrap().is_empty());
+
+        let due = store.due_notifications("webhook", 100).await.unwrap();
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].target, "http://localhost/hook");
+        assert_eq!(due[0].attempts, 0);
+
+        store.retry_notification(due[0].id, 1, 200).await.unwrap();
+        assert!(store.due_notifications("webhook", 150).await.unwrap().is_empty());
+        let due = store.due_notifications("webhook", 200).await.unwrap();
+        assert_eq!(due[0].attempts, 1);
+
+        store.remove_notification(due[0].id).await.unwrap();
+        assert!(store.due_notifications("webhook", 1000).await.unwrap().is_empty());
+
+        store.nuke().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_media() {
+        let _db = TEST_DB.lock().await;
+        let store = Store::connect().await.unwrap();
+        store.nuke().await.unwrap();
+
+        let still = store
+            .add_media("100.jpg", "still", "ScheduledSnapshot", 100)
+            .await
+            .unwrap();
+        let video = store
+            .add_media("200.mp4", "video", "PollDeviceResult", 200)
+            .await
+            .unwrap();
+        assert!(still.id < video.id);
+        assert_eq!(store.get_media().await.unwrap(), vec![still, video]);
+
+        store.nuke().await.unwrap();
+        assert!(store.get_media().await.unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_summarize_mail() {
+        const DAY: u32 = 60 * 60 * 24;
+        let now = 100 * DAY;
+        let at = |kind: EventKind, timestamp: u32| {
+            let mut e = Event::new(kind, None, None);
+            e.set_timestamp(timestamp);
+            e
+        };
+
+        // Nothing yet
+        assert_eq!(
+            summarize_mail(&[], now),
+            Bundle::MailSummary {
+                deliveries_since_pickup: 0,
+                waiting_since: None,
+                average_delivery_time: None,
+                days_since_pickup: None,
+            }
+        );
+
+        let events = vec![
+            // Too old to count towards the average
+            at(EventKind::MailDelivered, now - 40 * DAY),
+            at(EventKind::MailPickedUp, now - 3 * DAY - 10),
+            at(EventKind::MailDelivered, now - 2 * DAY),
+            at(EventKind::MailDelivered, now - DAY),
+        ];
+
+        // Both recent deliveries were at the same time of day as `now`
+        let time_of_day = Local
+            .timestamp_opt(now as i64, 0)
+            .unwrap()
+            .num_seconds_from_midnight();
+
+        assert_eq!(
+            summarize_mail(&events, now),
+            Bundle::MailSummary {
+                deliveries_since_pickup: 2,
+                waiting_since: Some(now - 2 * DAY),
+                average_delivery_time: Some(time_of_day),
+                days_since_pickup: Some(3),
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_get_mail_summary() {
+        let _db = TEST_DB.lock().await;
+        let store = Store::connect().await.unwrap();
+        store.nuke().await.unwrap();
+
+        for kind in [
+            EventKind::MailDelivered,
+            EventKind::MailPickedUp,
+            EventKind::MailDelivered,
+        ] {
+            store.write_event(Event::new(kind, None, None)).await.unwrap();
+        }
+
+        match store.get_mail_summary().await.unwrap() {
+            Bundle::MailSummary {
+                deliveries_since_pickup,
+                days_since_pickup,
+                ..
+            } => {
+                assert_eq!(deliveries_since_pickup, 1);
+                assert_eq!(days_since_pickup, Some(0));
+            }
+            other => panic!("Expected a MailSummary, got {:?}", other),
+        }
+
+        store.nuke().await.unwrap();
+    }
 }
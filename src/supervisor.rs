@@ -0,0 +1,295 @@
+//! Keeps the daemon's subsystems (the server, the watchdog, the notifiers and MQTT) running.
+//!
+//! If one of them returns an error or panics, it's restarted after a delay that grows with
+//! every failure in a row, and a SubsystemFailed event goes out to the clients and into the
+//! history. How each of them is doing is kept here for the `/health` endpoint.
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use futures::FutureExt;
+use log::*;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::model::{Bundle, Event, EventKind};
+use crate::notify::now;
+use crate::server::Clients;
+use crate::shutdown;
+use crate::store::Store;
+
+/// Seconds to wait before the first restart. It doubles for every failure in a row after that
+const BASE_DELAY_SECS: u32 = 1;
+/// The longest we'll wait between restarts
+const MAX_DELAY_SECS: u32 = 5 * 60;
+/// A subsystem that ran this long before failing is considered to have been working, so the
+/// delay starts over
+const HEALTHY_AFTER: Duration = Duration::from_secs(10 * 60);
+
+static SUBSYSTEMS: Mutex<BTreeMap<String, Status>> = Mutex::new(BTreeMap::new());
+
+/// What a subsystem is doing
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone, JsonSchema)]
+pub enum State {
+    Running,
+    /// It failed and is waiting to be started again
+    Restarting,
+    /// It finished, ie. because we're shutting down or it isn't set up
+    Stopped,
+}
+
+/// How a subsystem is doing
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone, JsonSchema)]
+pub struct Status {
+    pub state: State,
+    /// How many times it's been restarted since the daemon started
+    pub restarts: u32,
+    /// Why it failed last, if it ever has
+    pub last_error: Option<String>,
+    /// Timestamp of the last failure
+    pub failed_at: Option<u32>,
+}
+
+/// Served at `/health`
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone, JsonSchema)]
+pub struct Health {
+    /// False if anything is waiting to be restarted
+    pub healthy: bool,
+    pub subsystems: BTreeMap<String, Status>,
+}
+
+/// How every subsystem is doing right now
+pub fn health() -> Health {
+    let subsystems = SUBSYSTEMS.lock().expect("supervisor lock").clone();
+    Health {
+        healthy: subsystems.values().all(|s| s.state != State::Restarting),
+        subsystems,
+    }
+}
+
+fn update(name: &str, update: impl FnOnce(&mut Status)) {
+    let mut subsystems = SUBSYSTEMS.lock().expect("supervisor lock");
+    let status = subsystems.entry(name.to_string()).or_insert(Status {
+        state: State::Running,
+        restarts: 0,
+        last_error: None,
+        failed_at: None,
+    });
+    update(status);
+}
+
+/// How long to wait before restarting after `failures` failures in a row
+pub fn restart_delay(failures: u32) -> u32 {
+    let exponent = failures.saturating_sub(1).min(16);
+    BASE_DELAY_SECS
+        .saturating_mul(2u32.pow(exponent))
+        .min(MAX_DELAY_SECS)
+}
+
+/// Gets something readable out of a panic
+fn panic_message(panic: Box<dyn std::any::Any + Send>) -> String {
+    match panic.downcast::<String>() {
+        Ok(msg) => *msg,
+        Err(panic) => match panic.downcast::<&str>() {
+            Ok(msg) => msg.to_string(),
+            Err(_) => "unknown panic".to_string(),
+        },
+    }
+}
+
+/// Lets the clients know a subsystem failed, and puts it in the history. Clients older than
+/// protocol version 4 don't know about SubsystemFailed, so they aren't told
+async fn report(name: &str, error: &str, restarts: u32, retry_in: u32, clients: &Clients) {
+    let event = Event::new(
+        EventKind::SubsystemFailed,
+        None,
+        Some(Bundle::SubsystemFailed {
+            subsystem: name.to_string(),
+            error: error.to_string(),
+            restarts,
+            retry_in,
+        }),
+    );
+    for (id, client) in clients.lock().await.iter() {
+        if let Some(sender) = &client.sender {
+            if client.protocol_version >= 4 && sender.send(Ok(event.clone().to_msg())).is_err() {
+                warn!("Couldn't tell client {id} that {name} failed");
+            }
+        }
+    }
+    match Store::connect().await {
+        Ok(store) => {
+            if let Err(e) = store.write_event(event).await {
+                error!("Couldn't record that {name} failed: {e}");
+            }
+        }
+        Err(e) => error!("Couldn't record that {name} failed: {e}"),
+    }
+}
+
+/// Runs `task` until it finishes cleanly or we shut down, starting it again whenever it
+/// returns an error or panics
+pub async fn supervise<F, Fut>(name: &str, clients: &Clients, mut task: F)
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<(), Box<dyn std::error::Error>>>,
+{
+    // Failures in a row, for the delay
+    let mut failures = 0;
+    loop {
+        update(name, |s| s.state = State::Running);
+        let started = Instant::now();
+        let error = match AssertUnwindSafe(task()).catch_unwind().await {
+            Ok(Ok(())) => {
+                info!("{name} finished");
+                update(name, |s| s.state = State::Stopped);
+                return;
+            }
+            Ok(Err(e)) => e.to_string(),
+            Err(panic) => format!("panicked: {}", panic_message(panic)),
+        };
+
+        if shutdown::is_stopping() {
+            error!("{name} failed while shutting down: {error}");
+            update(name, |s| s.state = State::Stopped);
+            return;
+        }
+
+        if started.elapsed() >= HEALTHY_AFTER {
+            failures = 0;
+        }
+        failures += 1;
+        let retry_in = restart_delay(failures);
+        error!("{name} failed, restarting it in {retry_in}s: {error}");
+
+        let mut restarts = 0;
+        update(name, |s| {
+            s.state = State::Restarting;
+            s.restarts += 1;
+            s.last_error = Some(error.clone());
+            s.failed_at = Some(now());
+            restarts = s.restarts;
+        });
+        report(name, &error, restarts, retry_in, clients).await;
+
+        tokio::select! {
+            _ = tokio::time::sleep(Duration::from_secs(retry_in as u64)) => {}
+            _ = shutdown::stopped() => {
+                update(name, |s| s.state = State::Stopped);
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    use tokio::sync::{mpsc, Mutex};
+
+    use super::*;
+    use crate::server::Client;
+
+    #[test]
+    fn test_restart_delay() {
+        assert_eq!(restart_delay(1), 1);
+        assert_eq!(restart_delay(2), 2);
+        assert_eq!(restart_delay(5), 16);
+        assert_eq!(restart_delay(20), MAX_DELAY_SECS);
+        assert_eq!(restart_delay(u32::MAX), MAX_DELAY_SECS);
+    }
+
+    #[test]
+    fn test_health() {
+        update("test healthy", |s| s.state = State::Stopped);
+        let health = health();
+        assert_eq!(health.subsystems["test healthy"].state, State::Stopped);
+        assert_eq!(
+            health.healthy,
+            health
+                .subsystems
+                .values()
+                .all(|s| s.state != State::Restarting)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_report_to_new_clients_only() {
+        let _db = crate::store::TEST_DB.lock().await;
+        let clients: Clients = Arc::new(Mutex::new(HashMap::new()));
+        let mut receivers = Vec::new();
+        for version in [3, 4] {
+            let (sender, receiver) = mpsc::unbounded_channel();
+            clients.lock().await.insert(
+                format!("v{version}"),
+                Client {
+                    client_id: format!("v{version}"),
+                    protocol_version: version,
+                    authorized: false,
+                    sender: Some(sender),
+                },
+            );
+            receivers.push(receiver);
+        }
+
+        report("test report", "it broke", 1, 1, &clients).await;
+
+        // The v3 client wouldn't know what it is
+        assert!(receivers[0].try_recv().is_err());
+        let msg = receivers[1].try_recv().unwrap().unwrap();
+        let event: Event = serde_json::from_str(msg.to_str().unwrap()).unwrap();
+        assert_eq!(event.kind(), &EventKind::SubsystemFailed);
+    }
+
+    #[tokio::test]
+    async fn test_supervise() {
+        let _db = crate::store::TEST_DB.lock().await;
+        let _stop = shutdown::TEST_STOP.lock().await;
+        let clients: Clients = Arc::new(Mutex::new(HashMap::new()));
+
+        // Panics the first time, then works and finishes
+        let mut runs = 0;
+        supervise("test flaky", &clients, || {
+            runs += 1;
+            let run = runs;
+            async move {
+                if run == 1 {
+                    panic!("the camera fell off");
+                }
+                Ok(())
+            }
+        })
+        .await;
+        assert_eq!(runs, 2);
+
+        let status = &health().subsystems["test flaky"];
+        assert_eq!(status.state, State::Stopped);
+        assert_eq!(status.restarts, 1);
+        assert_eq!(
+            status.last_error.as_deref(),
+            Some("panicked: the camera fell off")
+        );
+
+        // And it's in the history
+        let store = Store::connect().await.unwrap();
+        let events = store.get_all_events().await.unwrap();
+        let failed = events
+            .iter()
+            .rev()
+            .find(|e| e.kind() == &EventKind::SubsystemFailed)
+            .expect("a SubsystemFailed event");
+        assert_eq!(
+            failed.data(),
+            Some(&Bundle::SubsystemFailed {
+                subsystem: "test flaky".to_string(),
+                error: "panicked: the camera fell off".to_string(),
+                restarts: 1,
+                retry_in: 1,
+            })
+        );
+    }
+}
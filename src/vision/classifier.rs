@@ -0,0 +1,192 @@
+//! Decides whether mail was delivered or picked up by comparing a still of the mailbox
+//! taken before the door opened with one taken after it closed.
+//!
+//! We only look at the region of interest (the mailbox floor). If that region didn't change much,
+//! nothing happened. If it did, we look at how "busy" the region is: an empty floor is mostly flat,
+//! and letters/packages add edges. More edges after means a delivery, fewer means a pickup.
+use std::path::Path;
+
+use image::{imageops, GrayImage};
+use log::*;
+
+use super::{mean_difference, Roi};
+use crate::defaults;
+use crate::drivers::DeviceError;
+
+/// What happened to the mail between the two images
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MailChange {
+    Delivered,
+    PickedUp,
+    Unchanged,
+}
+
+/// The result of comparing two images
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Classification {
+    pub change: MailChange,
+    /// How sure we are, from 0 to 1
+    pub confidence: f32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Classifier {
+    /// The part of the image to compare (the mailbox floor)
+    roi: Roi,
+    /// The mean difference (0 - 1) inside the roi before we consider it changed
+    threshold: f32,
+}
+
+impl Classifier {
+    pub fn new(roi: Roi, threshold: f32) -> Self {
+        Classifier { roi, threshold }
+    }
+
+    /// Uses the region and threshold from the environment, see `defaults`
+    pub fn from_env() -> Self {
+        Self::new(defaults::mail_roi(), defaults::mail_threshold())
+    }
+
+    /// Compares the stills at the two paths
+    pub fn classify_files(&self, before: &Path, after: &Path) -> Result<Classification, DeviceError> {
+        let before = image::open(before)?.to_luma8();
+        let after = image::open(after)?.to_luma8();
+        Ok(self.classify(&before, &after))
+    }
+
+    pub fn classify(&self, before: &GrayImage, after: &GrayImage) -> Classification {
+        // The camera resolution shouldn't change between captures, but just in case
+        let after = if after.dimensions() != before.dimensions() {
+            imageops::resize(
+                after,
+                before.width(),
+                before.height(),
+                imageops::FilterType::Triangle,
+            )
+        } else {
+            after.clone()
+        };
+
+        let before = self.roi.crop(before);
+        let after = self.roi.crop(&after);
+
+        let difference = mean_difference(&before, &after);
+        trace!(
+            "Mail classifier: difference {difference:.4} (threshold {})",
+            self.threshold
+        );
+
+        if difference < self.threshold {
+            return Classification {
+                change: MailChange::Unchanged,
+                confidence: 1.0 - difference / self.threshold,
+            };
+        }
+
+        let edges_before = edge_density(&before);
+        let edges_after = edge_density(&after);
+        trace!("Mail classifier: edge density {edges_before:.4} -> {edges_after:.4}");
+
+        // How much bigger the change was than the threshold, and how clearly
+        // the edges went one way or the other
+        let magnitude = (difference / (2.0 * self.threshold)).min(1.0);
+        let direction = (edges_after - edges_before).abs() / edges_before.max(edges_after).max(0.001);
+
+        Classification {
+            change: if edges_after > edges_before {
+                MailChange::Delivered
+            } else {
+                MailChange::PickedUp
+            },
+            confidence: magnitude * direction.min(1.0),
+        }
+    }
+}
+
+/// Average strength of the horizontal and vertical edges in an image, from 0 to 1
+fn edge_density(img: &GrayImage) -> f32 {
+    let (width, height) = img.dimensions();
+    if width < 2 || height < 2 {
+        return 0.0;
+    }
+
+    let mut total: u64 = 0;
+    for y in 0..height - 1 {
+        for x in 0..width - 1 {
+            let p = img.get_pixel(x, y).0[0] as i16;
+            let right = img.get_pixel(x + 1, y).0[0] as i16;
+            let below = img.get_pixel(x, y + 1).0[0] as i16;
+            total += ((p - right).unsigned_abs() + (p - below).unsigned_abs()) as u64;
+        }
+    }
+
+    total as f32 / ((width - 1) * (height - 1)) as f32 / (2.0 * 255.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use image::Luma;
+
+    use super::*;
+
+    // A dark, empty mailbox floor
+    fn empty_box() -> GrayImage {
+        GrayImage::from_pixel(80, 60, Luma([40]))
+    }
+
+    // The same box with a white envelope on the bottom half
+    fn box_with_letter() -> GrayImage {
+        let mut img = empty_box();
+        for x in 20..60 {
+            for y in 35..50 {
+                img.put_pixel(x, y, Luma([230]));
+            }
+        }
+        img
+    }
+
+    fn bottom_half() -> Classifier {
+        Classifier::new(Roi::parse("0,0.5,1,0.5").unwrap(), 0.05)
+    }
+
+    #[test]
+    fn test_delivered() {
+        let result = bottom_half().classify(&empty_box(), &box_with_letter());
+        assert_eq!(result.change, MailChange::Delivered);
+        assert!(result.confidence > 0.5);
+    }
+
+    #[test]
+    fn test_picked_up() {
+        let result = bottom_half().classify(&box_with_letter(), &empty_box());
+        assert_eq!(result.change, MailChange::PickedUp);
+        assert!(result.confidence > 0.5);
+    }
+
+    #[test]
+    fn test_unchanged() {
+        let result = bottom_half().classify(&box_with_letter(), &box_with_letter());
+        assert_eq!(result.change, MailChange::Unchanged);
+        assert_eq!(result.confidence, 1.0);
+    }
+
+    #[test]
+    fn test_change_outside_roi_is_ignored() {
+        // Something changed at the top of the image (the door, a hand), not on the floor
+        let mut after = empty_box();
+        for x in 0..80 {
+            for y in 0..20 {
+                after.put_pixel(x, y, Luma([255]));
+            }
+        }
+        let result = bottom_half().classify(&empty_box(), &after);
+        assert_eq!(result.change, MailChange::Unchanged);
+    }
+
+    #[test]
+    fn test_different_sizes() {
+        let small = imageops::resize(&box_with_letter(), 40, 30, imageops::FilterType::Nearest);
+        let result = bottom_half().classify(&empty_box(), &small);
+        assert_eq!(result.change, MailChange::Delivered);
+    }
+}
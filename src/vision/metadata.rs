@@ -0,0 +1,402 @@
+//! Writes where and when a picture or video was taken into the file itself, so media copied
+//! off the box still says what it is.
+//!
+//! Jpegs get an EXIF block (the standard camera tags) and an XMP packet (with our own fields
+//! too). Nothing we depend on can write EXIF, so both are built by hand here. Videos are tagged
+//! by ffmpeg.
+use std::fs;
+use std::path::Path;
+
+use chrono::{DateTime, Local};
+use log::*;
+
+use crate::drivers::{check_output, run, DeviceError};
+
+const EXIF_HEADER: &[u8] = b"Exif\0\0";
+const XMP_HEADER: &[u8] = b"http://ns.adobe.com/xap/1.0/\0";
+
+/// What we know about a capture
+#[derive(Debug, Clone, PartialEq)]
+pub struct Metadata {
+    pub captured_at: DateTime<Local>,
+    /// `MODKIT_DEVICE_ID`
+    pub device_id: String,
+    /// The event the capture was for, as `Kind-timestamp` like in the Events table
+    pub event_id: Option<String>,
+    /// The camera settings, ie. `800x550, ISO 100`
+    pub settings: String,
+}
+
+impl Metadata {
+    fn description(&self) -> String {
+        match &self.event_id {
+            Some(event_id) => format!("{} {event_id}", self.device_id),
+            None => self.device_id.clone(),
+        }
+    }
+}
+
+/// Adds EXIF and XMP metadata to a jpeg, replacing any that's already there
+pub fn embed_jpeg(path: &Path, metadata: &Metadata) -> Result<(), DeviceError> {
+    let jpeg = fs::read(path)?;
+    fs::write(path, with_metadata(&jpeg, metadata)?)?;
+    Ok(())
+}
+
+/// Tags an mp4 with a title, comment and creation time. ffmpeg can't do that in place, so it
+/// writes a copy that replaces the original
+pub fn embed_mp4(path: &Path, metadata: &Metadata) -> Result<(), DeviceError> {
+    let mut tagged = path.to_path_buf();
+    tagged.set_extension("tagged.mp4");
+
+    let args = mp4_args(path, &tagged, metadata);
+    let args: Vec<&str> = args.iter().map(|a| a.as_str()).collect();
+    if let Err(e) = run("ffmpeg", &args).and_then(|_| check_output(&tagged, "ffmpeg")) {
+        let _ = fs::remove_file(&tagged);
+        return Err(e);
+    }
+
+    fs::rename(&tagged, path)?;
+    trace!("Tagged {}", path.display());
+    Ok(())
+}
+
+fn mp4_args(input: &Path, output: &Path, metadata: &Metadata) -> Vec<String> {
+    let mut args = vec!["-y".to_string(), "-i".to_string()];
+    args.push(format!("{}", input.display()));
+    for arg in ["-c", "copy", "-map_metadata", "0"] {
+        args.push(arg.to_string());
+    }
+    for (key, value) in [
+        ("title", metadata.description()),
+        ("comment", metadata.settings.clone()),
+        ("creation_time", metadata.captured_at.to_rfc3339()),
+    ] {
+        args.push("-metadata".to_string());
+        args.push(format!("{key}={value}"));
+    }
+    args.push(format!("{}", output.display()));
+    args
+}
+
+/// A copy of the jpeg with our APP1 segments right after the SOI (and the JFIF header, if
+/// there is one), and any old EXIF or XMP taken out
+fn with_metadata(jpeg: &[u8], metadata: &Metadata) -> Result<Vec<u8>, DeviceError> {
+    let invalid = || DeviceError::ImageError("not a valid jpeg".to_string());
+    if !jpeg.starts_with(&[0xFF, 0xD8]) {
+        return Err(invalid());
+    }
+
+    let mut out = vec![0xFF, 0xD8];
+    let mut ours = Vec::new();
+    app1(&mut ours, EXIF_HEADER, &exif(metadata))?;
+    app1(&mut ours, XMP_HEADER, xmp(metadata).as_bytes())?;
+
+    // Walk the segments up to the start of the image data
+    let mut i = 2;
+    let mut inserted = false;
+    while i + 4 <= jpeg.len() && jpeg[i] == 0xFF {
+        let marker = jpeg[i + 1];
+        // Start of scan, the rest is image data
+        if marker == 0xDA {
+            break;
+        }
+        let length = u16::from_be_bytes([jpeg[i + 2], jpeg[i + 3]]) as usize;
+        let end = i + 2 + length;
+        if length < 2 || end > jpeg.len() {
+            return Err(invalid());
+        }
+        let segment = &jpeg[i..end];
+        let payload = &segment[4..];
+
+        // JFIF has to stay first
+        if marker != 0xE0 && !inserted {
+            out.extend_from_slice(&ours);
+            inserted = true;
+        }
+        let old_metadata =
+            marker == 0xE1 && (payload.starts_with(EXIF_HEADER) || payload.starts_with(XMP_HEADER));
+        if !old_metadata {
+            out.extend_from_slice(segment);
+        }
+        i = end;
+    }
+    if !inserted {
+        out.extend_from_slice(&ours);
+    }
+    out.extend_from_slice(&jpeg[i..]);
+    Ok(out)
+}
+
+fn app1(out: &mut Vec<u8>, header: &[u8], body: &[u8]) -> Result<(), DeviceError> {
+    let length = 2 + header.len() + body.len();
+    if length > u16::MAX as usize {
+        return Err(DeviceError::ImageError("metadata is too big".to_string()));
+    }
+    out.extend_from_slice(&[0xFF, 0xE1]);
+    out.extend_from_slice(&(length as u16).to_be_bytes());
+    out.extend_from_slice(header);
+    out.extend_from_slice(body);
+    Ok(())
+}
+
+/// A value in an EXIF directory
+enum Value {
+    Ascii(String),
+    Undefined(Vec<u8>),
+    Long(u32),
+}
+
+impl Value {
+    /// `(type, count, bytes)`
+    fn encode(&self) -> (u16, u32, Vec<u8>) {
+        match self {
+            Self::Ascii(s) => {
+                let mut bytes = s.as_bytes().to_vec();
+                bytes.push(0);
+                (2, bytes.len() as u32, bytes)
+            }
+            Self::Undefined(bytes) => (7, bytes.len() as u32, bytes.clone()),
+            Self::Long(n) => (4, 1, n.to_be_bytes().to_vec()),
+        }
+    }
+}
+
+/// The size of a directory and the values that don't fit in its entries
+fn ifd_size(entries: &[(u16, Value)]) -> u32 {
+    let data: usize = entries
+        .iter()
+        .map(|(_, value)| value.encode().2.len())
+        .filter(|len| *len > 4)
+        .map(|len| len + len % 2)
+        .sum();
+    (2 + 12 * entries.len() + 4 + data) as u32
+}
+
+/// Writes a directory that starts `offset` bytes into the TIFF data, followed by its values
+fn write_ifd(out: &mut Vec<u8>, entries: &[(u16, Value)], offset: u32) {
+    let mut data = Vec::new();
+    let data_offset = offset + 2 + 12 * entries.len() as u32 + 4;
+
+    out.extend_from_slice(&(entries.len() as u16).to_be_bytes());
+    for (tag, value) in entries {
+        let (kind, count, mut bytes) = value.encode();
+        out.extend_from_slice(&tag.to_be_bytes());
+        out.extend_from_slice(&kind.to_be_bytes());
+        out.extend_from_slice(&count.to_be_bytes());
+        if bytes.len() <= 4 {
+            bytes.resize(4, 0);
+            out.extend_from_slice(&bytes);
+        } else {
+            out.extend_from_slice(&(data_offset + data.len() as u32).to_be_bytes());
+            if bytes.len() % 2 == 1 {
+                bytes.push(0);
+            }
+            data.extend_from_slice(&bytes);
+        }
+    }
+    // No next directory
+    out.extend_from_slice(&0u32.to_be_bytes());
+    out.extend_from_slice(&data);
+}
+
+/// A big endian TIFF block with the main directory and an EXIF directory
+fn exif(metadata: &Metadata) -> Vec<u8> {
+    let time = metadata.captured_at.format("%Y:%m:%d %H:%M:%S").to_string();
+    let mut comment = b"ASCII\0\0\0".to_vec();
+    comment.extend_from_slice(metadata.settings.as_bytes());
+
+    let exif_entries = vec![
+        (0x9003, Value::Ascii(time.clone())),
+        (0x9286, Value::Undefined(comment)),
+    ];
+    // The main directory starts right after the 8 byte header
+    let mut main_entries = vec![
+        (0x010E, Value::Ascii(metadata.description())),
+        (0x010F, Value::Ascii("modkit".to_string())),
+        (0x0110, Value::Ascii(metadata.device_id.clone())),
+        (
+            0x0131,
+            Value::Ascii(format!("modkit {}", env!("CARGO_PKG_VERSION"))),
+        ),
+        (0x0132, Value::Ascii(time)),
+        (0x8769, Value::Long(0)),
+    ];
+    let exif_offset = 8 + ifd_size(&main_entries);
+    main_entries[5].1 = Value::Long(exif_offset);
+
+    let mut out = b"MM\0\x2A\0\0\0\x08".to_vec();
+    write_ifd(&mut out, &main_entries, 8);
+    write_ifd(&mut out, &exif_entries, exif_offset);
+    out
+}
+
+fn xmp(metadata: &Metadata) -> String {
+    let event_id = match &metadata.event_id {
+        Some(event_id) => format!("\n   <modkit:EventId>{}</modkit:EventId>", escape(event_id)),
+        None => String::new(),
+    };
+
+    format!(
+        r#"<?xpacket begin="" id="W5M0MpCehiHzreSzNTczkc9d"?>
+<x:xmpmeta xmlns:x="adobe:ns:meta/">
+ <rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">
+  <rdf:Description rdf:about=""
+    xmlns:xmp="http://ns.adobe.com/xap/1.0/"
+    xmlns:modkit="https://github.com/MailThieves/modkit/ns/1.0/">
+   <xmp:CreateDate>{}</xmp:CreateDate>
+   <xmp:CreatorTool>modkit {}</xmp:CreatorTool>
+   <modkit:DeviceId>{}</modkit:DeviceId>{event_id}
+   <modkit:CameraSettings>{}</modkit:CameraSettings>
+  </rdf:Description>
+ </rdf:RDF>
+</x:xmpmeta>
+<?xpacket end="w"?>"#,
+        metadata.captured_at.to_rfc3339(),
+        env!("CARGO_PKG_VERSION"),
+        escape(&metadata.device_id),
+        escape(&metadata.settings),
+    )
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryInto;
+
+    use chrono::{NaiveDate, TimeZone};
+    use image::{ImageBuffer, RgbImage};
+
+    use super::*;
+
+    fn metadata() -> Metadata {
+        let time = NaiveDate::from_ymd_opt(2023, 3, 4)
+            .unwrap()
+            .and_hms_opt(12, 30, 5)
+            .unwrap();
+        Metadata {
+            captured_at: Local.from_local_datetime(&time).unwrap(),
+            device_id: "frontbox".to_string(),
+            event_id: Some("DoorOpened-1677933005".to_string()),
+            settings: "800x550, ISO 100 & <vflip>".to_string(),
+        }
+    }
+
+    fn jpeg() -> Vec<u8> {
+        let img: RgbImage = ImageBuffer::from_pixel(20, 10, image::Rgb([120, 30, 200]));
+        let mut bytes = std::io::Cursor::new(Vec::new());
+        img.write_to(&mut bytes, image::ImageOutputFormat::Jpeg(90))
+            .unwrap();
+        bytes.into_inner()
+    }
+
+    fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+        haystack.windows(needle.len()).any(|w| w == needle)
+    }
+
+    fn count(haystack: &[u8], needle: &[u8]) -> usize {
+        haystack
+            .windows(needle.len())
+            .filter(|w| *w == needle)
+            .count()
+    }
+
+    // Reads an ascii tag out of a directory, the way a viewer would
+    fn read_ascii(tiff: &[u8], ifd: usize, tag: u16) -> Option<String> {
+        let be16 = |i: usize| u16::from_be_bytes([tiff[i], tiff[i + 1]]);
+        let be32 = |i: usize| u32::from_be_bytes([tiff[i], tiff[i + 1], tiff[i + 2], tiff[i + 3]]);
+        for n in 0..be16(ifd) as usize {
+            let entry = ifd + 2 + 12 * n;
+            if be16(entry) == tag {
+                let count = be32(entry + 4) as usize;
+                let start = if count <= 4 {
+                    entry + 8
+                } else {
+                    be32(entry + 8) as usize
+                };
+                let bytes = &tiff[start..start + count - 1];
+                return Some(String::from_utf8(bytes.to_vec()).unwrap());
+            }
+        }
+        None
+    }
+
+    #[test]
+    fn test_exif() {
+        let tiff = exif(&metadata());
+        assert!(tiff.starts_with(b"MM\0\x2A"));
+        assert_eq!(read_ascii(&tiff, 8, 0x0132).unwrap(), "2023:03:04 12:30:05");
+        assert_eq!(read_ascii(&tiff, 8, 0x0110).unwrap(), "frontbox");
+        assert_eq!(
+            read_ascii(&tiff, 8, 0x010E).unwrap(),
+            "frontbox DoorOpened-1677933005"
+        );
+
+        // Follow the pointer to the EXIF directory
+        let pointer = 8 + 2 + 12 * 5 + 8;
+        let exif_ifd = u32::from_be_bytes(tiff[pointer..pointer + 4].try_into().unwrap());
+        assert_eq!(
+            read_ascii(&tiff, exif_ifd as usize, 0x9003).unwrap(),
+            "2023:03:04 12:30:05"
+        );
+    }
+
+    #[test]
+    fn test_xmp() {
+        let xmp = xmp(&metadata());
+        assert!(xmp.contains("<modkit:DeviceId>frontbox</modkit:DeviceId>"));
+        assert!(xmp.contains("<modkit:EventId>DoorOpened-1677933005</modkit:EventId>"));
+        assert!(xmp.contains("800x550, ISO 100 &amp; &lt;vflip&gt;"));
+
+        let mut no_event = metadata();
+        no_event.event_id = None;
+        assert!(!super::xmp(&no_event).contains("EventId"));
+    }
+
+    #[test]
+    fn test_embed_jpeg() {
+        let path = std::env::temp_dir().join("modkit-metadata-test.jpg");
+        fs::write(&path, jpeg()).unwrap();
+
+        embed_jpeg(&path, &metadata()).unwrap();
+        // Doing it again replaces the old metadata instead of piling it up
+        embed_jpeg(&path, &metadata()).unwrap();
+
+        let bytes = fs::read(&path).unwrap();
+        assert_eq!(count(&bytes, EXIF_HEADER), 1);
+        assert_eq!(count(&bytes, XMP_HEADER), 1);
+        assert!(contains(&bytes, b"DoorOpened-1677933005"));
+        // JFIF stays first
+        assert_eq!(&bytes[6..11], b"JFIF\0");
+
+        // And it's still a picture
+        let img = image::open(&path).unwrap();
+        assert_eq!((img.width(), img.height()), (20, 10));
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_not_a_jpeg() {
+        assert!(with_metadata(b"\x89PNG", &metadata()).is_err());
+    }
+
+    #[test]
+    fn test_mp4_args() {
+        let args = mp4_args(
+            Path::new("img/1.mp4"),
+            Path::new("img/1.tagged.mp4"),
+            &metadata(),
+        );
+        assert_eq!(&args[..3], ["-y", "-i", "img/1.mp4"]);
+        assert!(args.contains(&"title=frontbox DoorOpened-1677933005".to_string()));
+        assert!(args.contains(&"comment=800x550, ISO 100 & <vflip>".to_string()));
+        assert_eq!(args.last().unwrap(), "img/1.tagged.mp4");
+    }
+}
@@ -0,0 +1,154 @@
+//! Image processing on frames captured by the camera
+use std::convert::TryFrom;
+use std::fmt;
+
+use image::{imageops, GrayImage};
+use serde::{Deserialize, Serialize};
+
+pub mod classifier;
+pub mod metadata;
+pub mod motion;
+pub mod pipeline;
+
+/// A region of an image, given as fractions of the image size so it doesn't
+/// depend on the capture resolution. `x = 0, y = 0` is the top left corner.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Roi {
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+    pub height: f32,
+}
+
+impl Roi {
+    /// The whole image
+    pub fn full() -> Self {
+        Roi {
+            x: 0.0,
+            y: 0.0,
+            width: 1.0,
+            height: 1.0,
+        }
+    }
+
+    /// Parses a region from `x,y,width,height`, ie. `0.1,0.5,0.8,0.5`
+    pub fn parse(s: &str) -> Option<Self> {
+        let parts: Vec<f32> = s
+            .split(',')
+            .map(|p| p.trim().parse::<f32>())
+            .collect::<Result<_, _>>()
+            .ok()?;
+
+        if parts.len() != 4 {
+            return None;
+        }
+
+        let roi = Roi {
+            x: parts[0],
+            y: parts[1],
+            width: parts[2],
+            height: parts[3],
+        };
+
+        // Make sure it actually fits inside an image
+        let in_range = |v: f32| (0.0..=1.0).contains(&v);
+        if !parts.iter().all(|p| in_range(*p))
+            || !in_range(roi.x + roi.width)
+            || !in_range(roi.y + roi.height)
+            || roi.width == 0.0
+            || roi.height == 0.0
+        {
+            return None;
+        }
+
+        Some(roi)
+    }
+
+    /// The region in pixels for an image of the given size, as `(x, y, width, height)`.
+    /// Always at least 1x1 pixels.
+    pub fn to_pixels(&self, img_width: u32, img_height: u32) -> (u32, u32, u32, u32) {
+        let x = ((self.x * img_width as f32) as u32).min(img_width.saturating_sub(1));
+        let y = ((self.y * img_height as f32) as u32).min(img_height.saturating_sub(1));
+        let width = ((self.width * img_width as f32) as u32).clamp(1, img_width - x);
+        let height = ((self.height * img_height as f32) as u32).clamp(1, img_height - y);
+        (x, y, width, height)
+    }
+
+    /// Copies this region out of an image
+    pub fn crop(&self, img: &GrayImage) -> GrayImage {
+        let (x, y, w, h) = self.to_pixels(img.width(), img.height());
+        imageops::crop_imm(img, x, y, w, h).to_image()
+    }
+}
+
+impl fmt::Display for Roi {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{},{},{},{}", self.x, self.y, self.width, self.height)
+    }
+}
+
+// So it's written as `0.1,0.5,0.8,0.5` in json, like in the environment
+impl TryFrom<String> for Roi {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        Self::parse(&s).ok_or(format!("{s:?} isn't a region like `0.1,0.5,0.8,0.5`"))
+    }
+}
+
+impl From<Roi> for String {
+    fn from(roi: Roi) -> Self {
+        roi.to_string()
+    }
+}
+
+/// Mean absolute difference between two images of the same size, from 0 (identical) to 1
+pub fn mean_difference(a: &GrayImage, b: &GrayImage) -> f32 {
+    let total: u64 = a
+        .pixels()
+        .zip(b.pixels())
+        .map(|(pa, pb)| (pa.0[0] as i16 - pb.0[0] as i16).unsigned_abs() as u64)
+        .sum();
+    let count = (a.width() * a.height()).max(1) as f32;
+    total as f32 / count / 255.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_roi() {
+        assert_eq!(
+            Roi::parse("0.1, 0.5,0.8,0.5"),
+            Some(Roi {
+                x: 0.1,
+                y: 0.5,
+                width: 0.8,
+                height: 0.5
+            })
+        );
+        assert_eq!(Roi::parse("0,0,1,1"), Some(Roi::full()));
+        // Falls off the edge of the image
+        assert_eq!(Roi::parse("0.5,0.5,0.8,0.5"), None);
+        assert_eq!(Roi::parse("0.1,0.5,0.8"), None);
+        assert_eq!(Roi::parse("a,b,c,d"), None);
+        assert_eq!(Roi::parse("0,0,0,1"), None);
+    }
+
+    #[test]
+    fn test_roi_to_pixels() {
+        let roi = Roi::parse("0.25,0.5,0.5,0.5").unwrap();
+        assert_eq!(roi.to_pixels(100, 50), (25, 25, 50, 25));
+        assert_eq!(Roi::full().to_pixels(3, 3), (0, 0, 3, 3));
+    }
+
+    #[test]
+    fn test_roi_json() {
+        let roi: Roi = serde_json::from_str(r#""0.25,0.5,0.5,0.5""#).unwrap();
+        assert_eq!(roi, Roi::parse("0.25,0.5,0.5,0.5").unwrap());
+        assert_eq!(serde_json::to_string(&roi).unwrap(), r#""0.25,0.5,0.5,0.5""#);
+        assert!(serde_json::from_str::<Roi>(r#""0.5,0.5,0.8,0.5""#).is_err());
+    }
+}
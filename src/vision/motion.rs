@@ -0,0 +1,228 @@
+//! Notices movement in front of the camera, ie. a package left next to the box, which never
+//! trips the door sensor.
+//!
+//! Frames are shrunk to a small fixed size and blurred a little so sensor noise doesn't count.
+//! Each one is compared with the one before it, and if enough of the picture (outside the masks)
+//! changed, that's motion. Both frames have their average brightness taken out first, so the
+//! light coming on or a cloud going over doesn't count either.
+use image::{imageops, GrayImage};
+
+use super::Roi;
+use crate::defaults;
+
+/// Frames are shrunk to this size before they're compared
+pub const FRAME_WIDTH: u32 = 64;
+pub const FRAME_HEIGHT: u32 = 48;
+
+/// How much (0 - 255) a pixel has to change to count as changed
+const PIXEL_THRESHOLD: u8 = 25;
+
+#[derive(Debug, Clone)]
+pub struct MotionDetector {
+    /// The fraction (0 - 1) of the frame that has to change. Lower is more sensitive
+    sensitivity: f32,
+    /// Which pixels of a shrunk frame to look at. Masked out ones are false
+    included: Vec<bool>,
+    /// Seconds after a detection before we report another one
+    cooldown: u32,
+    previous: Option<GrayImage>,
+    last_motion: Option<u32>,
+}
+
+impl MotionDetector {
+    /// `masks` are parts of the picture to ignore, ie. a road or a tree in the wind
+    pub fn new(sensitivity: f32, masks: &[Roi], cooldown: u32) -> Self {
+        let mut included = vec![true; (FRAME_WIDTH * FRAME_HEIGHT) as usize];
+        for mask in masks {
+            let (x, y, w, h) = mask.to_pixels(FRAME_WIDTH, FRAME_HEIGHT);
+            for row in y..y + h {
+                for col in x..x + w {
+                    included[(row * FRAME_WIDTH + col) as usize] = false;
+                }
+            }
+        }
+
+        MotionDetector {
+            sensitivity,
+            included,
+            cooldown,
+            previous: None,
+            last_motion: None,
+        }
+    }
+
+    /// Uses the settings from the environment, see `defaults`
+    pub fn from_env() -> Self {
+        Self::new(
+            defaults::motion_sensitivity(),
+            &defaults::motion_masks(),
+            defaults::motion_cooldown(),
+        )
+    }
+
+    /// Forgets the last frame, ie. when the door opened and the view changed for a reason
+    /// we already know about
+    pub fn reset(&mut self) {
+        self.previous = None;
+    }
+
+    /// Compares a frame with the last one. Returns how much of the frame changed if that's
+    /// motion, and we're not cooling down from the last detection
+    pub fn feed(&mut self, frame: &GrayImage, now: u32) -> Option<f32> {
+        let frame = shrink(frame);
+        let previous = self.previous.replace(frame);
+        let changed = self.changed(&previous?, self.previous.as_ref()?);
+
+        if changed < self.sensitivity {
+            return None;
+        }
+        if let Some(last) = self.last_motion {
+            if now.saturating_sub(last) < self.cooldown {
+                return None;
+            }
+        }
+
+        self.last_motion = Some(now);
+        Some(changed)
+    }
+
+    /// The fraction of the unmasked pixels that changed between two shrunk frames
+    fn changed(&self, a: &GrayImage, b: &GrayImage) -> f32 {
+        let mean_a = self.mean(a);
+        let mean_b = self.mean(b);
+
+        let mut total = 0;
+        let mut changed = 0;
+        for ((pa, pb), included) in a.pixels().zip(b.pixels()).zip(&self.included) {
+            if !included {
+                continue;
+            }
+            total += 1;
+            let difference = (pa.0[0] as f32 - mean_a) - (pb.0[0] as f32 - mean_b);
+            if difference.abs() > PIXEL_THRESHOLD as f32 {
+                changed += 1;
+            }
+        }
+
+        if total == 0 {
+            return 0.0;
+        }
+        changed as f32 / total as f32
+    }
+
+    /// Average brightness of the unmasked pixels
+    fn mean(&self, img: &GrayImage) -> f32 {
+        let (sum, count) = img
+            .pixels()
+            .zip(&self.included)
+            .filter(|(_, included)| **included)
+            .fold((0u64, 0u64), |(sum, count), (p, _)| {
+                (sum + p.0[0] as u64, count + 1)
+            });
+        sum as f32 / count.max(1) as f32
+    }
+}
+
+fn shrink(frame: &GrayImage) -> GrayImage {
+    let small = imageops::resize(
+        frame,
+        FRAME_WIDTH,
+        FRAME_HEIGHT,
+        imageops::FilterType::Triangle,
+    );
+    imageops::blur(&small, 0.8)
+}
+
+#[cfg(test)]
+mod tests {
+    use image::Luma;
+
+    use super::*;
+
+    // The path in front of the box, at 160x120 like the camera gives us
+    fn empty_scene(brightness: u8) -> GrayImage {
+        let mut img = GrayImage::from_pixel(160, 120, Luma([brightness]));
+        // Some texture, so it's not just a flat picture
+        for x in (0..160).step_by(20) {
+            for y in 0..120 {
+                img.put_pixel(x, y, Luma([brightness.saturating_add(30)]));
+            }
+        }
+        img
+    }
+
+    // The same scene with a box left in the bottom left corner
+    fn with_package(brightness: u8) -> GrayImage {
+        let mut img = empty_scene(brightness);
+        for x in 10..60 {
+            for y in 70..110 {
+                img.put_pixel(x, y, Luma([220]));
+            }
+        }
+        img
+    }
+
+    fn detector() -> MotionDetector {
+        MotionDetector::new(0.02, &[], 60)
+    }
+
+    #[test]
+    fn test_no_motion() {
+        let mut detector = detector();
+        // Nothing to compare the first frame with
+        assert_eq!(detector.feed(&empty_scene(60), 0), None);
+        assert_eq!(detector.feed(&empty_scene(60), 5), None);
+        assert_eq!(detector.feed(&empty_scene(60), 10), None);
+    }
+
+    #[test]
+    fn test_package_left() {
+        let mut detector = detector();
+        detector.feed(&empty_scene(60), 0);
+        let changed = detector.feed(&with_package(60), 5).unwrap();
+        assert!(changed > 0.05, "changed = {:?}", changed);
+
+        // It's just sitting there now
+        assert_eq!(detector.feed(&with_package(60), 10), None);
+    }
+
+    #[test]
+    fn test_lighting_change() {
+        // The whole picture getting brighter isn't motion
+        let mut detector = detector();
+        detector.feed(&empty_scene(60), 0);
+        assert_eq!(detector.feed(&empty_scene(120), 5), None);
+    }
+
+    #[test]
+    fn test_cooldown() {
+        let mut detector = detector();
+        detector.feed(&empty_scene(60), 0);
+        assert!(detector.feed(&with_package(60), 5).is_some());
+        // Picked back up straight away, but we already said something moved
+        assert_eq!(detector.feed(&empty_scene(60), 10), None);
+        assert!(detector.feed(&with_package(60), 70).is_some());
+    }
+
+    #[test]
+    fn test_masked() {
+        let mut detector = MotionDetector::new(0.02, &[Roi::parse("0,0.5,0.5,0.5").unwrap()], 60);
+        detector.feed(&empty_scene(60), 0);
+        assert_eq!(detector.feed(&with_package(60), 5), None);
+    }
+
+    #[test]
+    fn test_sensitivity() {
+        let mut detector = MotionDetector::new(0.5, &[], 60);
+        detector.feed(&empty_scene(60), 0);
+        assert_eq!(detector.feed(&with_package(60), 5), None);
+    }
+
+    #[test]
+    fn test_reset() {
+        let mut detector = detector();
+        detector.feed(&empty_scene(60), 0);
+        detector.reset();
+        assert_eq!(detector.feed(&with_package(60), 5), None);
+    }
+}
@@ -0,0 +1,321 @@
+//! Post-processing for stills: rotating, cropping, evening out the brightness, stamping the
+//! time on and blacking out private areas.
+//!
+//! The steps are listed in `MODKIT_STILL_PIPELINE` as json, and run in that order on every still
+//! after it's captured. It's all done here rather than with raspistill options, so simulated
+//! captures come out the same as real ones. Regions are fractions of the picture as it is at
+//! that step, so a mask after a crop is relative to the cropped picture.
+use std::path::Path;
+
+use chrono::Local;
+use image::{imageops, Rgb, RgbImage};
+use serde::{Deserialize, Serialize};
+
+use super::Roi;
+use crate::drivers::DeviceError;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Step {
+    /// Turns the picture clockwise by 90, 180 or 270 degrees
+    Rotate { degrees: u32 },
+    /// Keeps only this part of the picture
+    Crop { region: Roi },
+    /// Stretches the brightness so the darkest parts are black and the brightest are white
+    Normalize,
+    /// Writes the capture time in the bottom left corner
+    Timestamp,
+    /// Blacks out a region, ie. a neighbour's window
+    Mask { region: Roi },
+}
+
+/// The steps to run on each still, in order
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Pipeline {
+    steps: Vec<Step>,
+}
+
+impl Pipeline {
+    pub fn new(steps: Vec<Step>) -> Result<Self, String> {
+        for step in &steps {
+            if let Step::Rotate { degrees } = step {
+                if degrees % 90 != 0 {
+                    return Err(format!("can only rotate by 90 degree steps, not {degrees}"));
+                }
+            }
+        }
+        Ok(Pipeline { steps })
+    }
+
+    /// Parses a json list of steps, ie. `[{"Rotate": {"degrees": 90}}, "Timestamp"]`
+    pub fn parse(json: &str) -> Result<Self, String> {
+        let steps: Vec<Step> = serde_json::from_str(json).map_err(|e| format!("{e}"))?;
+        Self::new(steps)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.steps.is_empty()
+    }
+
+    /// Runs every step on an image. `timestamp` is the text for `Timestamp`
+    pub fn apply(&self, mut img: RgbImage, timestamp: &str) -> RgbImage {
+        for step in &self.steps {
+            img = match step {
+                Step::Rotate { degrees } => match degrees % 360 {
+                    90 => imageops::rotate90(&img),
+                    180 => imageops::rotate180(&img),
+                    270 => imageops::rotate270(&img),
+                    _ => img,
+                },
+                Step::Crop { region } => {
+                    let (x, y, w, h) = region.to_pixels(img.width(), img.height());
+                    imageops::crop_imm(&img, x, y, w, h).to_image()
+                }
+                Step::Normalize => normalize(img),
+                Step::Timestamp => stamp(img, timestamp),
+                Step::Mask { region } => {
+                    let (x, y, w, h) = region.to_pixels(img.width(), img.height());
+                    fill(&mut img, x, y, w, h, Rgb([0, 0, 0]));
+                    img
+                }
+            };
+        }
+        img
+    }
+
+    /// Runs every step on the still at `path`, and saves it over the original
+    pub fn apply_file(&self, path: &Path) -> Result<(), DeviceError> {
+        let img = image::open(path)?.to_rgb8();
+        let timestamp = Local::now().format("%Y-%m-%d %H:%M:%S").to_string();
+        self.apply(img, &timestamp).save(path)?;
+        Ok(())
+    }
+}
+
+/// Stretches the brightness between the darkest and brightest 1% of the pixels to the full range
+fn normalize(mut img: RgbImage) -> RgbImage {
+    let mut histogram = [0u32; 256];
+    for p in img.pixels() {
+        histogram[luma(p) as usize] += 1;
+    }
+
+    let total = img.width() * img.height();
+    let cutoff = total / 100;
+    let percentile = |levels: &mut dyn Iterator<Item = usize>| {
+        let mut seen = 0;
+        for level in levels {
+            seen += histogram[level];
+            if seen > cutoff {
+                return level as f32;
+            }
+        }
+        0.0
+    };
+    let low = percentile(&mut (0..256));
+    let high = percentile(&mut (0..256).rev());
+
+    // A flat picture (ie. pitch black) would just turn into noise
+    if high - low < 10.0 {
+        return img;
+    }
+
+    let scale = 255.0 / (high - low);
+    for p in img.pixels_mut() {
+        for c in p.0.iter_mut() {
+            *c = ((*c as f32 - low) * scale).clamp(0.0, 255.0) as u8;
+        }
+    }
+    img
+}
+
+fn luma(p: &Rgb<u8>) -> u8 {
+    (0.299 * p.0[0] as f32 + 0.587 * p.0[1] as f32 + 0.114 * p.0[2] as f32) as u8
+}
+
+/// Fills a rectangle, clipped to the image
+fn fill(img: &mut RgbImage, x: u32, y: u32, width: u32, height: u32, colour: Rgb<u8>) {
+    for py in y..(y + height).min(img.height()) {
+        for px in x..(x + width).min(img.width()) {
+            img.put_pixel(px, py, colour);
+        }
+    }
+}
+
+/// A tiny 3x5 pixel font, just enough for dates and times. Each row is 3 bits, left to right
+fn glyph(c: char) -> [u8; 5] {
+    match c {
+        '0' => [0b111, 0b101, 0b101, 0b101, 0b111],
+        '1' => [0b010, 0b110, 0b010, 0b010, 0b111],
+        '2' => [0b111, 0b001, 0b111, 0b100, 0b111],
+        '3' => [0b111, 0b001, 0b111, 0b001, 0b111],
+        '4' => [0b101, 0b101, 0b111, 0b001, 0b001],
+        '5' => [0b111, 0b100, 0b111, 0b001, 0b111],
+        '6' => [0b111, 0b100, 0b111, 0b101, 0b111],
+        '7' => [0b111, 0b001, 0b001, 0b001, 0b001],
+        '8' => [0b111, 0b101, 0b111, 0b101, 0b111],
+        '9' => [0b111, 0b101, 0b111, 0b001, 0b111],
+        '-' => [0b000, 0b000, 0b111, 0b000, 0b000],
+        ':' => [0b000, 0b010, 0b000, 0b010, 0b000],
+        '.' => [0b000, 0b000, 0b000, 0b000, 0b010],
+        '/' => [0b001, 0b001, 0b010, 0b100, 0b100],
+        _ => [0; 5],
+    }
+}
+
+/// Writes white text on a black box in the bottom left corner
+fn stamp(mut img: RgbImage, text: &str) -> RgbImage {
+    // Readable at any resolution, the box is 7% of the picture high
+    let scale = (img.height() / 100).max(1);
+    let chars = text.chars().count() as u32;
+    let box_width = (chars * 4 + 1) * scale;
+    let box_height = 7 * scale;
+    let box_y = img.height().saturating_sub(box_height);
+
+    fill(&mut img, 0, box_y, box_width, box_height, Rgb([0, 0, 0]));
+
+    for (i, c) in text.chars().enumerate() {
+        let char_x = (i as u32 * 4 + 1) * scale;
+        for (row, bits) in glyph(c).iter().enumerate() {
+            for col in 0..3 {
+                if bits & (0b100 >> col) != 0 {
+                    fill(
+                        &mut img,
+                        char_x + col * scale,
+                        box_y + (row as u32 + 1) * scale,
+                        scale,
+                        scale,
+                        Rgb([255, 255, 255]),
+                    );
+                }
+            }
+        }
+    }
+    img
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A grey 40x20 picture with a red pixel in the top left corner
+    fn picture() -> RgbImage {
+        let mut img = RgbImage::from_pixel(40, 20, Rgb([100, 100, 100]));
+        img.put_pixel(0, 0, Rgb([255, 0, 0]));
+        img
+    }
+
+    fn pipeline(json: &str) -> Pipeline {
+        Pipeline::parse(json).unwrap()
+    }
+
+    #[test]
+    fn test_parse() {
+        let parsed = pipeline(
+            r#"[
+                {"Rotate": {"degrees": 270}},
+                {"Crop": {"region": "0,0,0.5,0.5"}},
+                "Normalize",
+                "Timestamp",
+                {"Mask": {"region": "0.5,0,0.5,1"}}
+            ]"#,
+        );
+        assert_eq!(parsed.steps.len(), 5);
+        assert_eq!(parsed.steps[2], Step::Normalize);
+
+        assert!(Pipeline::parse(r#"[{"Rotate": {"degrees": 45}}]"#).is_err());
+        assert!(Pipeline::parse(r#"[{"Crop": {"region": "0,0,2,2"}}]"#).is_err());
+        assert!(Pipeline::parse(r#"["Sharpen"]"#).is_err());
+        assert!(Pipeline::default().is_empty());
+    }
+
+    #[test]
+    fn test_rotate() {
+        let rotated = pipeline(r#"[{"Rotate": {"degrees": 90}}]"#).apply(picture(), "");
+        assert_eq!(rotated.dimensions(), (20, 40));
+        // The top left corner ends up in the top right
+        assert_eq!(rotated.get_pixel(19, 0), &Rgb([255, 0, 0]));
+
+        let rotated = pipeline(r#"[{"Rotate": {"degrees": 180}}]"#).apply(picture(), "");
+        assert_eq!(rotated.dimensions(), (40, 20));
+        assert_eq!(rotated.get_pixel(39, 19), &Rgb([255, 0, 0]));
+
+        let rotated = pipeline(r#"[{"Rotate": {"degrees": 360}}]"#).apply(picture(), "");
+        assert_eq!(rotated, picture());
+    }
+
+    #[test]
+    fn test_crop() {
+        let cropped = pipeline(r#"[{"Crop": {"region": "0,0,0.5,0.5"}}]"#).apply(picture(), "");
+        assert_eq!(cropped.dimensions(), (20, 10));
+        assert_eq!(cropped.get_pixel(0, 0), &Rgb([255, 0, 0]));
+    }
+
+    #[test]
+    fn test_normalize() {
+        // A dull picture, everything between 80 and 140
+        let mut dull = RgbImage::new(50, 50);
+        for (x, _, p) in dull.enumerate_pixels_mut() {
+            let level = 80 + (x * 60 / 49) as u8;
+            *p = Rgb([level, level, level]);
+        }
+
+        let normalized = pipeline(r#"["Normalize"]"#).apply(dull, "");
+        assert!(normalized.get_pixel(0, 0).0[0] < 10);
+        assert!(normalized.get_pixel(49, 0).0[0] > 245);
+
+        // Nothing to stretch in a flat picture
+        let flat = RgbImage::from_pixel(10, 10, Rgb([5, 5, 5]));
+        assert_eq!(pipeline(r#"["Normalize"]"#).apply(flat.clone(), ""), flat);
+    }
+
+    #[test]
+    fn test_mask() {
+        let masked = pipeline(r#"[{"Mask": {"region": "0,0,0.5,1"}}]"#).apply(picture(), "");
+        assert_eq!(masked.get_pixel(0, 0), &Rgb([0, 0, 0]));
+        assert_eq!(masked.get_pixel(19, 19), &Rgb([0, 0, 0]));
+        assert_eq!(masked.get_pixel(20, 0), &Rgb([100, 100, 100]));
+    }
+
+    #[test]
+    fn test_timestamp() {
+        let img = RgbImage::from_pixel(200, 100, Rgb([100, 100, 100]));
+        let stamped = pipeline(r#"["Timestamp"]"#).apply(img, "2023-03-04 12:00:00");
+
+        // The box is 7 pixels high and 4 per character, plus a bit
+        let box_pixels: Vec<&Rgb<u8>> = (0..77)
+            .flat_map(|x| (93..100).map(move |y| (x, y)))
+            .map(|(x, y)| stamped.get_pixel(x, y))
+            .collect();
+        assert!(box_pixels.contains(&&Rgb([255, 255, 255])));
+        assert!(box_pixels.contains(&&Rgb([0, 0, 0])));
+
+        // The rest is untouched
+        assert_eq!(stamped.get_pixel(100, 50), &Rgb([100, 100, 100]));
+        assert_eq!(stamped.get_pixel(0, 92), &Rgb([100, 100, 100]));
+        assert_eq!(stamped.get_pixel(78, 99), &Rgb([100, 100, 100]));
+    }
+
+    #[test]
+    fn test_order() {
+        // Masking the left half then rotating puts the black in the top half
+        let img = pipeline(r#"[{"Mask": {"region": "0,0,0.5,1"}}, {"Rotate": {"degrees": 90}}]"#)
+            .apply(picture(), "");
+        assert_eq!(img.get_pixel(10, 0), &Rgb([0, 0, 0]));
+        assert_eq!(img.get_pixel(10, 39), &Rgb([100, 100, 100]));
+    }
+
+    #[test]
+    fn test_apply_file() {
+        let path = std::env::temp_dir().join("modkit-pipeline-test.jpg");
+        image::DynamicImage::ImageRgb8(picture())
+            .save(&path)
+            .unwrap();
+
+        pipeline(r#"[{"Rotate": {"degrees": 90}}]"#)
+            .apply_file(&path)
+            .unwrap();
+        assert_eq!(image::open(&path).unwrap().to_rgb8().dimensions(), (20, 40));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
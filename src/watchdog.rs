@@ -1,12 +1,23 @@
-use std::time::Duration;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 
+use chrono::Local;
 use log::*;
 
+use crate::drivers::camera::camera;
 use crate::drivers::contact_sensor::ContactSensor;
 use crate::drivers::device::DeviceType;
+use crate::drivers::hardware_enabled;
+use crate::drivers::light::light;
+use crate::drivers::prebuffer::prebuffer;
+use crate::notify::Priority;
+use crate::rules::{self, Context, Outcome};
+use crate::security::{AccessReason, SecurityMonitor};
 use crate::server::Clients;
 use crate::store::Store;
-use crate::{model::*, server};
+use crate::vision::classifier::{Classifier, MailChange};
+use crate::vision::motion::MotionDetector;
+use crate::{defaults, metrics, model::*, mqtt, notify, server, shutdown};
 
 /// Runs a continuous loop that watches for the door state changing.
 /// If the state changes:
@@ -14,7 +25,23 @@ use crate::{model::*, server};
 ///     2. If the door opened, record a 5 second video and send a PollDeviceResult (Camera) when
 ///        done
 ///             Unfortunately this blocks, we can't do it async
-///     3. If the door closed, send either a MailDelivered or MailPickedUp event
+///     3. If the door closed, take a still and compare it to the one from before the door opened
+///        to send either a MailDelivered or MailPickedUp event
+///     4. If the door has been open too long, send a DoorLeftOpen every so often until it
+///        closes, then a DoorClosed
+///     5. In security mode, send a SuspiciousAccess (with a still) right away if the door opened
+///        when it shouldn't have
+///
+/// If there's a snapshot schedule, it also takes a still whenever that comes around, puts it in
+/// the media catalog and sends a ScheduledSnapshot.
+///
+/// When the mailbox is armed, it always records a video when the door opens, then flashes the
+/// light, and every notification is high priority.
+///
+/// Every event goes through the rules (see `rules`) before it's sent anywhere
+///
+/// Returns once we're shutting down (see `shutdown`), after stopping the ring buffer and turning
+/// the light off
 pub async fn watch(clients: &Clients) -> Result<(), Box<dyn std::error::Error>> {
     info!("Running the watchdog");
     let store = Store::connect().await?;
@@ -25,12 +52,73 @@ pub async fn watch(clients: &Clients) -> Result<(), Box<dyn std::error::Error>>
     // Make an event queue
     let mut event_queue: Vec<Event> = Vec::new();
 
-    loop {
+    // Keeps track of how long the door's been open
+    let mut door_alarm = DoorAlarm::from_env();
+
+    // Watches for suspicious openings, if security mode is on
+    let mut security = if defaults::security_mode() {
+        info!("Security mode is on");
+        Some(SecurityMonitor::from_env())
+    } else {
+        None
+    };
+
+    // Looks for movement outside the box while the door is shut, if it's turned on
+    let mut motion = if defaults::motion_detection() && hardware_enabled() {
+        info!("Motion detection is on");
+        Some(MotionDetector::from_env())
+    } else {
+        None
+    };
+    let mut next_motion_check: u32 = 0;
+
+    // Takes a still every so often, even if nobody opens the door
+    let snapshots = defaults::snapshot_schedule();
+    let mut next_snapshot = snapshots.as_ref().and_then(|s| s.next_after(Local::now()));
+    if let (Some(schedule), Some(next)) = (&snapshots, next_snapshot) {
+        info!("Taking snapshots on the schedule `{schedule}`, starting at {next}");
+    }
+
+    // The still from the last time the door was closed, ie. what the box looks
+    // like before it's opened next
+    let classifier = Classifier::from_env();
+    let use_classifier = defaults::mail_classifier() && hardware_enabled();
+    let mut reference_still: Option<PathBuf> = None;
+    if use_classifier {
+        reference_still = camera::capture_still(None)
+            .map_err(|e| error!("Couldn't take a reference still for the mail classifier: {e}"))
+            .ok();
+    }
+
+    // Keep recording into the ring buffer, so videos include the moments before the door opened.
+    // The camera is busy while it runs, so stills and videos are taken from it
+    if defaults::preroll() > 0 && hardware_enabled() {
+        // Still running if the watchdog failed and this is a restart
+        stop_prebuffer().await;
+        if let Err(e) = prebuffer::start(defaults::preroll(), defaults::video_max()) {
+            error!("Couldn't start the pre-roll ring buffer: {e}");
+        }
+    }
+
+    // How long each time around the loop should take
+    let tick = Duration::from_secs(1);
+    let mut last_tick = Instant::now();
+
+    while !shutdown::is_stopping() {
+        // Anything over a tick means something held us up, ie. recording a video
+        metrics::watchdog_lag(last_tick.elapsed().saturating_sub(tick));
+        last_tick = Instant::now();
+
         // if the door sensor changes
         // (changed() calls poll() and updates the internal state)
         if door_sensor.changed().unwrap_or(false) {
             let is_open: bool = door_sensor.is_open();
 
+            // The view changes when the door moves, that's not motion
+            if let Some(motion) = &mut motion {
+                motion.reset();
+            }
+
             // Skip the queue and just send an event immediately
             // We want to skip the queue because right after this, we might record
             // a video. Recording a video will block for 6 seconds. If we queued up,
@@ -41,41 +129,119 @@ pub async fn watch(clients: &Clients) -> Result<(), Box<dyn std::error::Error>>
                 Some(DeviceType::ContactSensor),
                 Some(Bundle::ContactSensor { open: is_open }),
             );
-            server::ws::send_to_clients(&opened_event, &clients).await;
+            if is_open {
+                metrics::door_opened();
+                door_alarm.opened(opened_event.timestamp());
+            }
+            dispatch(&opened_event, clients, &store, door_alarm.opened_at()).await;
+            if !is_open {
+                if let Some(opened_at) = door_alarm.opened_at() {
+                    metrics::door_closed(opened_event.timestamp().saturating_sub(opened_at));
+                }
+                if let Some(closed) = door_alarm.closed(opened_event.timestamp()) {
+                    event_queue.push(closed);
+                }
+            }
+
+            // This one can't wait for the video either
+            if let (true, Some(security)) = (is_open, &mut security) {
+                let reasons = security.opened(Local::now());
+                if !reasons.is_empty() {
+                    warn!("Suspicious access: {:?}", reasons);
+                    let event = suspicious_access(reasons, &opened_event);
+                    dispatch(&event, clients, &store, door_alarm.opened_at()).await;
+                    store.write_event(event).await?;
+                }
+            }
+
+            let armed = is_armed(&store).await;
+            if is_open && armed {
+                warn!("The door opened while the mailbox is armed");
+            }
 
             // When the door opens, take a video and send that event
-            if is_open {
+            if is_open && (armed || defaults::record_video()) {
                 trace!("Door opened, taking a video (after 1 second delay)");
                 // Make a new event with the associated Camera type
                 let mut new_video_event =
                     Event::new(EventKind::PollDeviceResult, Some(DeviceType::Camera), None);
-                // Call poll_device, which will take a video and store the data bundle on itself
-                new_video_event.poll_device()?;
-                // Then queue it up to be sent
-                event_queue.push(new_video_event);
+                // Call poll_device, which will take a video of the door opening and store the
+                // data bundle on itself
+                match new_video_event.poll_device_for(Some(&opened_event)) {
+                    // Then queue it up to be sent
+                    Ok(_) => event_queue.push(new_video_event),
+                    Err(e) => error!("Couldn't record a video of the door opening: {e}"),
+                }
+            }
+
+            // Not while recording, it'd leave the video in the dark
+            if is_open && armed {
+                tokio::spawn(flash_light());
             }
 
             // When the door changes to closed (ie. someone opens the box then
             // closes it, mail delivered or picked up)
             if !is_open {
-                // Normally we should use image processing or something to determine if the mail is picked up,
-                // but I don't have time for that now. This just switches between the statuses.
-                if let Ok(mail_status) = store.get_mail_status().await {
-                    match mail_status.kind() {
-                        EventKind::MailDelivered => {
-                            // If the last status (ie. last time the door opened) was a delivery,
-                            // then mail is being picked up
-                            info!("Queueing up a MailPickedUp Event");
-                            event_queue.push(Event::new(EventKind::MailPickedUp, None, None));
+                let mut classified = false;
+
+                if use_classifier {
+                    match camera::capture_still(Some(&opened_event)) {
+                        Ok(after) => {
+                            if let Some(before) = &reference_still {
+                                match classify(&classifier, before, &after) {
+                                    Ok(Some(event)) => {
+                                        event_queue.push(event);
+                                        classified = true;
+                                    }
+                                    Ok(None) => {
+                                        info!("Door closed but the mail didn't change");
+                                        classified = true;
+                                    }
+                                    // Falls back to toggling below
+                                    Err(e) => error!("Couldn't classify the mail: {e}"),
+                                }
+                            }
+                            reference_still = Some(after);
                         }
-                        EventKind::MailPickedUp => {
-                            info!("Queueing up a MailDelivered Event");
-                            event_queue.push(Event::new(EventKind::MailDelivered, None, None));
+                        Err(e) => error!("Couldn't take a still to classify the mail: {e}"),
+                    }
+                }
+
+                // Without the camera we just switch between the statuses
+                if !classified {
+                    event_queue.push(toggle_mail_status(&store).await);
+                }
+            }
+        }
+
+        if let Some(left_open) = door_alarm.check(notify::now()) {
+            warn!("The door has been left open");
+            event_queue.push(left_open);
+        }
+
+        if let (None, Some(motion)) = (door_alarm.opened_at(), &mut motion) {
+            let now = notify::now();
+            if now >= next_motion_check {
+                next_motion_check = now + defaults::motion_interval();
+                match camera::capture_frame() {
+                    Ok(frame) => {
+                        if let Some(changed) = motion.feed(&frame, now) {
+                            info!("Motion detected ({:.0}% of the picture)", changed * 100.0);
+                            event_queue.push(motion_detected(changed));
                         }
-                        _ => {}
                     }
-                } else {
-                    event_queue.push(Event::new(EventKind::MailDelivered, None, None));
+                    Err(e) => trace!("Couldn't grab a frame to look for motion: {e}"),
+                }
+            }
+        }
+
+        if let (Some(schedule), Some(due)) = (&snapshots, next_snapshot) {
+            let now = Local::now();
+            if now >= due {
+                // If we were busy (ie. recording) and missed some, just go on from now
+                next_snapshot = schedule.next_after(now);
+                if let Some(snapshot) = scheduled_snapshot(&store).await {
+                    event_queue.push(snapshot);
                 }
             }
         }
@@ -85,11 +251,417 @@ pub async fn watch(clients: &Clients) -> Result<(), Box<dyn std::error::Error>>
         for event in event_queue {
             trace!("Sending event {} to clients", event.kind());
             trace!("{:#?}", event);
-            server::ws::send_to_clients(&event, &clients).await;
+            if let (EventKind::MailPickedUp, Some(security)) = (event.kind(), &mut security) {
+                security.picked_up(event.timestamp());
+            }
+            dispatch(&event, clients, &store, door_alarm.opened_at()).await;
             store.write_event(event).await?;
         }
 
         event_queue = Vec::new();
-        tokio::time::sleep(Duration::from_secs(1)).await;
+        tokio::select! {
+            _ = tokio::time::sleep(tick) => {}
+            _ = shutdown::stopped() => {}
+        }
+    }
+
+    info!("Stopping the watchdog");
+    stop_prebuffer().await;
+    if let Err(e) = light::set(false) {
+        error!("Couldn't turn the light off: {e}");
+    }
+    store.close().await;
+    Ok(())
+}
+
+/// Works out when to raise the alarm about the door being left open
+#[derive(Debug, Clone, PartialEq)]
+pub struct DoorAlarm {
+    /// Seconds the door can be open before the first DoorLeftOpen, 0 for never
+    threshold: u32,
+    /// Seconds between DoorLeftOpen events after that, 0 for only one
+    repeat: u32,
+    opened_at: Option<u32>,
+    last_alarm: Option<u32>,
+}
+
+impl DoorAlarm {
+    pub fn new(threshold: u32, repeat: u32) -> Self {
+        DoorAlarm {
+            threshold,
+            repeat,
+            opened_at: None,
+            last_alarm: None,
+        }
+    }
+
+    /// Uses the times from the environment, see `defaults`
+    pub fn from_env() -> Self {
+        Self::new(defaults::door_open_alarm(), defaults::door_open_repeat())
+    }
+
+    /// When the door opened, if it's open
+    pub fn opened_at(&self) -> Option<u32> {
+        self.opened_at
+    }
+
+    pub fn opened(&mut self, now: u32) {
+        self.opened_at = Some(now);
+        self.last_alarm = None;
+    }
+
+    /// Returns a DoorClosed event if we'd raised the alarm
+    pub fn closed(&mut self, now: u32) -> Option<Event> {
+        let opened_at = self.opened_at.take()?;
+        self.last_alarm.take()?;
+        Some(self.event(EventKind::DoorClosed, opened_at, now))
+    }
+
+    /// Returns a DoorLeftOpen event if one is due
+    pub fn check(&mut self, now: u32) -> Option<Event> {
+        let opened_at = self.opened_at?;
+        if self.threshold == 0 {
+            return None;
+        }
+
+        let due = match self.last_alarm {
+            None => opened_at + self.threshold,
+            Some(_) if self.repeat == 0 => return None,
+            Some(last) => last + self.repeat,
+        };
+        if now < due {
+            return None;
+        }
+
+        self.last_alarm = Some(now);
+        Some(self.event(EventKind::DoorLeftOpen, opened_at, now))
+    }
+
+    fn event(&self, kind: EventKind, opened_at: u32, now: u32) -> Event {
+        let mut event = Event::new(
+            kind,
+            Some(DeviceType::ContactSensor),
+            Some(Bundle::DoorOpenTime {
+                opened_at,
+                open_for: now.saturating_sub(opened_at),
+            }),
+        );
+        event.set_timestamp(now);
+        event
+    }
+}
+
+/// Sends an event to the clients and notifiers, unless a rule says otherwise,
+/// and does whatever else the matching rules ask for
+async fn dispatch(event: &Event, clients: &Clients, store: &Store, door_opened_at: Option<u32>) {
+    let outcome = apply_rules(store, event, door_opened_at).await;
+
+    if outcome.suppress {
+        info!("Rules {:?} suppressed {}", outcome.matched, event.kind());
+    } else {
+        server::ws::send_to_clients(event, clients).await;
+        mqtt::publish_event(event);
+    }
+
+    // Everything is high priority while the box is armed. High priority notifications
+    // still go out when a rule suppresses the event
+    let priority = if is_armed(store).await {
+        Priority::High
+    } else {
+        Priority::of(event.kind())
+    };
+    let wanted = notify::should_notify(event) && (!outcome.suppress || priority == Priority::High);
+    if outcome.notify || wanted {
+        notify::queue(store, event, priority).await;
+    }
+
+    if let Some(seconds) = outcome.light_on {
+        tokio::spawn(light_for(seconds));
+    }
+
+    if outcome.capture {
+        match camera::capture_still(Some(event)) {
+            Ok(path) => {
+                let still = Event::new(
+                    EventKind::PollDeviceResult,
+                    Some(DeviceType::Camera),
+                    Some(Bundle::Camera {
+                        file_name: path
+                            .file_name()
+                            .expect("image file name")
+                            .to_string_lossy()
+                            .to_string(),
+                        duration: None,
+                    }),
+                );
+                server::ws::send_to_clients(&still, clients).await;
+                if let Err(e) = store.write_event(still).await {
+                    error!("Couldn't save the still a rule asked for: {e}");
+                }
+            }
+            Err(e) => error!("Couldn't take the still a rule asked for: {e}"),
+        }
+    }
+}
+
+/// Takes a still and makes a SuspiciousAccess event out of it
+fn suspicious_access(reasons: Vec<AccessReason>, opened: &Event) -> Event {
+    let file_name = match camera::capture_still(Some(opened)) {
+        Ok(path) => path.file_name().map(|f| f.to_string_lossy().to_string()),
+        Err(e) => {
+            error!("Couldn't take a still of the suspicious access: {e}");
+            None
+        }
+    };
+
+    Event::new(
+        EventKind::SuspiciousAccess,
+        Some(DeviceType::Camera),
+        Some(Bundle::SuspiciousAccess { reasons, file_name }),
+    )
+}
+
+/// Takes a still and makes a MotionDetected event out of it
+fn motion_detected(changed: f32) -> Event {
+    let file_name = match camera::capture_still(None) {
+        Ok(path) => path.file_name().map(|f| f.to_string_lossy().to_string()),
+        Err(e) => {
+            error!("Couldn't take a still of the motion: {e}");
+            None
+        }
+    };
+
+    Event::new(
+        EventKind::MotionDetected,
+        Some(DeviceType::Camera),
+        Some(Bundle::Motion { changed, file_name }),
+    )
+}
+
+/// Takes a still for the snapshot schedule and adds it to the media catalog
+async fn scheduled_snapshot(store: &Store) -> Option<Event> {
+    let file_name = match camera::capture_still(None) {
+        Ok(path) => path.file_name()?.to_string_lossy().to_string(),
+        Err(e) => {
+            error!("Couldn't take a scheduled snapshot: {e}");
+            return None;
+        }
+    };
+
+    let event = Event::new(
+        EventKind::ScheduledSnapshot,
+        Some(DeviceType::Camera),
+        Some(Bundle::Camera {
+            file_name: file_name.clone(),
+            duration: None,
+        }),
+    );
+    let kind = event.kind().to_string();
+    if let Err(e) = store
+        .add_media(&file_name, "still", &kind, event.timestamp())
+        .await
+    {
+        error!("Couldn't add {file_name} to the media catalog: {e}");
+    }
+    Some(event)
+}
+
+/// Runs the rules in the db against an event
+async fn apply_rules(store: &Store, event: &Event, door_opened_at: Option<u32>) -> Outcome {
+    let rules = match store.get_rules().await {
+        Ok(rules) => rules,
+        Err(e) => {
+            error!("Couldn't load the rules, using the defaults: {e}");
+            return Outcome::default();
+        }
+    };
+    if rules.is_empty() {
+        return Outcome::default();
+    }
+
+    let mail_waiting = matches!(
+        store.get_mail_status().await.map(|e| e.kind().clone()),
+        Ok(EventKind::MailDelivered)
+    );
+    let context = Context {
+        now: Local::now(),
+        mail_waiting,
+        door_opened_at,
+    };
+
+    let outcome = rules::evaluate(&rules, event, &context);
+    if !outcome.matched.is_empty() {
+        trace!("{} matched rules {:?}", event.kind(), outcome.matched);
+    }
+    outcome
+}
+
+async fn is_armed(store: &Store) -> bool {
+    store
+        .is_armed()
+        .await
+        .map_err(|e| error!("Couldn't read the arm state, assuming disarmed: {e}"))
+        .unwrap_or(false)
+}
+
+/// Stops the ring buffer on a blocking thread, since waiting for raspivid can take a while
+async fn stop_prebuffer() {
+    if let Err(e) = tokio::task::spawn_blocking(prebuffer::stop).await {
+        error!("Stopping the ring buffer failed: {e}");
+    }
+}
+
+/// Flashes the light a few times, ie. to scare someone off when the box is armed
+async fn flash_light() {
+    for _ in 0..5 {
+        for on in [true, false] {
+            if let Err(e) = light::set(on) {
+                error!("Couldn't flash the light: {e}");
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(300)).await;
+        }
+    }
+}
+
+/// Turns the light on for a while, for a rule
+async fn light_for(seconds: u32) {
+    if let Err(e) = light::set(true) {
+        error!("Couldn't turn the light on for a rule: {e}");
+        return;
+    }
+    tokio::time::sleep(Duration::from_secs(seconds as u64)).await;
+    if let Err(e) = light::set(false) {
+        error!("Couldn't turn the light back off after a rule: {e}");
+    }
+}
+
+/// Compares the stills from before and after the door opened, and makes the right
+/// mail event for it. Returns None if the mail didn't change.
+fn classify(
+    classifier: &Classifier,
+    before: &Path,
+    after: &Path,
+) -> Result<Option<Event>, crate::drivers::DeviceError> {
+    let result = classifier.classify_files(before, after)?;
+    info!("Mail classified as {:?} ({:.2})", result.change, result.confidence);
+
+    let kind = match result.change {
+        MailChange::Delivered => EventKind::MailDelivered,
+        MailChange::PickedUp => EventKind::MailPickedUp,
+        MailChange::Unchanged => return Ok(None),
+    };
+
+    let bundle = Bundle::MailClassification {
+        confidence: result.confidence,
+        file_name: after
+            .file_name()
+            .map(|f| f.to_string_lossy().to_string())
+            .unwrap_or_default(),
+    };
+
+    Ok(Some(Event::new(kind, Some(DeviceType::Camera), Some(bundle))))
+}
+
+/// Switches to the other mail status from the one in the db.
+/// This is what we fall back to when we can't use the camera
+async fn toggle_mail_status(store: &Store) -> Event {
+    match store.get_mail_status().await.map(|e| e.kind().clone()) {
+        Ok(EventKind::MailDelivered) => {
+            // If the last status (ie. last time the door opened) was a delivery,
+            // then mail is being picked up
+            info!("Queueing up a MailPickedUp Event");
+            Event::new(EventKind::MailPickedUp, None, None)
+        }
+        _ => {
+            info!("Queueing up a MailDelivered Event");
+            Event::new(EventKind::MailDelivered, None, None)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_flash_while_recording() {
+        let _light = light::TEST_LIGHT.lock().await;
+        // Like an armed door opening, if the light flashed while the video was recorded
+        light::hold().unwrap();
+        let flashing = tokio::spawn(flash_light());
+        for _ in 0..5 {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            assert!(light::is_on().unwrap());
+        }
+
+        light::release().unwrap();
+        flashing.await.unwrap();
+        assert!(!light::is_on().unwrap());
+    }
+
+    fn open_for(event: &Event) -> u32 {
+        match event.data() {
+            Some(Bundle::DoorOpenTime { open_for, .. }) => *open_for,
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_door_alarm() {
+        let mut alarm = DoorAlarm::new(60, 300);
+        assert!(alarm.check(1000).is_none());
+
+        alarm.opened(1000);
+        assert_eq!(alarm.opened_at(), Some(1000));
+        assert!(alarm.check(1059).is_none());
+
+        let first = alarm.check(1060).unwrap();
+        assert_eq!(first.kind(), &EventKind::DoorLeftOpen);
+        assert_eq!(first.timestamp(), 1060);
+        assert_eq!(open_for(&first), 60);
+
+        // Then every 5 minutes
+        assert!(alarm.check(1061).is_none());
+        assert!(alarm.check(1359).is_none());
+        let second = alarm.check(1360).unwrap();
+        assert_eq!(open_for(&second), 360);
+
+        let closed = alarm.closed(1400).unwrap();
+        assert_eq!(closed.kind(), &EventKind::DoorClosed);
+        assert_eq!(open_for(&closed), 400);
+        assert_eq!(alarm.opened_at(), None);
+        assert!(alarm.check(2000).is_none());
+    }
+
+    #[test]
+    fn test_door_alarm_closed_in_time() {
+        let mut alarm = DoorAlarm::new(60, 300);
+        alarm.opened(1000);
+        assert!(alarm.check(1030).is_none());
+        // No alarm, so nothing to clear
+        assert!(alarm.closed(1040).is_none());
+        assert!(alarm.check(1100).is_none());
+
+        // Opening again starts over
+        alarm.opened(2000);
+        assert!(alarm.check(2059).is_none());
+        assert!(alarm.check(2060).is_some());
+    }
+
+    #[test]
+    fn test_door_alarm_without_repeat() {
+        let mut alarm = DoorAlarm::new(60, 0);
+        alarm.opened(1000);
+        assert!(alarm.check(1060).is_some());
+        assert!(alarm.check(5000).is_none());
+        assert!(alarm.closed(5000).is_some());
+    }
+
+    #[test]
+    fn test_door_alarm_off() {
+        let mut alarm = DoorAlarm::new(0, 300);
+        alarm.opened(1000);
+        assert!(alarm.check(100_000).is_none());
     }
 }